@@ -0,0 +1,4 @@
+ALTER TABLE order_slice
+    ADD COLUMN trail_type VARCHAR(30),
+    ADD COLUMN trail_value DECIMAL(30, 8),
+    ADD COLUMN trigger_price DECIMAL(30, 8);
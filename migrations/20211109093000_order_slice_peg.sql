@@ -0,0 +1,4 @@
+ALTER TABLE order_slice
+    ADD COLUMN peg_reference VARCHAR(30),
+    ADD COLUMN peg_offset DECIMAL(30, 8),
+    ADD COLUMN peg_limit DECIMAL(30, 8);
@@ -0,0 +1,10 @@
+CREATE TABLE mmp_slice (
+    slice_id BIGINT NOT NULL,
+    user_id VARCHAR(36) NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    qty_limit DECIMAL(30, 8) NOT NULL,
+    trade_count_limit INT CHECK (trade_count_limit >= 0) NOT NULL,
+    time_window DOUBLE PRECISION NOT NULL,
+    triggered BOOL NOT NULL DEFAULT 'false',
+    PRIMARY KEY (slice_id, user_id, market)
+);
@@ -0,0 +1,16 @@
+CREATE TABLE internal_tx (
+    id SERIAL PRIMARY KEY,
+    time TIMESTAMP(0) NOT NULL,
+    user_from VARCHAR(36) NOT NULL,
+    user_to VARCHAR(36) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    amount DECIMAL(30, 8) CHECK (amount > 0) NOT NULL,
+    business_id BIGINT CHECK (business_id >= 0) NOT NULL,
+    memo TEXT NOT NULL DEFAULT ''
+);
+
+CREATE INDEX internal_tx_idx_from ON internal_tx (user_from, asset);
+
+CREATE INDEX internal_tx_idx_to ON internal_tx (user_to, asset);
+
+CREATE UNIQUE INDEX internal_tx_idx_from_business ON internal_tx (user_from, asset, business_id);
@@ -0,0 +1,7 @@
+CREATE TABLE sub_account_slice (
+    slice_id BIGINT NOT NULL,
+    owner VARCHAR(36) NOT NULL,
+    name VARCHAR(30) NOT NULL,
+    account_id VARCHAR(36) NOT NULL,
+    PRIMARY KEY (slice_id, owner, name)
+);
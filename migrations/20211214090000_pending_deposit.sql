@@ -0,0 +1,15 @@
+ALTER TABLE asset ADD COLUMN deposit_confirmations INT CHECK (deposit_confirmations >= 0) NOT NULL DEFAULT 0;
+
+CREATE TABLE deposit_slice (
+    slice_id BIGINT NOT NULL,
+    user_id VARCHAR(36) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    business VARCHAR(30) NOT NULL,
+    business_id BIGINT CHECK (business_id >= 0) NOT NULL,
+    market_price DECIMAL(30, 8) NOT NULL,
+    amount DECIMAL(30, 8) NOT NULL,
+    confirmations INT CHECK (confirmations >= 0) NOT NULL,
+    create_time TIMESTAMP(0) NOT NULL,
+    detail TEXT NOT NULL,
+    PRIMARY KEY (slice_id, business, business_id)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE business_id_slice (
+    slice_id BIGINT NOT NULL,
+    user_id VARCHAR(36) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    business VARCHAR(30) NOT NULL,
+    business_id BIGINT CHECK (business_id >= 0) NOT NULL,
+    time TIMESTAMP(0) NOT NULL,
+    PRIMARY KEY (slice_id, user_id, asset, business, business_id)
+);
@@ -0,0 +1,8 @@
+CREATE TABLE asset_ledger_slice (
+    slice_id BIGINT NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    deposit DECIMAL(30, 8) NOT NULL,
+    withdraw DECIMAL(30, 8) NOT NULL,
+    fee DECIMAL(30, 8) NOT NULL,
+    PRIMARY KEY (slice_id, asset)
+);
@@ -0,0 +1,23 @@
+ALTER TABLE asset ADD COLUMN min_deposit DECIMAL(30, 8) NOT NULL DEFAULT 0;
+ALTER TABLE asset ADD COLUMN min_withdraw DECIMAL(30, 8) NOT NULL DEFAULT 0;
+ALTER TABLE asset ADD COLUMN max_withdraw DECIMAL(30, 8) NOT NULL DEFAULT 0;
+ALTER TABLE asset ADD COLUMN withdraw_daily_quota DECIMAL(30, 8) NOT NULL DEFAULT 0;
+
+CREATE TABLE withdraw_quota_slice (
+    slice_id BIGINT NOT NULL,
+    user_id VARCHAR(36) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    quota DECIMAL(30, 8) NOT NULL,
+    PRIMARY KEY (slice_id, user_id, asset)
+);
+
+CREATE TABLE withdraw_usage_slice (
+    id SERIAL PRIMARY KEY,
+    slice_id BIGINT NOT NULL,
+    user_id VARCHAR(36) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    time TIMESTAMP(0) NOT NULL,
+    amount DECIMAL(30, 8) NOT NULL
+);
+
+CREATE INDEX withdraw_usage_slice_idx_slice ON withdraw_usage_slice (slice_id);
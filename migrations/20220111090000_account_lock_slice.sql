@@ -0,0 +1,7 @@
+CREATE TABLE account_lock_slice (
+    slice_id BIGINT NOT NULL,
+    user_id VARCHAR(36) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    reason TEXT NOT NULL,
+    PRIMARY KEY (slice_id, user_id, asset)
+);
@@ -0,0 +1 @@
+ALTER TABLE slice_history ADD COLUMN state_hash VARCHAR(64);
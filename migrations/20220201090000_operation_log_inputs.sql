@@ -0,0 +1,3 @@
+-- the user and the engine inputs of an operation, both needed to replay it
+ALTER TABLE operation_log ADD COLUMN user_id VARCHAR(64) NOT NULL DEFAULT '00000000-0000-0000-0000-000000000000';
+ALTER TABLE operation_log ADD COLUMN inputs TEXT NOT NULL DEFAULT '';
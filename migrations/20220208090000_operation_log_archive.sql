@@ -0,0 +1,2 @@
+-- operation logs behind the oldest kept slice, moved here by the slice retention
+CREATE TABLE operation_log_archive (LIKE operation_log INCLUDING ALL);
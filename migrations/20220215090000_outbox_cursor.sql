@@ -0,0 +1,7 @@
+-- the operation log id up to which every message of the ordered stream is delivered, a restart
+-- publishes the messages of the operations after it again
+CREATE TABLE outbox_cursor (
+    id SMALLINT PRIMARY KEY CHECK (id = 1),
+    operation_log_id BIGINT NOT NULL,
+    update_time TIMESTAMP(0) NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,3 @@
+-- the operation that made a balance change, so balances can be rebuilt at an exact operation
+ALTER TABLE balance_history ADD COLUMN operation_log_id BIGINT;
+CREATE INDEX balance_history_idx_operation_log_id ON balance_history (operation_log_id);
@@ -0,0 +1,28 @@
+-- the epoch of the current leader, written under the leader lock when an engine takes over
+-- start_operation_log_id: the operation log the current leader started after
+-- wal_operation_log_id: the highest operation log id the leader may have answered from its wal,
+-- a successor waits for the db to have all of them, 0 without a wal
+CREATE TABLE leader_state (
+    id SMALLINT PRIMARY KEY CHECK (id = 1),
+    epoch BIGINT NOT NULL,
+    start_operation_log_id BIGINT NOT NULL,
+    wal_operation_log_id BIGINT NOT NULL,
+    update_time TIMESTAMP(0) NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+ALTER TABLE operation_log ADD COLUMN epoch BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE operation_log_archive ADD COLUMN epoch BIGINT NOT NULL DEFAULT 0;
+
+-- a deposed leader may go on writing until it notices, its operation logs past the start of the
+-- current leader are rejected so the log does not fork
+CREATE FUNCTION operation_log_fence() RETURNS TRIGGER AS $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM leader_state WHERE id = 1 AND NEW.epoch < epoch AND NEW.id > start_operation_log_id) THEN
+        RAISE EXCEPTION 'operation log % of the deposed leader epoch %', NEW.id, NEW.epoch;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER operation_log_fence BEFORE INSERT ON operation_log
+    FOR EACH ROW EXECUTE FUNCTION operation_log_fence();
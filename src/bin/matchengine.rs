@@ -51,8 +51,25 @@ async fn prepare() -> anyhow::Result<GrpcHandler> {
 
     let mut grpc_stub = create_controller((settings.clone(), market_cfg));
     log::info!("grpc_stub created");
-    persist::init_from_db(&mut conn, &mut grpc_stub).await?;
-    log::info!("init from db done");
+    // taken before the log is replayed, so no other leader appends to it meanwhile
+    let lock = if settings.role == config::Role::Leader {
+        let lock = persist::LeaderLock::try_acquire(&settings.db_log)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("another engine holds the leader lock"))?;
+        Some(lock)
+    } else {
+        None
+    };
+    if settings.slice_storage == config::SliceStorage::File && std::path::Path::new(&settings.slice_file).exists() {
+        persist::init_from_file(&mut conn, &settings.slice_file, &mut grpc_stub).await?;
+        log::info!("init from {} done", settings.slice_file);
+    } else {
+        persist::init_from_db(&mut conn, &mut grpc_stub).await?;
+        log::info!("init from db done");
+    }
+    if let Some(lock) = lock {
+        grpc_stub.take_over(lock).await?;
+    }
     let grpc = GrpcHandler::new(grpc_stub, settings);
     Ok(grpc)
 }
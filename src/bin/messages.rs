@@ -0,0 +1,67 @@
+use dingir_exchange::persist::{MessageFileReader, MessageFilter};
+use std::io::Write;
+
+const USAGE: &str = "usage:
+    messages [options] read <dir>    print the messages of a file persistor directory as json lines
+    messages index <dir>             print the index of the closed segments
+options:
+    --topic <topic>       only this topic, may be repeated
+    --user <user_id>      only the messages about this user
+    --market <market>     only the messages of this market
+    --from <secs>         only the messages from this unix time
+    --to <secs>           only the messages before this unix time
+    --after-id <id>       only the messages after this message id, <operation_log_id>-<seq>";
+
+fn main() {
+    let mut filter = MessageFilter::default();
+    let mut args: Vec<String> = Vec::new();
+    let mut iter = std::env::args().skip(1);
+    let ret = (|| -> anyhow::Result<()> {
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().ok_or_else(|| anyhow::anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "--topic" => filter.topics.push(value()?),
+                "--user" => filter.user = Some(value()?.parse()?),
+                "--market" => filter.market = Some(value()?),
+                "--from" => filter.start_time = Some(value()?.parse()?),
+                "--to" => filter.end_time = Some(value()?.parse()?),
+                "--after-id" => filter.after_id = Some(value()?.parse()?),
+                _ => args.push(arg),
+            }
+        }
+        Ok(())
+    })();
+    if let Err(e) = ret {
+        eprintln!("{}\n{}", e, USAGE);
+        std::process::exit(1);
+    }
+
+    let ret = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["read", dir] => read(dir, filter),
+        ["index", dir] => index(dir),
+        _ => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    };
+    if let Err(e) = ret {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
+}
+
+fn read(dir: &str, filter: MessageFilter) -> anyhow::Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    for record in MessageFileReader::new(dir).read(filter)? {
+        writeln!(out, "{}", serde_json::to_string(&record?)?)?;
+    }
+    Ok(())
+}
+
+fn index(dir: &str) -> anyhow::Result<()> {
+    for segment in MessageFileReader::new(dir).index()? {
+        println!("{}", serde_json::to_string(&segment)?);
+    }
+    Ok(())
+}
@@ -61,6 +61,8 @@ fn main() {
 
         let persistor_balance: DatabaseWriter<models::BalanceHistory> = DatabaseWriter::new(&write_config).start_schedule(&pool).unwrap();
 
+        let persistor_internal_tx: DatabaseWriter<models::InternalTx> = DatabaseWriter::new(&write_config).start_schedule(&pool).unwrap();
+
         let trade_cfg = TopicConfig::<message::Trade>::new(message::TRADES_TOPIC)
             .persist_to(&persistor_kline)
             .persist_to(&persistor_trade)
@@ -74,10 +76,13 @@ fn main() {
 
         let balance_cfg = TopicConfig::<message::BalanceMessage>::new(message::BALANCES_TOPIC).persist_to(&persistor_balance);
 
+        let transfer_cfg = TopicConfig::<message::TransferMessage>::new(message::TRANSFERS_TOPIC).persist_to(&persistor_internal_tx);
+
         let auto_commit = vec![
             trade_cfg.auto_commit_start(consumer.clone()),
             order_cfg.auto_commit_start(consumer.clone()),
             balance_cfg.auto_commit_start(consumer.clone()),
+            transfer_cfg.auto_commit_start(consumer.clone()),
         ];
         let consumer = consumer.as_ref();
 
@@ -86,6 +91,7 @@ fn main() {
                 .add_topic_config(&trade_cfg).unwrap()
                 .add_topic_config(&order_cfg).unwrap()
                 .add_topic_config(&balance_cfg).unwrap()
+                .add_topic_config(&transfer_cfg).unwrap()
 //                .add_topic(message::TRADES_TOPIC, MsgDataPersistor::new(&persistor).handle_message::<message::Trade>())
                 ;
 
@@ -106,6 +112,7 @@ fn main() {
             persistor_trade.finish(),
             persistor_order.finish(),
             persistor_balance.finish(),
+            persistor_internal_tx.finish(),
         )
         .expect("all persistor should success finish");
         let final_commits: Vec<Pin<Box<dyn std::future::Future<Output = ()> + Send>>> = auto_commit
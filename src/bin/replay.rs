@@ -0,0 +1,319 @@
+use dingir_exchange::asset::BalanceType;
+use dingir_exchange::config;
+use dingir_exchange::controller::{create_replay_controller, Controller};
+use dingir_exchange::models::{OrderSlice, SliceHistory};
+use dingir_exchange::persist::{self, StateSnapshot};
+use dingir_exchange::state_hash::StateHash;
+use dingir_exchange::types::{ConnectionType, OrderSide};
+use fluidex_common::non_blocking_tracing;
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::Connection;
+use std::collections::{BTreeMap, BTreeSet};
+
+const USAGE: &str = "usage:
+    replay [options] balances [user_id...]   print the balances, of every user by default
+    replay [options] orders [market...]      print the order books, of every market by default
+    replay [options] export <file>           write the balances and order books as json
+    replay [options] diff <slice_id>         compare the state with a slice of the db, replaying up
+                                             to the end of that slice by default
+options:
+    --slice <slice_id>     start from this slice of the db, the latest one before the target by default
+    --file <file>          start from a snapshot file instead
+    --until-id <id>        replay the operation log up to and including this id, all of it by default
+    --until-time <secs>    replay the operations executed up to this unix time";
+
+#[derive(Default)]
+struct Options {
+    slice_id: Option<i64>,
+    file: Option<String>,
+    until_id: Option<u64>,
+    until_time: Option<f64>,
+}
+
+fn main() {
+    dotenv::dotenv().ok();
+    let _guard = non_blocking_tracing::setup();
+
+    let mut options = Options::default();
+    let mut args: Vec<String> = Vec::new();
+    let mut iter = std::env::args().skip(1);
+    let ret = (|| -> anyhow::Result<()> {
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().ok_or_else(|| anyhow::anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "--slice" => options.slice_id = Some(value()?.parse()?),
+                "--file" => options.file = Some(value()?),
+                "--until-id" => options.until_id = Some(value()?.parse()?),
+                "--until-time" => options.until_time = Some(value()?.parse()?),
+                _ => args.push(arg),
+            }
+        }
+        Ok(())
+    })();
+    if let Err(e) = ret {
+        eprintln!("{}\n{}", e, USAGE);
+        std::process::exit(1);
+    }
+
+    let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build runtime");
+
+    let ret = rt.block_on(async {
+        match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["balances", users @ ..] => balances(options, users).await,
+            ["orders", markets @ ..] => orders(options, markets).await,
+            ["export", file] => export(options, file).await,
+            ["diff", slice_id] => diff(options, slice_id.parse()?).await,
+            _ => {
+                println!("{}", USAGE);
+                Ok(true)
+            }
+        }
+    });
+    match ret {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(2),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Rebuilds the engine state at the target operation log id. Nothing is published or written
+// while replaying, the operations go through the dummy persistor. `diff_slice` is the slice the
+// state is compared with, it is the default target and never the starting point.
+async fn replay(conn: &mut ConnectionType, options: &Options, diff_slice: Option<&SliceHistory>) -> anyhow::Result<Controller> {
+    let until_id = match (options.until_id, options.until_time, diff_slice) {
+        (Some(id), _, _) => id,
+        (None, Some(time), _) => persist::get_operation_log_id_at(conn, time).await?,
+        (None, None, Some(slice)) => slice.end_operation_log_id as u64,
+        (None, None, None) => persist::get_last_operation_log_id(conn).await?,
+    };
+    let start_before_id = match diff_slice {
+        Some(slice) => until_id.min(slice.end_operation_log_id as u64).saturating_sub(1),
+        None => until_id,
+    };
+
+    let mut settings = config::Settings::new();
+    let snapshot = match (&options.file, options.slice_id) {
+        (Some(file), _) => Some(StateSnapshot::read_file(file)?),
+        (None, Some(slice_id)) => {
+            let slice = persist::get_slice(conn, slice_id).await?;
+            let slice = slice.ok_or_else(|| anyhow::anyhow!("slice {} not found", slice_id))?;
+            Some(persist::load_slice_from_db(conn, slice).await?)
+        }
+        (None, None) => match persist::get_slice_before(conn, start_before_id).await? {
+            Some(slice) => Some(persist::load_slice_from_db(conn, slice).await?),
+            None => None,
+        },
+    };
+    // a snapshot file carries the markets and assets it was taken with
+    let market_cfg = match &snapshot {
+        Some(snapshot) if !snapshot.markets.is_empty() => {
+            settings.market_from_db = false;
+            settings.assets = snapshot.assets.clone();
+            settings.markets = snapshot.markets.clone();
+            persist::MarketConfigs::new()
+        }
+        _ => persist::init_config_from_db(conn, &mut settings).await?,
+    };
+    let mut controller = create_replay_controller((settings, market_cfg));
+
+    if let Some(snapshot) = snapshot {
+        log::info!("start from slice {:?}", snapshot.slice_history);
+        let end_operation_log_id = snapshot.restore(&mut controller)?;
+        if end_operation_log_id > until_id {
+            anyhow::bail!("the slice ends at operation log {}, after {}", end_operation_log_id, until_id);
+        }
+        controller.sequencer.set_operation_log_id(end_operation_log_id);
+    }
+    persist::replay_operation_log_until(conn, until_id, &mut controller).await?;
+    let replayed = controller.sequencer.get_operation_log_id();
+    if replayed < until_id {
+        log::warn!("the operation log ends at {}, before {}", replayed, until_id);
+    }
+    eprintln!("replayed to operation log {}", replayed);
+    Ok(controller)
+}
+
+async fn connect() -> anyhow::Result<ConnectionType> {
+    let settings = config::Settings::new();
+    Ok(ConnectionType::connect(&settings.db_log).await?)
+}
+
+async fn balances(options: Options, users: &[&str]) -> anyhow::Result<bool> {
+    let mut conn = connect().await?;
+    let controller = replay(&mut conn, &options, None).await?;
+    let state = State::of(&StateSnapshot::take(0, &controller));
+    for balance in state.balances {
+        if users.is_empty() || users.contains(&balance.user_id.as_str()) {
+            println!(
+                "{} {} {:?} {}",
+                balance.user_id, balance.asset, balance.balance_type, balance.amount
+            );
+        }
+    }
+    Ok(true)
+}
+
+async fn orders(options: Options, markets: &[&str]) -> anyhow::Result<bool> {
+    let mut conn = connect().await?;
+    let controller = replay(&mut conn, &options, None).await?;
+    let state = State::of(&StateSnapshot::take(0, &controller));
+    for (market, orders) in state.orders {
+        if !markets.is_empty() && !markets.contains(&market.as_str()) {
+            continue;
+        }
+        println!("{}", market);
+        for order in orders {
+            let stop = if order.trigger_price.is_some() { " stop" } else { "" };
+            println!(
+                "    {} {:?}{} price {} remain {} of {} user {}",
+                order.id, order.order_side, stop, order.price, order.remain, order.amount, order.user_id
+            );
+        }
+    }
+    Ok(true)
+}
+
+async fn export(options: Options, file: &str) -> anyhow::Result<bool> {
+    let mut conn = connect().await?;
+    let controller = replay(&mut conn, &options, None).await?;
+    let state = State::of(&StateSnapshot::take(0, &controller));
+    std::fs::write(file, serde_json::to_string_pretty(&state)?)?;
+    Ok(true)
+}
+
+// prints what differs and returns whether the states are the same
+async fn diff(options: Options, slice_id: i64) -> anyhow::Result<bool> {
+    let mut conn = connect().await?;
+    let slice = persist::get_slice(&mut conn, slice_id).await?;
+    let slice = slice.ok_or_else(|| anyhow::anyhow!("slice {} not found", slice_id))?;
+    let end_operation_log_id = slice.end_operation_log_id as u64;
+    let controller = replay(&mut conn, &options, Some(&slice)).await?;
+    let other = State::of(&persist::load_slice_from_db(&mut conn, slice).await?);
+    if controller.sequencer.get_operation_log_id() != end_operation_log_id {
+        eprintln!("the slice ends at operation log {}", end_operation_log_id);
+    }
+    let state = State::of(&StateSnapshot::take(0, &controller));
+    println!(
+        "state hash {} replayed, {} in slice {}",
+        state.state_hash, other.state_hash, slice_id
+    );
+    if state.state_hash == other.state_hash {
+        return Ok(true);
+    }
+
+    let balances: BTreeMap<_, _> = state.balances.iter().map(|balance| (balance.key(), balance.amount)).collect();
+    let other_balances: BTreeMap<_, _> = other.balances.iter().map(|balance| (balance.key(), balance.amount)).collect();
+    for key in balances.keys().chain(other_balances.keys()).collect::<BTreeSet<_>>() {
+        let (ours, theirs) = (balances.get(key), other_balances.get(key));
+        if ours != theirs {
+            println!(
+                "balance {} {} {}: {} replayed, {} in slice",
+                key.0,
+                key.1,
+                key.2,
+                show(ours),
+                show(theirs)
+            );
+        }
+    }
+
+    let orders = order_values(&state)?;
+    let other_orders = order_values(&other)?;
+    for id in orders.keys().chain(other_orders.keys()).collect::<BTreeSet<_>>() {
+        match (orders.get(id), other_orders.get(id)) {
+            (Some(_), None) => println!("order {} only replayed", id),
+            (None, Some(_)) => println!("order {} only in slice", id),
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                println!("order {}", id);
+                println!("    replayed {}", ours);
+                println!("    in slice {}", theirs);
+            }
+            _ => (),
+        }
+    }
+    Ok(false)
+}
+
+fn show(amount: Option<&Decimal>) -> String {
+    amount.map_or_else(|| "nothing".to_string(), |amount| amount.to_string())
+}
+
+// the orders by id, without the slice they were read from
+fn order_values(state: &State) -> anyhow::Result<BTreeMap<i64, serde_json::Value>> {
+    let mut values = BTreeMap::new();
+    for order in state.orders.values().flatten() {
+        let mut order = order.clone();
+        order.slice_id = 0;
+        values.insert(order.id, serde_json::to_value(&order)?);
+    }
+    Ok(values)
+}
+
+#[derive(Serialize)]
+struct Balance {
+    user_id: String,
+    asset: String,
+    balance_type: BalanceType,
+    amount: Decimal,
+}
+
+impl Balance {
+    fn key(&self) -> (String, String, String) {
+        (self.user_id.clone(), self.asset.clone(), format!("{:?}", self.balance_type))
+    }
+}
+
+// the balances and order books in a stable order, zero balances left out
+#[derive(Serialize)]
+struct State {
+    operation_log_id: i64,
+    state_hash: String,
+    balances: Vec<Balance>,
+    // asks from the lowest price, then bids from the highest
+    orders: BTreeMap<String, Vec<OrderSlice>>,
+}
+
+impl State {
+    fn of(snapshot: &StateSnapshot) -> Self {
+        let mut balances: Vec<Balance> = snapshot
+            .balances
+            .iter()
+            .filter(|(_, amount)| !amount.is_zero())
+            .map(|(key, amount)| Balance {
+                user_id: key.user_id.to_string(),
+                asset: key.asset.clone(),
+                balance_type: key.balance_type,
+                amount: amount.normalize(),
+            })
+            .collect();
+        balances.sort_by(|a, b| (&a.user_id, &a.asset, a.balance_type as i16).cmp(&(&b.user_id, &b.asset, b.balance_type as i16)));
+
+        let mut orders: BTreeMap<String, Vec<OrderSlice>> = BTreeMap::new();
+        for order in &snapshot.orders {
+            orders.entry(order.market.clone()).or_default().push(order.clone());
+        }
+        for orders in orders.values_mut() {
+            orders.sort_by(|a, b| match (a.order_side, b.order_side) {
+                (OrderSide::ASK, OrderSide::ASK) => (a.price, a.id).cmp(&(b.price, b.id)),
+                (OrderSide::BID, OrderSide::BID) => (b.price, a.id).cmp(&(a.price, b.id)),
+                (OrderSide::ASK, OrderSide::BID) => std::cmp::Ordering::Less,
+                (OrderSide::BID, OrderSide::ASK) => std::cmp::Ordering::Greater,
+            });
+        }
+
+        Self {
+            operation_log_id: snapshot.slice_history.end_operation_log_id,
+            state_hash: StateHash::of(snapshot).root_hex(),
+            balances,
+            orders,
+        }
+    }
+}
@@ -4,6 +4,7 @@ use actix_web_httpauth::extractors::AuthenticationError;
 use actix_web_httpauth::middleware::HttpAuthentication;
 use dingir_exchange::matchengine::authentication;
 use dingir_exchange::matchengine::authentication::UserExtension;
+use dingir_exchange::restapi::balances::{all_balances_at, my_balances_at};
 use dingir_exchange::restapi::manage::market;
 use dingir_exchange::restapi::personal_history::my_orders;
 use dingir_exchange::restapi::public_history::{order_trades, recent_trades};
@@ -24,14 +25,15 @@ const PUBLIC_ENDPOINTS: [&str; 6] = [
     "/api/exchange/panel/tradingview",
     "/api/spec",
 ];
-const ADMIN_ENDPOINTS: [&str; 1] = ["/api/exchange/panel/manage"];
+const ADMIN_ENDPOINTS: [&str; 2] = ["/api/exchange/panel/manage", "/api/exchange/panel/balances/all"];
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
     let _guard = non_blocking_tracing::setup();
 
-    let db_url = dingir_exchange::config::Settings::new().db_history;
+    let engine_settings = dingir_exchange::config::Settings::new();
+    let db_url = engine_settings.db_history;
     log::debug!("Prepared DB connection: {}", &db_url);
 
     let config = dingir_exchange::restapi::config::Settings::new();
@@ -52,6 +54,7 @@ async fn main() -> std::io::Result<()> {
     let user_map = web::Data::new(AppState {
         manage_channel,
         db: Pool::<Postgres>::connect(&db_url).await.unwrap(),
+        db_log: Pool::<Postgres>::connect(&engine_settings.db_log).await.unwrap(),
         config,
     });
 
@@ -70,6 +73,8 @@ async fn main() -> std::io::Result<()> {
                     .route("/recenttrades/{market}", web::get().to(recent_trades))
                     .route("/ordertrades/{market}/{order_id}", web::get().to(order_trades))
                     .route("/closedorders/{market}", web::get().to(my_orders))
+                    .route("/balances/at", web::get().to(my_balances_at))
+                    .route("/balances/all", web::get().to(all_balances_at))
                     .route("/ticker_{ticker_inv}/{market}", web::get().to(ticker))
                     .service(
                         web::scope("/tradingview")
@@ -84,7 +89,8 @@ async fn main() -> std::io::Result<()> {
                             web::scope("/market")
                                 .route("/reload", web::get().to(market::reload))
                                 .route("/tradepairs", web::post().to(market::add_pair))
-                                .route("/assets", web::post().to(market::add_assets)),
+                                .route("/assets", web::post().to(market::add_assets))
+                                .route("/assets/limits", web::post().to(market::update_asset_limits)),
                         )
                     } else {
                         web::scope("/manage")
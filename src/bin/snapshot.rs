@@ -0,0 +1,130 @@
+use dingir_exchange::controller::create_replay_controller;
+use dingir_exchange::persist::{self, SnapshotFileHeader, StateSnapshot};
+use dingir_exchange::state_hash::StateHash;
+use dingir_exchange::{config, types};
+use fluidex_common::non_blocking_tracing;
+use sqlx::Connection;
+use types::ConnectionType;
+
+const USAGE: &str = "usage:
+    snapshot inspect <file>               check a snapshot file and print what it holds
+    snapshot export <file> [slice_id]     write a slice from the db into a file, the latest one by default
+    snapshot import <file>                write a snapshot file into the slice tables of the db
+    snapshot hash <operation_log_id>...   replay the db operation log and print the state hash at each id";
+
+fn main() {
+    dotenv::dotenv().ok();
+    let _guard = non_blocking_tracing::setup();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build runtime");
+
+    let ret = rt.block_on(async {
+        match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["inspect", file] => inspect(file),
+            ["export", file] => export(file, None).await,
+            ["export", file, slice_id] => export(file, Some(slice_id.parse()?)).await,
+            ["import", file] => import(file).await,
+            ["hash", ids @ ..] if !ids.is_empty() => hash(ids).await,
+            _ => {
+                println!("{}", USAGE);
+                Ok(())
+            }
+        }
+    });
+    if let Err(e) = ret {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
+}
+
+fn inspect(file: &str) -> anyhow::Result<()> {
+    let bytes = std::fs::read(file)?;
+    let (header, _) = SnapshotFileHeader::parse(&bytes)?;
+    let snapshot = StateSnapshot::decode(&bytes)?;
+    let slice = &snapshot.slice_history;
+    println!("version: {}", header.version);
+    println!("content: {} bytes, crc32 {:08x}", header.content_len, header.checksum);
+    println!("slice id: {}", slice.time);
+    println!("state hash: {}", slice.state_hash.as_deref().unwrap_or("-"));
+    println!(
+        "end operation_log_id: {}, order_id: {}, trade_id: {}",
+        slice.end_operation_log_id, slice.end_order_id, slice.end_trade_id
+    );
+    let markets: Vec<&str> = snapshot.markets.iter().map(|market| market.name.as_str()).collect();
+    println!("markets: {}", markets.join(", "));
+    let assets: Vec<&str> = snapshot.assets.iter().map(|asset| asset.id.as_str()).collect();
+    println!("assets: {}", assets.join(", "));
+    println!("balances: {}", snapshot.balances.len());
+    println!("orders: {}", snapshot.orders.len());
+    println!("mmp configs: {}", snapshot.mmps.len());
+    println!("sub-accounts: {}", snapshot.sub_accounts.len());
+    println!("business ids: {}", snapshot.business_ids.len());
+    println!("asset ledgers: {}", snapshot.asset_ledgers.len());
+    println!("withdraw quotas: {}", snapshot.withdraw_quotas.len());
+    println!("withdraw usages: {}", snapshot.withdraw_usages.len());
+    println!("account locks: {}", snapshot.account_locks.len());
+    println!("vestings: {}", snapshot.vestings.len());
+    println!("pending deposits: {}", snapshot.deposits.len());
+    println!("pending withdraws: {}", snapshot.withdraws.len());
+    Ok(())
+}
+
+async fn export(file: &str, slice_id: Option<i64>) -> anyhow::Result<()> {
+    let settings = config::Settings::new();
+    let mut conn = ConnectionType::connect(&settings.db_log).await?;
+    let slice = match slice_id {
+        Some(slice_id) => persist::get_slice(&mut conn, slice_id).await?,
+        None => persist::get_last_slice(&mut conn).await,
+    };
+    let slice = slice.ok_or_else(|| anyhow::anyhow!("slice not found"))?;
+    let mut snapshot = persist::load_slice_from_db(&mut conn, slice).await?;
+    let mut market_cfg = persist::MarketConfigs::new();
+    snapshot.assets = market_cfg.load_asset_from_db(&mut conn).await?;
+    snapshot.markets = market_cfg.load_market_from_db(&mut conn).await?;
+    snapshot.write_file(file)
+}
+
+async fn import(file: &str) -> anyhow::Result<()> {
+    let settings = config::Settings::new();
+    let snapshot = StateSnapshot::read_file(file)?;
+    let slice_id = snapshot.slice_id();
+    let mut conn = ConnectionType::connect(&settings.db_log).await?;
+    if let Some(slice) = persist::get_slice(&mut conn, slice_id).await? {
+        anyhow::bail!("slice {} is already in the db: {:?}", slice_id, slice);
+    }
+    snapshot.dump(&mut conn).await?;
+    println!("slice {} imported", slice_id);
+    Ok(())
+}
+
+// run it against the db of each engine and diff the output to find where they diverged
+async fn hash(ids: &[&str]) -> anyhow::Result<()> {
+    let mut ids = ids.iter().map(|id| id.parse()).collect::<Result<Vec<u64>, _>>()?;
+    ids.sort_unstable();
+    let mut settings = config::Settings::new();
+    let mut conn = ConnectionType::connect(&settings.db_log).await?;
+    let market_cfg = if settings.market_from_db {
+        persist::init_config_from_db(&mut conn, &mut settings).await?
+    } else {
+        persist::MarketConfigs::new()
+    };
+    let mut controller = create_replay_controller((settings, market_cfg));
+    if let Some(slice) = persist::get_slice_before(&mut conn, ids[0]).await? {
+        let end_operation_log_id = persist::load_slice_from_db(&mut conn, slice).await?.restore(&mut controller)?;
+        controller.sequencer.set_operation_log_id(end_operation_log_id);
+    }
+    for id in ids {
+        persist::replay_operation_log_until(&mut conn, id, &mut controller).await?;
+        let hash = StateHash::of(&StateSnapshot::take(0, &controller));
+        println!("{} {}", id, hash.root_hex());
+        println!("    balances {}", hex::encode(hash.balances));
+        for (market, root) in &hash.markets {
+            println!("    {} {}", market, hex::encode(root));
+        }
+    }
+    Ok(())
+}
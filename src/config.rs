@@ -13,6 +13,25 @@ pub struct Asset {
     pub name: String,
     pub prec_save: u32,
     pub prec_show: u32,
+    // a pending deposit is credited once it has this many confirmations
+    pub deposit_confirmations: u32,
+    // limits are in the asset unit, zero means no limit
+    pub min_deposit: Decimal,
+    pub min_withdraw: Decimal,
+    pub max_withdraw: Decimal,
+    // the most a user can withdraw within any 24 hours
+    pub withdraw_daily_quota: Decimal,
+}
+
+// the limits of an asset, updated apart from the rest of it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default, Apiv2Schema)]
+#[serde(default)]
+pub struct AssetLimits {
+    pub asset_id: String,
+    pub min_deposit: Decimal,
+    pub min_withdraw: Decimal,
+    pub max_withdraw: Decimal,
+    pub withdraw_daily_quota: Decimal,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -79,6 +98,86 @@ impl<'de> de::Deserialize<'de> for PersistPolicy {
     }
 }
 
+// how the periodical slices are made: in a forked child process, or from an in-process
+// snapshot written by a background task
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SliceMode {
+    Fork,
+    Snapshot,
+}
+
+impl<'de> de::Deserialize<'de> for SliceMode {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        match s.as_ref() {
+            "Fork" | "fork" => Ok(SliceMode::Fork),
+            "Snapshot" | "snapshot" => Ok(SliceMode::Snapshot),
+            _ => Err(serde::de::Error::custom("unexpected specification for slice mode")),
+        }
+    }
+}
+
+// where slices are written to and loaded from, the operation log always stays in the db
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SliceStorage {
+    Db,
+    File,
+}
+
+impl<'de> de::Deserialize<'de> for SliceStorage {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        match s.as_ref() {
+            "Db" | "db" | "DB" => Ok(SliceStorage::Db),
+            "File" | "file" => Ok(SliceStorage::File),
+            _ => Err(serde::de::Error::custom("unexpected specification for slice storage")),
+        }
+    }
+}
+
+// what happens to the operation logs every kept slice already covers
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum OperationLogRetention {
+    Keep,
+    Archive,
+    Prune,
+}
+
+impl<'de> de::Deserialize<'de> for OperationLogRetention {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        match s.as_ref() {
+            "Keep" | "keep" => Ok(OperationLogRetention::Keep),
+            "Archive" | "archive" => Ok(OperationLogRetention::Archive),
+            "Prune" | "prune" => Ok(OperationLogRetention::Prune),
+            _ => Err(serde::de::Error::custom("unexpected specification for operation log retention")),
+        }
+    }
+}
+
+// a leader executes the operations, a follower replays the leader's operation log and only
+// answers queries until it is promoted
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Role {
+    Leader,
+    Follower,
+}
+
+impl<'de> de::Deserialize<'de> for Role {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        match s.as_ref() {
+            "Leader" | "leader" => Ok(Role::Leader),
+            "Follower" | "follower" => Ok(Role::Follower),
+            _ => Err(serde::de::Error::custom("unexpected specification for role")),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(default)]
 pub struct Settings {
@@ -98,6 +197,39 @@ pub struct Settings {
     pub persist_interval: i32,
     pub slice_interval: i32,
     pub slice_keeptime: i32,
+    pub slice_mode: SliceMode,
+    pub slice_storage: SliceStorage,
+    // the snapshot file used when slice_storage is file
+    pub slice_file: String,
+    // slices kept in the db: the latest ones, and the latest of each recent day and week
+    pub slice_keep_recent: usize,
+    pub slice_keep_daily: i32,
+    pub slice_keep_weekly: i32,
+    pub operation_log_retention: OperationLogRetention,
+    // directory of the operation write-ahead log, the log only goes to the db when it is empty
+    pub wal_dir: String,
+    // bytes written to a wal segment before the next one is started
+    pub wal_segment_size: u64,
+    // the file persistor, used when no brokers are set: a directory of json line segments, each
+    // closed after the size in bytes or the seconds of messages it holds, then gzipped if asked to
+    pub message_file_dir: String,
+    pub message_file_segment_size: u64,
+    pub message_file_segment_interval: i32,
+    pub message_file_compress: bool,
+    pub role: Role,
+    // seconds between two polls of the operation log by a follower, and between two checks of
+    // the leader lock and saves of the outbox cursor by the leader
+    pub follow_interval: f64,
+    // operation log ids the leader reserves ahead for its wal at each renewal of its lease, a
+    // successor of a leader that did not leave waits for the db to have all of them
+    pub operation_log_reserve: u64,
+    // seconds an applied balance update business id is kept for deduplication, this includes the
+    // creation of a vesting allocation that is fully released
+    pub business_id_keeptime: i32,
+    // run the ledger auditor each time a slice is made
+    pub audit_after_slice: bool,
+    // seconds between two checks for unlocked vesting allocations
+    pub vesting_release_interval: i32,
     pub history_thread: i32,
     pub cache_timeout: f64,
     pub disable_self_trade: bool,
@@ -124,6 +256,25 @@ impl Default for Settings {
             persist_interval: 3600,
             slice_interval: 86400,
             slice_keeptime: 86400 * 3,
+            slice_mode: SliceMode::Fork,
+            slice_storage: SliceStorage::Db,
+            slice_file: "slice.snapshot".to_string(),
+            slice_keep_recent: 3,
+            slice_keep_daily: 7,
+            slice_keep_weekly: 4,
+            operation_log_retention: OperationLogRetention::Keep,
+            wal_dir: String::new(),
+            wal_segment_size: 64 * 1024 * 1024,
+            message_file_dir: "persistor_output".to_string(),
+            message_file_segment_size: 64 * 1024 * 1024,
+            message_file_segment_interval: 86400,
+            message_file_compress: true,
+            role: Role::Leader,
+            follow_interval: 0.5,
+            operation_log_reserve: 100000,
+            business_id_keeptime: 86400 * 7,
+            audit_after_slice: false,
+            vesting_release_interval: 60,
             history_thread: 10,
             cache_timeout: 0.45,
             disable_self_trade: true,
@@ -5,7 +5,7 @@
 #![allow(clippy::single_char_pattern)]
 
 pub mod matchengine;
-pub use matchengine::{asset, controller, dto, history, market, persist, sequencer, server};
+pub use matchengine::{asset, audit, clock, controller, dto, history, market, persist, sequencer, server, state_hash};
 pub mod storage;
 pub use storage::{database, models, sqlxextend};
 pub mod config;
@@ -0,0 +1,56 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+// Accounts locked by admins, e.g. during an investigation. A locked account can not place
+// orders or withdraw, but can still receive deposits. Not to be confused with the FREEZE
+// balance, which backs open orders.
+#[derive(Default)]
+pub struct AccountLockManager {
+    // (user, asset) -> reason, an empty asset locks every asset of the user
+    pub locks: BTreeMap<(Uuid, String), String>,
+}
+
+impl AccountLockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn reset(&mut self) {
+        self.locks.clear()
+    }
+    pub fn lock(&mut self, user_id: Uuid, asset: &str, reason: &str) {
+        self.locks.insert((user_id, asset.to_owned()), reason.to_owned());
+    }
+    pub fn unlock(&mut self, user_id: Uuid, asset: &str) -> Result<()> {
+        if self.locks.remove(&(user_id, asset.to_owned())).is_none() {
+            bail!("not locked");
+        }
+        Ok(())
+    }
+    pub fn is_account_locked(&self, user_id: Uuid) -> bool {
+        self.locks.contains_key(&(user_id, String::new()))
+    }
+    pub fn is_locked(&self, user_id: Uuid, asset: &str) -> bool {
+        self.is_account_locked(user_id) || self.locks.contains_key(&(user_id, asset.to_owned()))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_account_lock() {
+    use std::str::FromStr;
+
+    let alice = Uuid::from_str("0f0e0d0c-0b0a-4908-8706-050403020100").unwrap();
+    let bob = Uuid::from_str("1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d").unwrap();
+    let mut manager = AccountLockManager::new();
+    manager.lock(alice, "ETH", "investigation");
+    assert!(manager.is_locked(alice, "ETH"));
+    assert!(!manager.is_locked(alice, "USDT"));
+    assert!(!manager.is_account_locked(alice));
+    manager.lock(bob, "", "investigation");
+    assert!(manager.is_locked(bob, "ETH"));
+    assert!(manager.is_locked(bob, "USDT"));
+    assert!(manager.unlock(alice, "USDT").is_err());
+    manager.unlock(alice, "ETH").unwrap();
+    assert!(!manager.is_locked(alice, "ETH"));
+}
@@ -1,7 +1,7 @@
 use crate::config;
 use crate::market::{Market, OrderCommitment};
 use anyhow::{bail, Result};
-use fluidex_common::rust_decimal::{self, RoundingStrategy};
+use fluidex_common::rust_decimal::{self, Decimal, RoundingStrategy};
 use fluidex_common::types::DecimalExt;
 use orchestra::rpc::exchange::*;
 use serde::{Deserialize, Serialize};
@@ -12,6 +12,11 @@ use std::str::FromStr;
 pub struct AssetInfo {
     pub prec_save: u32,
     pub prec_show: u32,
+    pub deposit_confirmations: u32,
+    pub min_deposit: Decimal,
+    pub min_withdraw: Decimal,
+    pub max_withdraw: Decimal,
+    pub withdraw_daily_quota: Decimal,
     pub inner_id: u32,
 }
 
@@ -30,6 +35,11 @@ impl AssetManager {
                 AssetInfo {
                     prec_save: item.prec_save,
                     prec_show: item.prec_show,
+                    deposit_confirmations: item.deposit_confirmations,
+                    min_deposit: item.min_deposit,
+                    min_withdraw: item.min_withdraw,
+                    max_withdraw: item.max_withdraw,
+                    withdraw_daily_quota: item.withdraw_daily_quota,
                     inner_id: u32::from_str_radix(&item.id, 36).unwrap(), // turn string into unique u32
                 },
             );
@@ -45,6 +55,11 @@ impl AssetManager {
                 AssetInfo {
                     prec_save: item.prec_save,
                     prec_show: item.prec_show,
+                    deposit_confirmations: item.deposit_confirmations,
+                    min_deposit: item.min_deposit,
+                    min_withdraw: item.min_withdraw,
+                    max_withdraw: item.max_withdraw,
+                    withdraw_daily_quota: item.withdraw_daily_quota,
                     inner_id: u32::from_str_radix(&item.id, 36).unwrap(), // turn string into unique u32
                 },
             );
@@ -68,6 +83,9 @@ impl AssetManager {
     pub fn asset_prec_show(&self, id: &str) -> u32 {
         self.asset_get(id).unwrap().prec_show
     }
+    pub fn asset_deposit_confirmations(&self, id: &str) -> u32 {
+        self.asset_get(id).unwrap().deposit_confirmations
+    }
 
     pub fn commit_order(&self, o: &OrderPutRequest, market: &Market) -> Result<OrderCommitment> {
         let assets: Vec<&str> = o.market.split('_').collect();
@@ -8,7 +8,6 @@ use fluidex_common::rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
 use num_enum::TryFromPrimitive;
-use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash, Copy, TryFromPrimitive)]
@@ -16,6 +15,12 @@ use uuid::Uuid;
 pub enum BalanceType {
     AVAILABLE = 1,
     FREEZE = 2,
+    // locked by a withdrawal waiting for the admin to confirm or reject it
+    WITHDRAWING = 3,
+    // credited by a deposit still waiting for enough confirmations
+    DEPOSITING = 4,
+    // a vesting allocation not unlocked yet
+    LOCKED = 5,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash)]
@@ -32,12 +37,19 @@ pub struct BalanceStatus {
     pub available: Decimal,
     pub frozen_count: u32,
     pub frozen: Decimal,
+    pub withdrawing_count: u32,
+    pub withdrawing: Decimal,
+    pub depositing_count: u32,
+    pub depositing: Decimal,
+    pub locked_count: u32,
+    pub locked: Decimal,
 }
 
 //#[derive(default)]
 pub struct BalanceManager {
     pub asset_manager: AssetManager,
-    pub balances: HashMap<BalanceMapKey, Decimal>,
+    // a persistent map, so a slice snapshot can hold a copy of it for cheap
+    pub balances: im::HashMap<BalanceMapKey, Decimal>,
 }
 
 impl BalanceManager {
@@ -45,7 +57,7 @@ impl BalanceManager {
         let asset_manager = AssetManager::new(asset_config)?;
         Ok(BalanceManager {
             asset_manager,
-            balances: HashMap::new(),
+            balances: im::HashMap::new(),
         })
     }
 
@@ -169,12 +181,27 @@ impl BalanceManager {
         for (k, amount) in self.balances.iter() {
             if k.asset.eq(asset) && !amount.is_zero() {
                 result.total += amount;
-                if k.balance_type == BalanceType::AVAILABLE {
-                    result.available_count += 1;
-                    result.available += amount;
-                } else {
-                    result.frozen_count += 1;
-                    result.frozen += amount;
+                match k.balance_type {
+                    BalanceType::AVAILABLE => {
+                        result.available_count += 1;
+                        result.available += amount;
+                    }
+                    BalanceType::FREEZE => {
+                        result.frozen_count += 1;
+                        result.frozen += amount;
+                    }
+                    BalanceType::WITHDRAWING => {
+                        result.withdrawing_count += 1;
+                        result.withdrawing += amount;
+                    }
+                    BalanceType::DEPOSITING => {
+                        result.depositing_count += 1;
+                        result.depositing += amount;
+                    }
+                    BalanceType::LOCKED => {
+                        result.locked_count += 1;
+                        result.locked += amount;
+                    }
                 }
             }
         }
@@ -1,6 +1,14 @@
+pub mod account_lock_manager;
 pub mod asset_manager;
 pub mod balance_manager;
+pub mod sub_account_manager;
 pub mod update_controller;
+pub mod vesting;
+pub mod withdraw_quota;
+pub use account_lock_manager::*;
 pub use asset_manager::*;
 pub use balance_manager::*;
+pub use sub_account_manager::*;
 pub use update_controller::*;
+pub use vesting::*;
+pub use withdraw_quota::*;
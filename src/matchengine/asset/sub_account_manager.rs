@@ -0,0 +1,87 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+const MAX_SUB_ACCOUNT_NUM: usize = 64;
+const MAX_SUB_ACCOUNT_NAME_LEN: usize = 30;
+
+// A sub-account has its own balances and orders. Its id is derived from the owner and
+// the sub-account name, so nobody else can claim it. The owner id itself is the master account.
+#[derive(Default)]
+pub struct SubAccountManager {
+    // owner -> (name -> account id)
+    pub accounts: BTreeMap<Uuid, BTreeMap<String, Uuid>>,
+}
+
+impl SubAccountManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn reset(&mut self) {
+        self.accounts.clear()
+    }
+    pub fn account_id(owner: &Uuid, name: &str) -> Uuid {
+        Uuid::new_v5(owner, name.as_bytes())
+    }
+    pub fn create(&mut self, owner: Uuid, name: &str) -> Result<Uuid> {
+        if name.is_empty() || name.len() > MAX_SUB_ACCOUNT_NAME_LEN {
+            bail!("invalid sub-account name");
+        }
+        let sub_accounts = self.accounts.entry(owner).or_insert_with(BTreeMap::new);
+        if sub_accounts.contains_key(name) {
+            bail!("sub-account already exists");
+        }
+        if sub_accounts.len() >= MAX_SUB_ACCOUNT_NUM {
+            bail!("too many sub-accounts");
+        }
+        let account_id = Self::account_id(&owner, name);
+        sub_accounts.insert(name.to_owned(), account_id);
+        Ok(account_id)
+    }
+    // an empty name means the master account
+    pub fn resolve(&self, owner: Uuid, name: &str) -> Result<Uuid> {
+        if name.is_empty() {
+            return Ok(owner);
+        }
+        match self.accounts.get(&owner).and_then(|sub_accounts| sub_accounts.get(name)) {
+            Some(account_id) => Ok(*account_id),
+            None => bail!("invalid sub-account"),
+        }
+    }
+    // the master account of a sub-account, a master account is its own owner
+    pub fn owner(&self, account_id: &Uuid) -> Uuid {
+        self.accounts
+            .iter()
+            .find(|(_, sub_accounts)| sub_accounts.values().any(|id| id == account_id))
+            .map(|(owner, _)| *owner)
+            .unwrap_or(*account_id)
+    }
+    pub fn list(&self, owner: &Uuid) -> Vec<(String, Uuid)> {
+        self.accounts
+            .get(owner)
+            .map(|sub_accounts| sub_accounts.iter().map(|(name, account_id)| (name.clone(), *account_id)).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_sub_accounts() {
+    use std::str::FromStr;
+
+    let alice = Uuid::from_str("0f0e0d0c-0b0a-4908-8706-050403020100").unwrap();
+    let bob = Uuid::from_str("1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d").unwrap();
+    let mut manager = SubAccountManager::new();
+    let alice_hedge = manager.create(alice, "hedge").unwrap();
+    assert!(manager.create(alice, "hedge").is_err());
+    assert!(manager.create(alice, "").is_err());
+    // the same name under another owner is another account
+    let bob_hedge = manager.create(bob, "hedge").unwrap();
+    assert_ne!(alice_hedge, bob_hedge);
+    assert_eq!(manager.resolve(alice, "").unwrap(), alice);
+    assert_eq!(manager.resolve(alice, "hedge").unwrap(), alice_hedge);
+    assert!(manager.resolve(alice, "arb").is_err());
+    assert_eq!(manager.owner(&alice_hedge), alice);
+    assert_eq!(manager.owner(&alice), alice);
+    assert_eq!(manager.list(&alice), vec![(String::from("hedge"), alice_hedge)]);
+}
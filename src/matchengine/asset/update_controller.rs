@@ -1,18 +1,30 @@
 use super::balance_manager::{BalanceManager, BalanceType};
+use super::vesting::{Vesting, VestingSchedule};
+use crate::clock;
 use crate::models;
 use crate::persist::PersistExector;
-use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
-pub use models::BalanceHistory;
+use crate::types::{DepositStatus, WithdrawStatus};
+use fluidex_common::utils::timeutil::FTimestamp;
+pub use models::{BalanceHistory, InternalTx};
 
 use anyhow::{bail, Result};
+use fluidex_common::rust_decimal::prelude::Zero;
 use fluidex_common::rust_decimal::Decimal;
-use ttl_cache::TtlCache;
+use serde_json::json;
 use uuid::Uuid;
 
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 const BALANCE_MAP_INIT_SIZE_ASSET: usize = 64;
 const PERSIST_ZERO_BALANCE_UPDATE: bool = false;
+// seconds an applied business id is remembered, unless configured
+const DEFAULT_BUSINESS_ID_KEEPTIME: f64 = 86400.0 * 7.0;
+
+// business names of the two balance changes of an internal transfer
+pub const BUSINESS_TRANSFER_OUT: &str = "transfer_out";
+pub const BUSINESS_TRANSFER_IN: &str = "transfer_in";
+// business name of the balance change when a vesting allocation unlocks
+pub const BUSINESS_VESTING_RELEASE: &str = "vesting_release";
 
 pub struct BalanceUpdateParams {
     pub balance_type: BalanceType,
@@ -26,6 +38,84 @@ pub struct BalanceUpdateParams {
     pub detail: serde_json::Value,
 }
 
+pub struct TransferParams {
+    pub from: Uuid,
+    pub to: Uuid,
+    pub asset: String,
+    pub business_id: u64,
+    pub market_price: Decimal,
+    pub amount: Decimal,
+    pub memo: String,
+}
+
+pub struct WithdrawParams {
+    pub user_id: Uuid,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+    pub market_price: Decimal,
+    pub amount: Decimal,
+    pub detail: serde_json::Value,
+}
+
+// a withdrawal holding WITHDRAWING balance until the admin confirms or rejects it
+#[derive(Clone, Debug)]
+pub struct PendingWithdraw {
+    pub user_id: Uuid,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+    pub market_price: Decimal,
+    pub amount: Decimal,
+    pub create_time: f64,
+    pub detail: serde_json::Value,
+}
+
+pub struct DepositParams {
+    pub user_id: Uuid,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+    pub market_price: Decimal,
+    pub amount: Decimal,
+    pub confirmations: u32,
+    pub detail: serde_json::Value,
+}
+
+pub struct VestingParams {
+    pub user_id: Uuid,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+    pub market_price: Decimal,
+    pub amount: Decimal,
+    pub schedule: VestingSchedule,
+    pub detail: serde_json::Value,
+}
+
+// a deposit seen on chain, held as DEPOSITING balance until it has enough confirmations
+#[derive(Clone, Debug)]
+pub struct PendingDeposit {
+    pub user_id: Uuid,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+    pub market_price: Decimal,
+    pub amount: Decimal,
+    pub confirmations: u32,
+    pub create_time: f64,
+    pub detail: serde_json::Value,
+}
+
+// funds that entered and left the exchange, the holdings of an asset should always be
+// deposit - withdraw - fee
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AssetLedger {
+    pub deposit: Decimal,
+    pub withdraw: Decimal,
+    pub fee: Decimal,
+}
+
 #[derive(Clone, Copy, Eq, Hash, PartialEq)]
 pub enum BusinessType {
     Deposit,
@@ -34,16 +124,26 @@ pub enum BusinessType {
     Withdraw,
 }
 
-#[derive(PartialEq, Eq, Hash)]
-struct BalanceUpdateKey {
-    pub balance_type: BalanceType,
-    pub business_type: BusinessType,
+// identifies one applied balance update, a second update with the same key is a duplicate
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BalanceUpdateKey {
     pub user_id: Uuid,
     pub asset: String,
     pub business: String,
     pub business_id: u64,
 }
 
+impl From<&BalanceUpdateParams> for BalanceUpdateKey {
+    fn from(params: &BalanceUpdateParams) -> Self {
+        Self {
+            user_id: params.user_id,
+            asset: params.asset.clone(),
+            business: params.business.clone(),
+            business_id: params.business_id,
+        }
+    }
+}
+
 //pub trait BalanceUpdateValidator {
 //    pub fn is_valid()
 //}
@@ -51,24 +151,70 @@ struct BalanceUpdateKey {
 // TODO: this class needs to be refactored
 // Currently it has two purpose: (1) filter duplicate (2) generate message
 pub struct BalanceUpdateController {
-    cache: TtlCache<BalanceUpdateKey, bool>,
+    // applied business ids with the time they were applied, kept for `keeptime` seconds
+    applied: HashMap<BalanceUpdateKey, f64>,
+    // the same keys in the order they were applied, to expire the oldest first
+    applied_order: VecDeque<(f64, BalanceUpdateKey)>,
+    keeptime: f64,
+    // keyed by asset
+    pub ledger: BTreeMap<String, AssetLedger>,
+    // all keyed by (business, business_id), which is unique across the users while pending
+    pub pending_deposits: BTreeMap<(String, u64), PendingDeposit>,
+    pub pending_withdraws: BTreeMap<(String, u64), PendingWithdraw>,
+    pub vestings: BTreeMap<(String, u64), Vesting>,
 }
 
 impl BalanceUpdateController {
     pub fn new() -> BalanceUpdateController {
-        let capacity = 1_000_000;
+        Self::with_keeptime(DEFAULT_BUSINESS_ID_KEEPTIME)
+    }
+    pub fn with_keeptime(keeptime: f64) -> BalanceUpdateController {
         BalanceUpdateController {
-            cache: TtlCache::new(capacity),
+            applied: HashMap::new(),
+            applied_order: VecDeque::new(),
+            keeptime,
+            ledger: BTreeMap::new(),
+            pending_deposits: BTreeMap::new(),
+            pending_withdraws: BTreeMap::new(),
+            vestings: BTreeMap::new(),
         }
     }
     pub fn reset(&mut self) {
-        self.cache.clear()
+        self.applied.clear();
+        self.applied_order.clear();
+        self.ledger.clear();
+        self.pending_deposits.clear();
+        self.pending_withdraws.clear();
+        self.vestings.clear();
+    }
+    // time the business id was applied at, None if it is unknown or expired
+    pub fn applied_time(&self, key: &BalanceUpdateKey) -> Option<f64> {
+        self.applied.get(key).copied().filter(|time| *time >= clock::now() - self.keeptime)
     }
-    pub fn on_timer(&mut self) {
-        self.cache.clear()
+    // applied business ids, oldest first
+    pub fn applied_iter(&self) -> impl Iterator<Item = &(f64, BalanceUpdateKey)> {
+        self.applied_order.iter()
     }
-    pub fn timer_interval(&self) -> Duration {
-        Duration::from_secs(60)
+    // used when loading a slice, keys must come in the order they were applied
+    pub fn restore_applied(&mut self, key: BalanceUpdateKey, time: f64) {
+        self.applied.insert(key.clone(), time);
+        self.applied_order.push_back((time, key));
+    }
+    fn is_applied(&self, key: &BalanceUpdateKey) -> bool {
+        self.applied_time(key).is_some()
+    }
+    fn mark_applied(&mut self, key: BalanceUpdateKey) {
+        let now = clock::now();
+        while let Some((time, _)) = self.applied_order.front() {
+            if *time >= now - self.keeptime {
+                break;
+            }
+            let (time, expired) = self.applied_order.pop_front().unwrap();
+            if self.applied.get(&expired) == Some(&time) {
+                self.applied.remove(&expired);
+            }
+        }
+        self.restore_applied(key, now);
     }
     // return false if duplicate
     pub fn update_user_balance(
@@ -77,21 +223,15 @@ impl BalanceUpdateController {
         persistor: &mut impl PersistExector,
         mut params: BalanceUpdateParams,
     ) -> Result<()> {
+        let applied_key = BalanceUpdateKey::from(&params);
+        // trade ids come from the sequencer and never repeat, remembering them would only grow the slice
+        let dedupe = params.business_type != BusinessType::Trade;
         let asset = params.asset;
         let balance_type = params.balance_type;
         let business = params.business;
-        let business_type = params.business_type;
         let business_id = params.business_id;
         let user_id = params.user_id;
-        let cache_key = BalanceUpdateKey {
-            balance_type,
-            business_type,
-            user_id,
-            asset: asset.clone(),
-            business: business.clone(),
-            business_id,
-        };
-        if self.cache.contains_key(&cache_key) {
+        if dedupe && self.is_applied(&applied_key) {
             bail!("duplicate request");
         }
         let old_balance = balance_manager.get(user_id, balance_type, &asset);
@@ -106,33 +246,414 @@ impl BalanceUpdateController {
             balance_manager.sub(user_id, balance_type, &asset, &abs_change);
         }
         log::debug!("change user balance: {} {} {}", user_id, asset, change);
-        self.cache.insert(cache_key, true, Duration::from_secs(3600));
+        if dedupe {
+            self.mark_applied(applied_key);
+        }
+        // the same rounding as the balance change itself
+        let applied_change = change.round_dp(balance_manager.asset_manager.asset_prec(&asset));
+        let ledger = self.ledger.entry(asset.clone()).or_default();
+        match params.business_type {
+            BusinessType::Deposit => ledger.deposit += applied_change,
+            BusinessType::Withdraw => ledger.withdraw -= applied_change,
+            // the changes of a trade add up to minus its fees
+            BusinessType::Trade => ledger.fee -= applied_change,
+            BusinessType::Transfer => {}
+        }
         if persistor.real_persist() && (PERSIST_ZERO_BALANCE_UPDATE || !change.is_zero()) {
             params.detail["id"] = serde_json::Value::from(business_id);
-            let balance_available = balance_manager.get(user_id, BalanceType::AVAILABLE, &asset);
-            let balance_frozen = balance_manager.get(user_id, BalanceType::FREEZE, &asset);
-            let balance_history = BalanceHistory {
-                time: FTimestamp(current_timestamp()).into(),
-                user_id: user_id.to_string(),
-                business_id: business_id as i64,
+            let balance_history = Self::balance_history(
+                balance_manager,
+                user_id,
                 asset,
                 business,
-                market_price: params.market_price,
+                business_id,
+                params.market_price,
                 change,
-                balance: balance_available + balance_frozen,
-                balance_available,
-                balance_frozen,
-                detail: params.detail.to_string(),
-            };
+                &params.detail,
+            );
             persistor.put_balance(&balance_history);
             match params.business_type {
-                BusinessType::Deposit => persistor.put_deposit(&balance_history),
-                BusinessType::Withdraw => persistor.put_withdraw(&balance_history),
+                BusinessType::Deposit => persistor.put_deposit(&balance_history, DepositStatus::CONFIRMED),
+                // a plain negative balance update is a withdrawal done in one step
+                BusinessType::Withdraw => persistor.put_withdraw(&balance_history, WithdrawStatus::CONFIRMED),
                 _ => {}
             }
         }
         Ok(())
     }
+    fn balance_history(
+        balance_manager: &BalanceManager,
+        user_id: Uuid,
+        asset: String,
+        business: String,
+        business_id: u64,
+        market_price: Decimal,
+        change: Decimal,
+        detail: &serde_json::Value,
+    ) -> BalanceHistory {
+        let balance_available = balance_manager.get(user_id, BalanceType::AVAILABLE, &asset);
+        let balance_frozen = balance_manager.get(user_id, BalanceType::FREEZE, &asset);
+        BalanceHistory {
+            time: FTimestamp(clock::now()).into(),
+            user_id: user_id.to_string(),
+            business_id: business_id as i64,
+            asset,
+            business,
+            market_price,
+            change,
+            balance: balance_available + balance_frozen,
+            balance_available,
+            balance_frozen,
+            detail: detail.to_string(),
+            // known once the operation is logged
+            operation_log_id: None,
+        }
+    }
+    // hold the deposit as DEPOSITING balance, it is credited at once if it has enough confirmations already
+    pub fn deposit_request(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        params: DepositParams,
+    ) -> Result<()> {
+        if !params.amount.is_sign_positive() || params.amount.is_zero() {
+            bail!("invalid amount");
+        }
+        let pending_key = (params.business.clone(), params.business_id);
+        let applied_key = BalanceUpdateKey {
+            user_id: params.user_id,
+            asset: params.asset.clone(),
+            business: params.business.clone(),
+            business_id: params.business_id,
+        };
+        match self.pending_deposits.get(&pending_key) {
+            // a confirmation finds the deposit by (business, business_id) only, not by its user
+            Some(pending) if pending.user_id != params.user_id => bail!("business id is pending for another user"),
+            Some(_) => bail!("duplicate request"),
+            None if self.is_applied(&applied_key) => bail!("duplicate request"),
+            None => (),
+        }
+        balance_manager.add(params.user_id, BalanceType::DEPOSITING, &params.asset, &params.amount);
+        log::debug!("deposit request: {} {} {}", params.user_id, params.asset, params.amount);
+        self.mark_applied(applied_key);
+        let mut deposit = PendingDeposit {
+            user_id: params.user_id,
+            asset: params.asset,
+            business: params.business,
+            business_id: params.business_id,
+            market_price: params.market_price,
+            amount: params.amount,
+            confirmations: params.confirmations,
+            create_time: clock::now(),
+            detail: params.detail,
+        };
+        deposit.detail["id"] = serde_json::Value::from(deposit.business_id);
+        if persistor.real_persist() {
+            persistor.put_deposit(&Self::deposit_history(balance_manager, &deposit), DepositStatus::PENDING);
+        }
+        self.pending_deposits.insert(pending_key.clone(), deposit);
+        self.deposit_confirm(balance_manager, persistor, &pending_key.0, pending_key.1, params.confirmations)
+    }
+    // record the confirmations reported so far, credit the deposit once there are enough for the asset
+    pub fn deposit_confirm(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        business: &str,
+        business_id: u64,
+        confirmations: u32,
+    ) -> Result<()> {
+        let pending_key = (business.to_owned(), business_id);
+        let deposit = match self.pending_deposits.get_mut(&pending_key) {
+            Some(deposit) => deposit,
+            None => bail!("deposit not found"),
+        };
+        deposit.confirmations = deposit.confirmations.max(confirmations);
+        if deposit.confirmations < balance_manager.asset_manager.asset_deposit_confirmations(&deposit.asset) {
+            return Ok(());
+        }
+        let deposit = self.pending_deposits.remove(&pending_key).unwrap();
+        balance_manager.sub(deposit.user_id, BalanceType::DEPOSITING, &deposit.asset, &deposit.amount);
+        balance_manager.add(deposit.user_id, BalanceType::AVAILABLE, &deposit.asset, &deposit.amount);
+        self.ledger.entry(deposit.asset.clone()).or_default().deposit += deposit.amount;
+        if persistor.real_persist() {
+            let balance_history = Self::deposit_history(balance_manager, &deposit);
+            persistor.put_balance(&balance_history);
+            persistor.put_deposit(&balance_history, DepositStatus::CONFIRMED);
+        }
+        Ok(())
+    }
+    // the deposit is gone after a chain reorg, drop the DEPOSITING balance
+    pub fn deposit_cancel(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        business: &str,
+        business_id: u64,
+    ) -> Result<()> {
+        let deposit = match self.pending_deposits.remove(&(business.to_owned(), business_id)) {
+            Some(deposit) => deposit,
+            None => bail!("deposit not found"),
+        };
+        balance_manager.sub(deposit.user_id, BalanceType::DEPOSITING, &deposit.asset, &deposit.amount);
+        if persistor.real_persist() {
+            persistor.put_deposit(&Self::deposit_history(balance_manager, &deposit), DepositStatus::CANCELLED);
+        }
+        Ok(())
+    }
+    fn deposit_history(balance_manager: &BalanceManager, deposit: &PendingDeposit) -> BalanceHistory {
+        Self::balance_history(
+            balance_manager,
+            deposit.user_id,
+            deposit.asset.clone(),
+            deposit.business.clone(),
+            deposit.business_id,
+            deposit.market_price,
+            deposit.amount,
+            &deposit.detail,
+        )
+    }
+    // lock AVAILABLE balance as WITHDRAWING until the withdrawal is confirmed or rejected
+    pub fn withdraw_request(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        params: WithdrawParams,
+    ) -> Result<()> {
+        if !params.amount.is_sign_positive() || params.amount.is_zero() {
+            bail!("invalid amount");
+        }
+        let pending_key = (params.business.clone(), params.business_id);
+        let applied_key = BalanceUpdateKey {
+            user_id: params.user_id,
+            asset: params.asset.clone(),
+            business: params.business.clone(),
+            business_id: params.business_id,
+        };
+        match self.pending_withdraws.get(&pending_key) {
+            // confirm and reject look the withdrawal up by (business, business_id) only
+            Some(pending) if pending.user_id != params.user_id => bail!("business id is pending for another user"),
+            Some(_) => bail!("duplicate request"),
+            None if self.is_applied(&applied_key) => bail!("duplicate request"),
+            None => (),
+        }
+        if balance_manager.get(params.user_id, BalanceType::AVAILABLE, &params.asset) < params.amount {
+            bail!("balance not enough");
+        }
+        balance_manager.sub(params.user_id, BalanceType::AVAILABLE, &params.asset, &params.amount);
+        balance_manager.add(params.user_id, BalanceType::WITHDRAWING, &params.asset, &params.amount);
+        log::debug!("withdraw request: {} {} {}", params.user_id, params.asset, params.amount);
+        self.mark_applied(applied_key);
+        let mut withdraw = PendingWithdraw {
+            user_id: params.user_id,
+            asset: params.asset,
+            business: params.business,
+            business_id: params.business_id,
+            market_price: params.market_price,
+            amount: params.amount,
+            create_time: clock::now(),
+            detail: params.detail,
+        };
+        withdraw.detail["id"] = serde_json::Value::from(withdraw.business_id);
+        if persistor.real_persist() {
+            let balance_history = Self::withdraw_history(balance_manager, &withdraw, -withdraw.amount);
+            persistor.put_balance(&balance_history);
+            persistor.put_withdraw(&balance_history, WithdrawStatus::PENDING);
+        }
+        self.pending_withdraws.insert(pending_key, withdraw);
+        Ok(())
+    }
+    // the funds have been paid out, burn the WITHDRAWING balance
+    pub fn withdraw_confirm(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        business: &str,
+        business_id: u64,
+    ) -> Result<()> {
+        let withdraw = match self.pending_withdraws.remove(&(business.to_owned(), business_id)) {
+            Some(withdraw) => withdraw,
+            None => bail!("withdraw not found"),
+        };
+        balance_manager.sub(withdraw.user_id, BalanceType::WITHDRAWING, &withdraw.asset, &withdraw.amount);
+        self.ledger.entry(withdraw.asset.clone()).or_default().withdraw += withdraw.amount;
+        if persistor.real_persist() {
+            let balance_history = Self::withdraw_history(balance_manager, &withdraw, -withdraw.amount);
+            persistor.put_withdraw(&balance_history, WithdrawStatus::CONFIRMED);
+        }
+        Ok(())
+    }
+    // the payout failed, return the WITHDRAWING balance to AVAILABLE
+    pub fn withdraw_reject(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        business: &str,
+        business_id: u64,
+    ) -> Result<()> {
+        let withdraw = match self.pending_withdraws.remove(&(business.to_owned(), business_id)) {
+            Some(withdraw) => withdraw,
+            None => bail!("withdraw not found"),
+        };
+        balance_manager.sub(withdraw.user_id, BalanceType::WITHDRAWING, &withdraw.asset, &withdraw.amount);
+        balance_manager.add(withdraw.user_id, BalanceType::AVAILABLE, &withdraw.asset, &withdraw.amount);
+        if persistor.real_persist() {
+            persistor.put_balance(&Self::withdraw_history(balance_manager, &withdraw, withdraw.amount));
+            let balance_history = Self::withdraw_history(balance_manager, &withdraw, -withdraw.amount);
+            persistor.put_withdraw(&balance_history, WithdrawStatus::REJECTED);
+        }
+        Ok(())
+    }
+    fn withdraw_history(balance_manager: &BalanceManager, withdraw: &PendingWithdraw, change: Decimal) -> BalanceHistory {
+        Self::balance_history(
+            balance_manager,
+            withdraw.user_id,
+            withdraw.asset.clone(),
+            withdraw.business.clone(),
+            withdraw.business_id,
+            withdraw.market_price,
+            change,
+            &withdraw.detail,
+        )
+    }
+    // Credit the allocation as LOCKED balance, it becomes AVAILABLE through `vesting_release`.
+    // The key is dropped once everything is released, from then on only the applied business ids
+    // reject a retry, for `keeptime` seconds after the creation like any other balance update.
+    pub fn vesting_create(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        params: VestingParams,
+    ) -> Result<()> {
+        if !params.amount.is_sign_positive() || params.amount.is_zero() {
+            bail!("invalid amount");
+        }
+        params.schedule.validate(&params.amount)?;
+        let key = (params.business.clone(), params.business_id);
+        if self.vestings.contains_key(&key) {
+            bail!("duplicate request");
+        }
+        self.update_user_balance(
+            balance_manager,
+            persistor,
+            BalanceUpdateParams {
+                balance_type: BalanceType::LOCKED,
+                business_type: BusinessType::Deposit,
+                user_id: params.user_id,
+                business_id: params.business_id,
+                asset: params.asset.clone(),
+                business: params.business.clone(),
+                market_price: params.market_price,
+                change: params.amount,
+                detail: params.detail.clone(),
+            },
+        )?;
+        self.vestings.insert(
+            key,
+            Vesting {
+                user_id: params.user_id,
+                asset: params.asset,
+                business: params.business,
+                business_id: params.business_id,
+                total: params.amount,
+                released: Decimal::zero(),
+                schedule: params.schedule,
+                detail: params.detail,
+            },
+        );
+        Ok(())
+    }
+    // move everything unlocked by `time` from LOCKED to AVAILABLE, returns how many vestings released some
+    pub fn vesting_release(&mut self, balance_manager: &mut BalanceManager, persistor: &mut impl PersistExector, time: f64) -> usize {
+        let mut count = 0;
+        for vesting in self.vestings.values_mut() {
+            let prec = balance_manager.asset_manager.asset_prec(&vesting.asset);
+            let due = vesting.schedule.unlocked_at(&vesting.total, time, prec) - vesting.released;
+            if !due.is_sign_positive() || due.is_zero() {
+                continue;
+            }
+            balance_manager.sub(vesting.user_id, BalanceType::LOCKED, &vesting.asset, &due);
+            balance_manager.add(vesting.user_id, BalanceType::AVAILABLE, &vesting.asset, &due);
+            vesting.released += due;
+            count += 1;
+            log::debug!("vesting release: {} {} {}", vesting.user_id, vesting.asset, due);
+            if persistor.real_persist() {
+                let mut detail = vesting.detail.clone();
+                detail["business"] = serde_json::Value::from(vesting.business.clone());
+                detail["released"] = serde_json::Value::from(vesting.released.to_string());
+                persistor.put_balance(&Self::balance_history(
+                    balance_manager,
+                    vesting.user_id,
+                    vesting.asset.clone(),
+                    BUSINESS_VESTING_RELEASE.to_owned(),
+                    vesting.business_id,
+                    Decimal::zero(),
+                    due,
+                    &detail,
+                ));
+            }
+        }
+        self.vestings.retain(|_, vesting| vesting.released < vesting.total);
+        count
+    }
+    // move AVAILABLE balance between two users, both sides are applied or none
+    pub fn transfer(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        params: TransferParams,
+    ) -> Result<()> {
+        if params.from == params.to {
+            bail!("cannot transfer to oneself");
+        }
+        if !params.amount.is_sign_positive() || params.amount.is_zero() {
+            bail!("invalid amount");
+        }
+        let detail = json!({"from": params.from, "to": params.to, "memo": params.memo});
+        let debit = BalanceUpdateParams {
+            balance_type: BalanceType::AVAILABLE,
+            business_type: BusinessType::Transfer,
+            user_id: params.from,
+            business_id: params.business_id,
+            asset: params.asset.clone(),
+            business: BUSINESS_TRANSFER_OUT.to_owned(),
+            market_price: params.market_price,
+            change: -params.amount,
+            detail: detail.clone(),
+        };
+        let credit = BalanceUpdateParams {
+            balance_type: BalanceType::AVAILABLE,
+            business_type: BusinessType::Transfer,
+            user_id: params.to,
+            business_id: params.business_id,
+            asset: params.asset.clone(),
+            business: BUSINESS_TRANSFER_IN.to_owned(),
+            market_price: params.market_price,
+            change: params.amount,
+            detail,
+        };
+        // check everything before the first change, so the credit can not fail after the debit
+        if self.is_applied(&BalanceUpdateKey::from(&debit)) || self.is_applied(&BalanceUpdateKey::from(&credit)) {
+            bail!("duplicate request");
+        }
+        if balance_manager.get(params.from, BalanceType::AVAILABLE, &params.asset) < params.amount {
+            bail!("balance not enough");
+        }
+        self.update_user_balance(balance_manager, persistor, debit)?;
+        self.update_user_balance(balance_manager, persistor, credit)?;
+        if persistor.real_persist() {
+            persistor.put_transfer(&InternalTx {
+                time: FTimestamp(clock::now()).into(),
+                user_from: params.from.to_string(),
+                user_to: params.to.to_string(),
+                asset: params.asset,
+                amount: params.amount,
+                business_id: params.business_id as i64,
+                memo: params.memo,
+            });
+        }
+        Ok(())
+    }
 }
 
 impl Default for BalanceUpdateController {
@@ -140,3 +661,244 @@ impl Default for BalanceUpdateController {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchengine::mock::*;
+    use crate::persist::MemBasedPersistor;
+    use fluidex_common::rust_decimal_macros::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_transfer() {
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::new();
+        let mut persistor = MemBasedPersistor::default();
+        let alice = Uuid::from_str("6a1f4e3b-2c8d-4b7a-9e5f-0d1c2b3a4f5e").unwrap();
+        let bob = Uuid::from_str("b2c3d4e5-f6a7-4b8c-9d0e-1f2a3b4c5d6e").unwrap();
+        balance_manager.add(alice, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+        let params = |from, to, business_id, amount| TransferParams {
+            from,
+            to,
+            asset: MockAsset::USDT.id(),
+            business_id,
+            market_price: dec!(0),
+            amount,
+            memo: String::new(),
+        };
+
+        update_controller
+            .transfer(balance_manager, &mut persistor, params(alice, bob, 1, dec!(30)))
+            .unwrap();
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(70));
+        assert_eq!(balance_manager.get(bob, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(30));
+        // two balance messages and the transfer itself
+        assert_eq!(persistor.messages.len(), 3);
+
+        // the same business id is rejected
+        assert!(update_controller
+            .transfer(balance_manager, &mut persistor, params(alice, bob, 1, dec!(30)))
+            .is_err());
+        // bob may reuse the business id for his own transfer
+        update_controller
+            .transfer(balance_manager, &mut persistor, params(bob, alice, 1, dec!(10)))
+            .unwrap();
+        // nothing changes when the balance is not enough
+        assert!(update_controller
+            .transfer(balance_manager, &mut persistor, params(alice, bob, 2, dec!(1000)))
+            .is_err());
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(80));
+        assert_eq!(balance_manager.get(bob, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(20));
+    }
+
+    #[test]
+    fn test_withdraw() {
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::new();
+        let mut persistor = MemBasedPersistor::default();
+        let alice = Uuid::from_str("6a1f4e3b-2c8d-4b7a-9e5f-0d1c2b3a4f5e").unwrap();
+        let usdt = MockAsset::USDT.id();
+        balance_manager.add(alice, BalanceType::AVAILABLE, &usdt, &dec!(100));
+        let params = |business_id, amount| WithdrawParams {
+            user_id: alice,
+            asset: MockAsset::USDT.id(),
+            business: "withdraw".to_owned(),
+            business_id,
+            market_price: dec!(0),
+            amount,
+            detail: json!({}),
+        };
+
+        update_controller
+            .withdraw_request(balance_manager, &mut persistor, params(1, dec!(30)))
+            .unwrap();
+        update_controller
+            .withdraw_request(balance_manager, &mut persistor, params(2, dec!(20)))
+            .unwrap();
+        assert!(update_controller
+            .withdraw_request(balance_manager, &mut persistor, params(1, dec!(30)))
+            .is_err());
+        assert!(update_controller
+            .withdraw_request(balance_manager, &mut persistor, params(3, dec!(60)))
+            .is_err());
+        let bob = Uuid::from_str("0b7e2d4c-9a1f-4e3b-8c5d-6f7a8b9c0d1e").unwrap();
+        balance_manager.add(bob, BalanceType::AVAILABLE, &usdt, &dec!(10));
+        let err = update_controller
+            .withdraw_request(
+                balance_manager,
+                &mut persistor,
+                WithdrawParams {
+                    user_id: bob,
+                    ..params(1, dec!(10))
+                },
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "business id is pending for another user");
+        assert_eq!(balance_manager.get(bob, BalanceType::AVAILABLE, &usdt), dec!(10));
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &usdt), dec!(50));
+        assert_eq!(balance_manager.get(alice, BalanceType::WITHDRAWING, &usdt), dec!(50));
+
+        update_controller
+            .withdraw_confirm(balance_manager, &mut persistor, "withdraw", 1)
+            .unwrap();
+        update_controller
+            .withdraw_reject(balance_manager, &mut persistor, "withdraw", 2)
+            .unwrap();
+        assert!(update_controller
+            .withdraw_confirm(balance_manager, &mut persistor, "withdraw", 2)
+            .is_err());
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &usdt), dec!(70));
+        assert_eq!(balance_manager.get(alice, BalanceType::WITHDRAWING, &usdt), dec!(0));
+        assert!(update_controller.pending_withdraws.is_empty());
+        // request: balance + withdraw each, confirm: withdraw, reject: balance + withdraw
+        assert_eq!(persistor.messages.len(), 7);
+    }
+
+    #[test]
+    fn test_deposit() {
+        let mut asset_config = get_simple_asset_config(8);
+        asset_config[0].deposit_confirmations = 3;
+        let balance_manager = &mut get_simple_balance_manager(asset_config);
+        let mut update_controller = BalanceUpdateController::new();
+        let mut persistor = MemBasedPersistor::default();
+        let alice = Uuid::from_str("6a1f4e3b-2c8d-4b7a-9e5f-0d1c2b3a4f5e").unwrap();
+        let usdt = MockAsset::USDT.id();
+        let params = |business_id, confirmations| DepositParams {
+            user_id: alice,
+            asset: MockAsset::USDT.id(),
+            business: "deposit".to_owned(),
+            business_id,
+            market_price: dec!(0),
+            amount: dec!(10),
+            confirmations,
+            detail: json!({}),
+        };
+
+        update_controller
+            .deposit_request(balance_manager, &mut persistor, params(1, 1))
+            .unwrap();
+        update_controller
+            .deposit_request(balance_manager, &mut persistor, params(2, 0))
+            .unwrap();
+        assert!(update_controller
+            .deposit_request(balance_manager, &mut persistor, params(1, 1))
+            .is_err());
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &usdt), dec!(0));
+        assert_eq!(balance_manager.get(alice, BalanceType::DEPOSITING, &usdt), dec!(20));
+
+        update_controller
+            .deposit_confirm(balance_manager, &mut persistor, "deposit", 1, 2)
+            .unwrap();
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &usdt), dec!(0));
+        update_controller
+            .deposit_confirm(balance_manager, &mut persistor, "deposit", 1, 3)
+            .unwrap();
+        update_controller
+            .deposit_cancel(balance_manager, &mut persistor, "deposit", 2)
+            .unwrap();
+        assert!(update_controller
+            .deposit_cancel(balance_manager, &mut persistor, "deposit", 1)
+            .is_err());
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &usdt), dec!(10));
+        assert_eq!(balance_manager.get(alice, BalanceType::DEPOSITING, &usdt), dec!(0));
+        assert!(update_controller.pending_deposits.is_empty());
+        // two pending deposits, balance + deposit for the confirmed one, the cancelled one
+        assert_eq!(persistor.messages.len(), 5);
+    }
+
+    #[test]
+    fn test_vesting() {
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::new();
+        let mut persistor = MemBasedPersistor::default();
+        let alice = Uuid::from_str("6a1f4e3b-2c8d-4b7a-9e5f-0d1c2b3a4f5e").unwrap();
+        let usdt = MockAsset::USDT.id();
+        let params = |business_id| VestingParams {
+            user_id: alice,
+            asset: MockAsset::USDT.id(),
+            business: "vesting".to_owned(),
+            business_id,
+            market_price: dec!(0),
+            amount: dec!(100),
+            schedule: VestingSchedule::Dates(vec![(100.0, dec!(40)), (200.0, dec!(60))]),
+            detail: json!({}),
+        };
+
+        update_controller
+            .vesting_create(balance_manager, &mut persistor, params(1))
+            .unwrap();
+        assert!(update_controller
+            .vesting_create(balance_manager, &mut persistor, params(1))
+            .is_err());
+        assert_eq!(balance_manager.get(alice, BalanceType::LOCKED, &usdt), dec!(100));
+
+        assert_eq!(update_controller.vesting_release(balance_manager, &mut persistor, 50.0), 0);
+        assert_eq!(update_controller.vesting_release(balance_manager, &mut persistor, 150.0), 1);
+        // nothing more until the next date
+        assert_eq!(update_controller.vesting_release(balance_manager, &mut persistor, 160.0), 0);
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &usdt), dec!(40));
+        assert_eq!(balance_manager.get(alice, BalanceType::LOCKED, &usdt), dec!(60));
+        assert_eq!(update_controller.vesting_release(balance_manager, &mut persistor, 250.0), 1);
+        assert_eq!(balance_manager.get(alice, BalanceType::AVAILABLE, &usdt), dec!(100));
+        assert_eq!(balance_manager.get(alice, BalanceType::LOCKED, &usdt), dec!(0));
+        assert!(update_controller.vestings.is_empty());
+        // the allocation and its two releases
+        assert_eq!(persistor.messages.len(), 3);
+    }
+
+    #[test]
+    fn test_business_id_keeptime() {
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::with_keeptime(0.0);
+        let mut persistor = MemBasedPersistor::default();
+        let alice = Uuid::from_str("6a1f4e3b-2c8d-4b7a-9e5f-0d1c2b3a4f5e").unwrap();
+        let params = || BalanceUpdateParams {
+            balance_type: BalanceType::AVAILABLE,
+            business_type: BusinessType::Deposit,
+            user_id: alice,
+            business_id: 1,
+            asset: MockAsset::USDT.id(),
+            business: "deposit".to_owned(),
+            market_price: dec!(0),
+            change: dec!(10),
+            detail: json!({}),
+        };
+        let key = BalanceUpdateKey::from(&params());
+
+        // an applied business id is found until it expires
+        update_controller.restore_applied(key.clone(), clock::now() + 60.0);
+        assert!(update_controller.applied_time(&key).is_some());
+        assert!(update_controller
+            .update_user_balance(balance_manager, &mut persistor, params())
+            .is_err());
+        update_controller.reset();
+        update_controller.restore_applied(key.clone(), clock::now() - 60.0);
+        assert!(update_controller.applied_time(&key).is_none());
+        update_controller
+            .update_user_balance(balance_manager, &mut persistor, params())
+            .unwrap();
+        // the expired entry is dropped, the new one is kept
+        assert_eq!(update_controller.applied_iter().count(), 1);
+    }
+}
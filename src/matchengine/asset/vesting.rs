@@ -0,0 +1,141 @@
+use anyhow::{bail, Result};
+use fluidex_common::rust_decimal::prelude::{FromPrimitive, Zero};
+use fluidex_common::rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VestingSchedule {
+    // nothing unlocks before the cliff, then the amount unlocks linearly from start to end
+    Linear { cliff_time: f64, start_time: f64, end_time: f64 },
+    // (time, amount) unlocks in time order, the amounts add up to the total
+    Dates(Vec<(f64, Decimal)>),
+}
+
+impl VestingSchedule {
+    pub fn validate(&self, total: &Decimal) -> Result<()> {
+        match self {
+            VestingSchedule::Linear {
+                cliff_time,
+                start_time,
+                end_time,
+            } => {
+                if !cliff_time.is_finite() || !start_time.is_finite() || !end_time.is_finite() {
+                    bail!("invalid vesting time");
+                }
+                if start_time >= end_time {
+                    bail!("invalid vesting period");
+                }
+            }
+            VestingSchedule::Dates(unlocks) => {
+                if unlocks.iter().any(|(time, _)| !time.is_finite()) {
+                    bail!("invalid vesting time");
+                }
+                if unlocks.is_empty() || unlocks.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+                    bail!("invalid vesting dates");
+                }
+                if unlocks.iter().any(|(_, amount)| !amount.is_sign_positive() || amount.is_zero()) {
+                    bail!("invalid vesting amount");
+                }
+                if unlocks.iter().map(|(_, amount)| amount).sum::<Decimal>() != *total {
+                    bail!("vesting amounts do not add up to the total");
+                }
+            }
+        }
+        Ok(())
+    }
+    // the amount unlocked by `time` in total, rounded down to the asset precision
+    pub fn unlocked_at(&self, total: &Decimal, time: f64, prec: u32) -> Decimal {
+        match self {
+            VestingSchedule::Linear {
+                cliff_time,
+                start_time,
+                end_time,
+            } => {
+                if time < *cliff_time || time <= *start_time {
+                    Decimal::zero()
+                } else if time >= *end_time {
+                    *total
+                } else {
+                    let ratio = Decimal::from_f64((time - start_time) / (end_time - start_time)).unwrap_or_default();
+                    (total * ratio).round_dp_with_strategy(prec, RoundingStrategy::ToZero).min(*total)
+                }
+            }
+            VestingSchedule::Dates(unlocks) => unlocks.iter().filter(|(t, _)| *t <= time).map(|(_, amount)| amount).sum(),
+        }
+    }
+    // a linear schedule that has started unlocks continuously, its next unlock is reported as the end
+    pub fn next_unlock(&self, time: f64) -> Option<f64> {
+        match self {
+            VestingSchedule::Linear {
+                cliff_time,
+                start_time,
+                end_time,
+            } => {
+                if time >= *end_time {
+                    None
+                } else if time < cliff_time.max(*start_time) {
+                    Some(cliff_time.max(*start_time))
+                } else {
+                    Some(*end_time)
+                }
+            }
+            VestingSchedule::Dates(unlocks) => unlocks.iter().map(|(t, _)| *t).find(|t| *t > time),
+        }
+    }
+}
+
+// an allocation held as LOCKED balance and moved to AVAILABLE as its schedule unlocks it
+#[derive(Clone, Debug)]
+pub struct Vesting {
+    pub user_id: Uuid,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+    pub total: Decimal,
+    pub released: Decimal,
+    pub schedule: VestingSchedule,
+    pub detail: serde_json::Value,
+}
+
+#[cfg(test)]
+#[test]
+fn test_vesting_schedule() {
+    use fluidex_common::rust_decimal_macros::*;
+
+    let linear = VestingSchedule::Linear {
+        cliff_time: 150.0,
+        start_time: 100.0,
+        end_time: 200.0,
+    };
+    linear.validate(&dec!(100)).unwrap();
+    assert_eq!(linear.unlocked_at(&dec!(100), 149.0, 8), dec!(0));
+    // everything since the start unlocks at the cliff
+    assert_eq!(linear.unlocked_at(&dec!(100), 150.0, 8), dec!(50));
+    assert_eq!(linear.unlocked_at(&dec!(100), 300.0, 8), dec!(100));
+    assert_eq!(linear.next_unlock(0.0), Some(150.0));
+    assert_eq!(linear.next_unlock(160.0), Some(200.0));
+    assert_eq!(linear.next_unlock(200.0), None);
+
+    let dates = VestingSchedule::Dates(vec![(100.0, dec!(30)), (200.0, dec!(70))]);
+    assert!(dates.validate(&dec!(90)).is_err());
+    assert!(VestingSchedule::Dates(vec![(f64::NAN, dec!(100))]).validate(&dec!(100)).is_err());
+    assert!(VestingSchedule::Linear {
+        cliff_time: f64::NEG_INFINITY,
+        start_time: 100.0,
+        end_time: 200.0,
+    }
+    .validate(&dec!(100))
+    .is_err());
+    assert!(VestingSchedule::Linear {
+        cliff_time: 150.0,
+        start_time: 100.0,
+        end_time: f64::INFINITY,
+    }
+    .validate(&dec!(100))
+    .is_err());
+    dates.validate(&dec!(100)).unwrap();
+    assert_eq!(dates.unlocked_at(&dec!(100), 99.0, 8), dec!(0));
+    assert_eq!(dates.unlocked_at(&dec!(100), 100.0, 8), dec!(30));
+    assert_eq!(dates.next_unlock(100.0), Some(200.0));
+}
@@ -0,0 +1,93 @@
+use fluidex_common::rust_decimal::Decimal;
+use std::collections::{BTreeMap, VecDeque};
+use uuid::Uuid;
+
+// the quota is a rolling one over the last 24 hours
+pub const WITHDRAW_QUOTA_WINDOW: f64 = 86400.0;
+
+// Tracks the withdrawals of each user within the quota window. Admins can override the
+// daily quota of the asset for a single user.
+#[derive(Default)]
+pub struct WithdrawQuotaManager {
+    // (user, asset) -> quota, zero means no limit
+    pub overrides: BTreeMap<(Uuid, String), Decimal>,
+    // (user, asset) -> (time, amount) of the withdrawals, oldest first
+    pub usage: BTreeMap<(Uuid, String), VecDeque<(f64, Decimal)>>,
+}
+
+impl WithdrawQuotaManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn reset(&mut self) {
+        self.overrides.clear();
+        self.usage.clear();
+    }
+    pub fn quota(&self, user_id: Uuid, asset: &str, asset_quota: Decimal) -> Decimal {
+        self.overrides.get(&(user_id, asset.to_owned())).copied().unwrap_or(asset_quota)
+    }
+    // None drops the override and the asset quota applies again
+    pub fn set_override(&mut self, user_id: Uuid, asset: &str, quota: Option<Decimal>) {
+        let key = (user_id, asset.to_owned());
+        match quota {
+            Some(quota) => self.overrides.insert(key, quota),
+            None => self.overrides.remove(&key),
+        };
+    }
+    pub fn used(&self, user_id: Uuid, asset: &str, now: f64) -> Decimal {
+        self.usage
+            .get(&(user_id, asset.to_owned()))
+            .map(|withdraws| {
+                withdraws
+                    .iter()
+                    .filter(|(time, _)| *time > now - WITHDRAW_QUOTA_WINDOW)
+                    .map(|(_, amount)| amount)
+                    .sum()
+            })
+            .unwrap_or_default()
+    }
+    pub fn record(&mut self, user_id: Uuid, asset: &str, time: f64, amount: Decimal) {
+        let withdraws = self.usage.entry((user_id, asset.to_owned())).or_default();
+        while matches!(withdraws.front(), Some((t, _)) if *t <= time - WITHDRAW_QUOTA_WINDOW) {
+            withdraws.pop_front();
+        }
+        withdraws.push_back((time, amount));
+    }
+    // give the quota back for a withdrawal which did not go through, the time is only
+    // compared to the second since it may come from a slice
+    pub fn release(&mut self, user_id: Uuid, asset: &str, time: f64, amount: Decimal) {
+        let key = (user_id, asset.to_owned());
+        if let Some(withdraws) = self.usage.get_mut(&key) {
+            if let Some(pos) = withdraws.iter().position(|(t, a)| *a == amount && (*t - time).abs() < 1.0) {
+                withdraws.remove(pos);
+            }
+            if withdraws.is_empty() {
+                self.usage.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_withdraw_quota() {
+    use fluidex_common::rust_decimal_macros::*;
+    use std::str::FromStr;
+
+    let alice = Uuid::from_str("0f0e0d0c-0b0a-4908-8706-050403020100").unwrap();
+    let mut manager = WithdrawQuotaManager::new();
+    assert_eq!(manager.quota(alice, "ETH", dec!(10)), dec!(10));
+    manager.set_override(alice, "ETH", Some(dec!(20)));
+    assert_eq!(manager.quota(alice, "ETH", dec!(10)), dec!(20));
+    manager.set_override(alice, "ETH", None);
+    assert_eq!(manager.quota(alice, "ETH", dec!(10)), dec!(10));
+
+    manager.record(alice, "ETH", 1000.0, dec!(3));
+    manager.record(alice, "ETH", 2000.0, dec!(4));
+    assert_eq!(manager.used(alice, "ETH", 2000.0), dec!(7));
+    assert_eq!(manager.used(alice, "USDT", 2000.0), dec!(0));
+    // the first one drops out of the window
+    assert_eq!(manager.used(alice, "ETH", 1000.0 + WITHDRAW_QUOTA_WINDOW), dec!(4));
+    manager.release(alice, "ETH", 2000.4, dec!(4));
+    assert_eq!(manager.used(alice, "ETH", 2000.0), dec!(3));
+}
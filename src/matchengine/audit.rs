@@ -0,0 +1,196 @@
+use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController};
+use crate::market::Market;
+
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::Decimal;
+use fluidex_common::utils::timeutil::current_timestamp;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditCheck {
+    // FREEZE balance of a user against the frozen amount of its open orders
+    FrozenBalance,
+    // holdings of all users against deposits - withdraws - fees
+    AssetTotal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditMismatch {
+    pub check: AuditCheck,
+    // None for the checks over all users
+    pub user_id: Option<Uuid>,
+    pub asset: String,
+    pub expected: Decimal,
+    pub actual: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditReport {
+    pub timestamp: f64,
+    pub mismatches: Vec<AuditMismatch>,
+}
+
+impl AuditReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+pub fn audit<'a>(
+    balance_manager: &BalanceManager,
+    update_controller: &BalanceUpdateController,
+    markets: impl IntoIterator<Item = &'a Market>,
+) -> AuditReport {
+    // (expected, actual) per user and asset
+    let mut frozen: BTreeMap<(Uuid, String), (Decimal, Decimal)> = BTreeMap::new();
+    for market in markets {
+        for order in market.orders.values() {
+            if order.frozen.is_zero() {
+                continue;
+            }
+            let asset = if order.is_ask() { market.base } else { market.quote };
+            // every freeze is rounded to the asset precision by the balance manager
+            let amount = order.frozen.round_dp(balance_manager.asset_manager.asset_prec(asset));
+            frozen.entry((order.user, asset.to_owned())).or_default().0 += amount;
+        }
+    }
+    let mut holdings: BTreeMap<String, Decimal> = BTreeMap::new();
+    for (key, amount) in balance_manager.balances.iter() {
+        match key.balance_type {
+            // not credited yet
+            BalanceType::DEPOSITING => continue,
+            BalanceType::FREEZE if !amount.is_zero() => {
+                frozen.entry((key.user_id, key.asset.clone())).or_default().1 += amount;
+            }
+            _ => {}
+        }
+        *holdings.entry(key.asset.clone()).or_default() += amount;
+    }
+
+    let mut mismatches: Vec<AuditMismatch> = frozen
+        .into_iter()
+        .filter(|(_, (expected, actual))| expected != actual)
+        .map(|((user_id, asset), (expected, actual))| AuditMismatch {
+            check: AuditCheck::FrozenBalance,
+            user_id: Some(user_id),
+            asset,
+            expected,
+            actual,
+        })
+        .collect();
+    let mut totals: BTreeMap<String, (Decimal, Decimal)> = BTreeMap::new();
+    for (asset, ledger) in update_controller.ledger.iter() {
+        totals.entry(asset.clone()).or_default().0 = ledger.deposit - ledger.withdraw - ledger.fee;
+    }
+    for (asset, amount) in holdings {
+        totals.entry(asset).or_default().1 = amount;
+    }
+    mismatches.extend(
+        totals
+            .into_iter()
+            .filter(|(_, (expected, actual))| expected != actual)
+            .map(|(asset, (expected, actual))| AuditMismatch {
+                check: AuditCheck::AssetTotal,
+                user_id: None,
+                asset,
+                expected,
+                actual,
+            }),
+    );
+    AuditReport {
+        timestamp: current_timestamp(),
+        mismatches,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceUpdateParams, BusinessType};
+    use crate::config::Settings;
+    use crate::market::OrderInput;
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use crate::sequencer::Sequencer;
+    use crate::types::{OrderSide, OrderType};
+    use fluidex_common::rust_decimal_macros::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_audit() {
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::new();
+        let mut persistor = DummyPersistor::default();
+        let ask_user_id = Uuid::from_str("f2c3a119-efc8-4a8a-9e44-9e3c378a7145").unwrap();
+        let bid_user_id = Uuid::from_str("9f165718-6f7a-49f0-a619-85add5d0aacb").unwrap();
+        for (business_id, user_id, asset) in [(1, ask_user_id, MockAsset::ETH.id()), (2, bid_user_id, MockAsset::USDT.id())] {
+            update_controller
+                .update_user_balance(
+                    balance_manager,
+                    &mut persistor,
+                    BalanceUpdateParams {
+                        balance_type: BalanceType::AVAILABLE,
+                        business_type: BusinessType::Deposit,
+                        user_id,
+                        business_id,
+                        asset,
+                        business: "deposit".to_owned(),
+                        market_price: dec!(0),
+                        change: dec!(1000),
+                        detail: serde_json::Value::default(),
+                    },
+                )
+                .unwrap();
+        }
+
+        let sequencer = &mut Sequencer::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        for (side, user_id) in [(OrderSide::ASK, ask_user_id), (OrderSide::BID, bid_user_id)] {
+            let amount = if side == OrderSide::ASK { dec!(20) } else { dec!(10) };
+            let input = OrderInput {
+                side,
+                type_: OrderType::LIMIT,
+                amount,
+                price: dec!(1.1),
+                quote_limit: dec!(0),
+                taker_fee: dec!(0.001),
+                maker_fee: dec!(0.001),
+                market: market.name.to_string(),
+                post_only: false,
+                trailing_stop: None,
+                peg: None,
+            };
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &mut persistor,
+                    input,
+                    user_id,
+                )
+                .unwrap();
+        }
+        // half of the ask is still open and the fees are taken
+        assert!(!update_controller.ledger[&MockAsset::ETH.id()].fee.is_zero());
+        assert!(audit(balance_manager, &update_controller, std::iter::once(&market)).is_clean());
+
+        balance_manager.add(ask_user_id, BalanceType::FREEZE, &MockAsset::ETH.id(), &dec!(1));
+        let report = audit(balance_manager, &update_controller, std::iter::once(&market));
+        assert_eq!(report.mismatches.len(), 2);
+        assert_eq!(
+            report.mismatches[0],
+            AuditMismatch {
+                check: AuditCheck::FrozenBalance,
+                user_id: Some(ask_user_id),
+                asset: MockAsset::ETH.id(),
+                expected: dec!(10),
+                actual: dec!(11),
+            }
+        );
+        assert_eq!(report.mismatches[1].check, AuditCheck::AssetTotal);
+    }
+}
@@ -0,0 +1,42 @@
+use fluidex_common::utils::timeutil::current_timestamp;
+use std::cell::Cell;
+
+// The engine reads the time from here rather than from the system clock. While an operation runs
+// the time is pinned to the one logged with it, so its orders, trades and balance updates carry
+// a single timestamp and a replay of the log reproduces them exactly.
+thread_local! {
+    static PINNED: Cell<Option<f64>> = Cell::new(None);
+}
+
+pub fn now() -> f64 {
+    PINNED.with(|pinned| pinned.get()).unwrap_or_else(current_timestamp)
+}
+
+// unpins, or restores the outer pin, when dropped
+#[must_use]
+pub struct PinnedTime(Option<f64>);
+
+pub fn pin(time: f64) -> PinnedTime {
+    PinnedTime(PINNED.with(|pinned| pinned.replace(Some(time))))
+}
+
+impl Drop for PinnedTime {
+    fn drop(&mut self) {
+        PINNED.with(|pinned| pinned.set(self.0));
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_pinned_time() {
+    {
+        let _outer = pin(100.0);
+        assert_eq!(now(), 100.0);
+        {
+            let _inner = pin(200.0);
+            assert_eq!(now(), 200.0);
+        }
+        assert_eq!(now(), 100.0);
+    }
+    assert!(now() > 100.0);
+}
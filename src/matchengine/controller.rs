@@ -1,13 +1,23 @@
-use crate::asset::update_controller::{BalanceUpdateParams, BusinessType};
-use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController};
+use crate::asset::update_controller::{BalanceUpdateParams, BusinessType, DepositParams, TransferParams, VestingParams, WithdrawParams};
+use crate::asset::{
+    AccountLockManager, BalanceManager, BalanceType, BalanceUpdateController, BalanceUpdateKey, SubAccountManager, VestingSchedule,
+    WithdrawQuotaManager,
+};
+use crate::audit::{self, AuditReport};
+use crate::clock::{self, PinnedTime};
 use crate::config::{self};
 use crate::database::{DatabaseWriterConfig, OperationLogSender};
+use crate::dto::str_to_decimal;
 use crate::history::DatabaseHistoryWriter;
 use crate::market::{self, Order, OrderInput};
 use crate::message::{FullOrderMessageManager, SimpleMessageManager};
 use crate::models::{self};
-use crate::persist::{CompositePersistor, DBBasedPersistor, DummyPersistor, FileBasedPersistor, MessengerBasedPersistor, PersistExector};
+use crate::persist::{
+    CompositePersistor, DBBasedPersistor, DummyPersistor, FileBasedPersistor, LeaderLock, MessageFileConfig, MessengerBasedPersistor,
+    OperationWal, PersistExector, StateSnapshot,
+};
 use crate::sequencer::Sequencer;
+use crate::state_hash::StateHash;
 use crate::storage::config::MarketConfigs;
 use crate::types::{ConnectionType, DbType, SimpleResult};
 
@@ -16,14 +26,15 @@ use fluidex_common::helper::{MergeSortIterator, Order as SortOrder};
 use fluidex_common::rust_decimal::prelude::Zero;
 use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
+use itertools::Itertools;
 use orchestra::rpc::exchange::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::Connection;
 use sqlx::Executor;
 use tonic::{self, Status};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::str::FromStr;
 use uuid::Uuid;
@@ -32,6 +43,17 @@ type MarketName = String;
 type BaseAsset = String;
 type QuoteAsset = String;
 
+// What an operation takes from the engine besides its request, logged with it so a replay
+// executes it with the same values
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OperationInputs {
+    pub time: f64,
+    // the USDT price of each asset the operation was recorded with, the last trade prices are
+    // not part of a slice
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub market_prices: BTreeMap<String, Decimal>,
+}
+
 pub trait OperationLogConsumer {
     fn is_block(&self) -> bool;
     fn append_operation_log(&mut self, item: models::OperationLog) -> anyhow::Result<(), models::OperationLog>;
@@ -79,7 +101,7 @@ fn create_persistor(settings: &config::Settings) -> Box<dyn PersistExector> {
         ))));
     }
     if settings.brokers.is_empty() || persist_to_file {
-        persistor.add_persistor(Box::new(FileBasedPersistor::new("persistor_output.txt")));
+        persistor.add_persistor(Box::new(FileBasedPersistor::new(MessageFileConfig::from_settings(settings))));
     }
     persistor
 }
@@ -93,10 +115,20 @@ pub struct Controller {
     pub balance_manager: BalanceManager,
     //    pub asset_manager: AssetManager,
     pub update_controller: BalanceUpdateController,
+    pub sub_account_manager: SubAccountManager,
+    pub withdraw_quota_manager: WithdrawQuotaManager,
+    pub account_lock_manager: AccountLockManager,
+    // inputs of the operation being executed
+    pub inputs: OperationInputs,
     pub markets: HashMap<MarketName, market::Market>,
     pub asset_market_names: HashMap<(BaseAsset, QuoteAsset), MarketName>,
     // TODO: is it worth to use generics rather than dynamic pointer?
     pub log_handler: Box<dyn OperationLogConsumer + Send + Sync>,
+    // opened once the log has been replayed at startup, none when wal_dir is not set
+    pub wal: Option<OperationWal>,
+    pub role: config::Role,
+    // held as long as this engine is the leader
+    pub leader_lock: Option<LeaderLock>,
     pub persistor: Box<dyn PersistExector>,
     // TODO: is this needed?
     pub dummy_persistor: Box<dyn PersistExector>,
@@ -108,16 +140,46 @@ const ORDER_LIST_MAX_LEN: usize = 100;
 const OPERATION_BALANCE_UPDATE: &str = "balance_update";
 const OPERATION_ORDER_CANCEL: &str = "order_cancel";
 const OPERATION_ORDER_CANCEL_ALL: &str = "order_cancel_all";
+const OPERATION_ORDER_BULK_CANCEL: &str = "order_bulk_cancel";
 const OPERATION_ORDER_PUT: &str = "order_put";
 const OPERATION_BATCH_ORDER_PUT: &str = "batch_order_put";
 const OPERATION_TRANSFER: &str = "transfer";
+const OPERATION_MMP_SET: &str = "mmp_set";
+const OPERATION_MMP_RESET: &str = "mmp_reset";
+pub const OPERATION_SUB_ACCOUNT_CREATE: &str = "sub_account_create";
+const OPERATION_DEPOSIT_REQUEST: &str = "deposit_request";
+const OPERATION_DEPOSIT_CONFIRM: &str = "deposit_confirm";
+const OPERATION_DEPOSIT_CANCEL: &str = "deposit_cancel";
+const OPERATION_WITHDRAW_REQUEST: &str = "withdraw_request";
+const OPERATION_WITHDRAW_CONFIRM: &str = "withdraw_confirm";
+const OPERATION_WITHDRAW_REJECT: &str = "withdraw_reject";
+const OPERATION_WITHDRAW_QUOTA_SET: &str = "withdraw_quota_set";
+const OPERATION_ACCOUNT_FREEZE: &str = "account_freeze";
+const OPERATION_ACCOUNT_UNFREEZE: &str = "account_unfreeze";
+const OPERATION_VESTING_CREATE: &str = "vesting_create";
+const OPERATION_VESTING_RELEASE: &str = "vesting_release";
 
 pub fn create_controller(cfgs: (config::Settings, MarketConfigs)) -> Controller {
+    // a follower publishes nothing, the leader does
+    let persistor = match cfgs.0.role {
+        config::Role::Leader => create_persistor(&cfgs.0),
+        config::Role::Follower => DummyPersistor::new_box(),
+    };
+    create_controller_with_persistor(cfgs, persistor)
+}
+
+// For the tools that replay the operation log. They publish nothing, and they leave the message
+// files alone, an engine may be writing them.
+pub fn create_replay_controller(cfgs: (config::Settings, MarketConfigs)) -> Controller {
+    create_controller_with_persistor(cfgs, DummyPersistor::new_box())
+}
+
+fn create_controller_with_persistor(cfgs: (config::Settings, MarketConfigs), persistor: Box<dyn PersistExector>) -> Controller {
     let settings = cfgs.0;
     let main_pool = sqlx::Pool::<DbType>::connect_lazy(&settings.db_log).unwrap();
     let balance_manager = BalanceManager::new(&settings.assets).unwrap();
 
-    let update_controller = BalanceUpdateController::new();
+    let update_controller = BalanceUpdateController::with_keeptime(settings.business_id_keeptime as f64);
     //        let asset_manager = AssetManager::new(&settings.assets).unwrap();
     let sequencer = Sequencer::default();
     let mut markets = HashMap::new();
@@ -128,7 +190,6 @@ pub fn create_controller(cfgs: (config::Settings, MarketConfigs)) -> Controller
         asset_market_names.insert((entry.base.clone(), entry.quote.clone()), entry.name.clone());
     }
 
-    let persistor = create_persistor(&settings);
     let log_handler = OperationLogSender::new(&DatabaseWriterConfig {
         spawn_limit: 4,
         apply_benchmark: true,
@@ -137,14 +198,21 @@ pub fn create_controller(cfgs: (config::Settings, MarketConfigs)) -> Controller
     .start_schedule(&main_pool)
     .unwrap();
     Controller {
+        role: settings.role,
         settings,
         sequencer,
         //            asset_manager,
         balance_manager,
         update_controller,
+        sub_account_manager: SubAccountManager::new(),
+        withdraw_quota_manager: WithdrawQuotaManager::new(),
+        account_lock_manager: AccountLockManager::new(),
+        inputs: OperationInputs::default(),
         markets,
         asset_market_names,
         log_handler: Box::<OperationLogSender>::new(log_handler),
+        wal: None,
+        leader_lock: None,
         persistor,
         dummy_persistor: DummyPersistor::new_box(),
         db_pool: main_pool,
@@ -175,6 +243,7 @@ impl Controller {
         Ok(result)
     }
     pub fn balance_query(&self, req: BalanceQueryRequest, user_id: Uuid) -> Result<BalanceQueryResponse, Status> {
+        let account_id = self.account_id(user_id, &req.sub_account)?;
         let all_asset_param_valid = req
             .assets
             .iter()
@@ -187,24 +256,90 @@ impl Controller {
         } else {
             req.assets
         };
+        Ok(BalanceQueryResponse {
+            balances: self.asset_balances(account_id, query_assets),
+        })
+    }
+    fn asset_balances(&self, account_id: Uuid, assets: Vec<String>) -> Vec<balance_query_response::AssetBalance> {
         let balance_manager = &self.balance_manager;
-        let balances = query_assets
+        // the withdrawal quota is shared by the master account and its sub-accounts
+        let owner = self.sub_account_manager.owner(&account_id);
+        let now = clock::now();
+        assets
             .into_iter()
             .map(|asset_id| {
                 let available = balance_manager
-                    .get_with_round(user_id, BalanceType::AVAILABLE, &asset_id)
+                    .get_with_round(account_id, BalanceType::AVAILABLE, &asset_id)
+                    .to_string();
+                let frozen = balance_manager
+                    .get_with_round(account_id, BalanceType::FREEZE, &asset_id)
+                    .to_string();
+                let depositing = balance_manager
+                    .get_with_round(account_id, BalanceType::DEPOSITING, &asset_id)
+                    .to_string();
+                let withdrawing = balance_manager
+                    .get_with_round(account_id, BalanceType::WITHDRAWING, &asset_id)
+                    .to_string();
+                let asset_quota = balance_manager.asset_manager.asset_get(&asset_id).unwrap().withdraw_daily_quota;
+                let withdraw_quota = self.withdraw_quota_manager.quota(owner, &asset_id, asset_quota).to_string();
+                let withdraw_used = self.withdraw_quota_manager.used(owner, &asset_id, now).to_string();
+                let frozen_by_admin = self.account_lock_manager.is_locked(owner, &asset_id);
+                let locked = balance_manager
+                    .get_with_round(account_id, BalanceType::LOCKED, &asset_id)
                     .to_string();
-                let frozen = balance_manager.get_with_round(user_id, BalanceType::FREEZE, &asset_id).to_string();
+                // zero when nothing is left to unlock
+                let next_unlock = self
+                    .update_controller
+                    .vestings
+                    .values()
+                    .filter(|vesting| vesting.user_id == account_id && vesting.asset == asset_id)
+                    .filter_map(|vesting| vesting.schedule.next_unlock(now))
+                    .fold(0.0, |next: f64, time| if next == 0.0 { time } else { next.min(time) });
                 balance_query_response::AssetBalance {
                     asset_id,
                     available,
                     frozen,
+                    depositing,
+                    withdrawing,
+                    withdraw_quota,
+                    withdraw_used,
+                    frozen_by_admin,
+                    locked,
+                    next_unlock,
                 }
             })
+            .collect()
+    }
+    // the master account and all sub-accounts of the user with their balances
+    pub fn sub_account_list(&self, _req: SubAccountListRequest, user_id: Uuid) -> Result<SubAccountListResponse, Status> {
+        let assets: Vec<String> = self.settings.assets.iter().map(|asset| asset.id.clone()).collect();
+        let master = std::iter::once((String::new(), user_id));
+        let sub_accounts = master
+            .chain(self.sub_account_manager.list(&user_id))
+            .map(|(name, account_id)| SubAccountInfo {
+                name,
+                account_id: account_id.to_string(),
+                balances: self.asset_balances(account_id, assets.clone()),
+            })
             .collect();
-        Ok(BalanceQueryResponse { balances })
+        Ok(SubAccountListResponse { sub_accounts })
+    }
+    // whether a balance update with this business id was applied and is still remembered
+    pub fn business_id_query(&self, req: BusinessIdQueryRequest, user_id: Uuid) -> Result<BusinessIdQueryResponse, Status> {
+        let account_id = self.account_id(user_id, &req.sub_account)?;
+        let applied_time = self.update_controller.applied_time(&BalanceUpdateKey {
+            user_id: account_id,
+            asset: req.asset,
+            business: req.business,
+            business_id: req.business_id,
+        });
+        Ok(BusinessIdQueryResponse {
+            applied: applied_time.is_some(),
+            applied_time: applied_time.unwrap_or_default(),
+        })
     }
     pub fn order_query(&self, req: OrderQueryRequest, user_id: Uuid) -> Result<OrderQueryResponse, Status> {
+        let user_id = self.account_id(user_id, &req.sub_account)?;
         if req.market != "all" && !self.markets.contains_key(&req.market) {
             return Err(Status::invalid_argument("invalid market"));
         }
@@ -225,13 +360,15 @@ impl Controller {
             .map(|(_key, market)| market);
         let total_order_count: usize = markets
             .clone()
-            .map(|m| m.users.get(&user_id).map(|order_map| order_map.len()).unwrap_or(0))
+            .map(|m| m.users.get(&user_id).map(|order_ids| order_ids.len()).unwrap_or(0))
             .sum();
         let orders_by_market: Vec<Box<dyn Iterator<Item = Order>>> = markets
             .map(|m| {
                 m.users
                     .get(&user_id)
-                    .map(|order_map| Box::new(order_map.values().rev().map(|order_rc| order_rc.deep())) as Box<dyn Iterator<Item = Order>>)
+                    .map(|order_ids| {
+                        Box::new(order_ids.iter().rev().map(move |order_id| m.orders[order_id])) as Box<dyn Iterator<Item = Order>>
+                    })
                     .unwrap_or_else(|| Box::new(Vec::new().into_iter()) as Box<dyn Iterator<Item = Order>>)
             })
             .collect();
@@ -333,7 +470,18 @@ impl Controller {
         Ok(MarketSummaryResponse { market_summaries })
     }
 
-    fn check_service_available(&self) -> bool {
+    fn check_service_available(&self, real: bool) -> bool {
+        // a follower only applies what the leader has logged
+        if real && self.role == config::Role::Follower {
+            return false;
+        }
+        // a leader answers within its lease and the operation log ids it has reserved only
+        if let (true, Some(lock)) = (real, self.leader_lock.as_ref()) {
+            if !lock.may_answer(self.sequencer.get_operation_log_id() + 1) {
+                log::warn!("leader lease expired or operation log reservation used up");
+                return false;
+            }
+        }
         if self.log_handler.is_block() {
             log::warn!("log_handler full");
             return false;
@@ -347,21 +495,31 @@ impl Controller {
         req: BalanceUpdateRequest,
         user_id: Uuid,
     ) -> std::result::Result<BalanceUpdateResponse, Status> {
-        if !self.check_service_available() {
+        if !self.check_service_available(real) {
             return Err(Status::unavailable(""));
         }
+        let _now = self.begin_operation(real);
         let asset = &req.asset;
         if !self.balance_manager.asset_manager.asset_exist(asset) {
             return Err(Status::invalid_argument("invalid asset"));
         }
+        let account_id = self.account_id(user_id, &req.sub_account)?;
         let prec = self.balance_manager.asset_manager.asset_prec_show(asset);
         let change_result = Decimal::from_str(req.delta.as_str()).map_err(|_| Status::invalid_argument("invalid amount"))?;
         let change = change_result.round_dp(prec);
+        if change.is_sign_positive() {
+            self.check_deposit_limits(real, asset, &change)?;
+        } else {
+            self.check_account_lock(real, user_id, asset)?;
+            self.check_withdraw_limits(real, user_id, asset, &-change)?;
+        }
         let detail_json: serde_json::Value = if req.detail.is_empty() {
             json!({})
         } else {
             serde_json::from_str(req.detail.as_str()).map_err(|_| Status::invalid_argument("invalid detail"))?
         };
+        // Get market price of requested base asset and quote asset of USDT.
+        let market_price = self.market_price(real, asset);
         //let persistor = self.get_persistor(real);
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
         let business_type = if change.is_sign_positive() {
@@ -369,11 +527,6 @@ impl Controller {
         } else {
             BusinessType::Withdraw
         };
-        // Get market price of requested base asset and quote asset of USDT.
-        let market_price = match self.asset_market_names.get(&(asset.to_owned(), "USDT".to_owned())) {
-            Some(market_name) => self.markets.get(market_name).unwrap().price,
-            None => Decimal::zero(),
-        };
         self.update_controller
             .update_user_balance(
                 &mut self.balance_manager,
@@ -381,7 +534,7 @@ impl Controller {
                 BalanceUpdateParams {
                     balance_type: BalanceType::AVAILABLE,
                     business_type,
-                    user_id,
+                    user_id: account_id,
                     asset: asset.to_owned(),
                     business: req.business.clone(),
                     business_id: req.business_id,
@@ -391,20 +544,464 @@ impl Controller {
                 },
             )
             .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if !change.is_sign_positive() {
+            self.withdraw_quota_manager.record(user_id, asset, clock::now(), -change);
+        }
 
         // TODO how to handle this error?
-        // TODO operation_log after exec or before exec?
         if real {
             self.append_operation_log(OPERATION_BALANCE_UPDATE, &req, user_id);
         }
         Ok(BalanceUpdateResponse::default())
     }
 
+    // a deposit seen on chain, it can not be traded before the asset's confirmations are reached
+    pub fn deposit_request(&mut self, real: bool, req: DepositRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let asset = &req.asset;
+        if !self.balance_manager.asset_manager.asset_exist(asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let account_id = self.account_id(user_id, &req.sub_account)?;
+        let prec = self.balance_manager.asset_manager.asset_prec_show(asset);
+        let amount = Decimal::from_str(req.amount.as_str()).map_err(|_| Status::invalid_argument("invalid amount"))?;
+        if amount.round_dp(prec) != amount {
+            return Err(Status::invalid_argument("invalid amount precision"));
+        }
+        self.check_deposit_limits(real, asset, &amount)?;
+        let detail: serde_json::Value = if req.detail.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(req.detail.as_str()).map_err(|_| Status::invalid_argument("invalid detail"))?
+        };
+        let market_price = self.market_price(real, asset);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .deposit_request(
+                &mut self.balance_manager,
+                persistor,
+                DepositParams {
+                    user_id: account_id,
+                    asset: asset.to_owned(),
+                    business: req.business.clone(),
+                    business_id: req.business_id,
+                    market_price,
+                    amount,
+                    confirmations: req.confirmations,
+                    detail,
+                },
+            )
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_DEPOSIT_REQUEST, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    pub fn deposit_confirm(&mut self, real: bool, req: DepositConfirmRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .deposit_confirm(
+                &mut self.balance_manager,
+                persistor,
+                &req.business,
+                req.business_id,
+                req.confirmations,
+            )
+            .map_err(|e| Status::not_found(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_DEPOSIT_CONFIRM, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    pub fn deposit_cancel(&mut self, real: bool, req: DepositCancelRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .deposit_cancel(&mut self.balance_manager, persistor, &req.business, req.business_id)
+            .map_err(|e| Status::not_found(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_DEPOSIT_CANCEL, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    // first phase of a withdrawal, the amount stays locked until it is confirmed or rejected
+    pub fn withdraw_request(&mut self, real: bool, req: WithdrawRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let asset = &req.asset;
+        if !self.balance_manager.asset_manager.asset_exist(asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let account_id = self.account_id(user_id, &req.sub_account)?;
+        let prec = self.balance_manager.asset_manager.asset_prec_show(asset);
+        let amount = Decimal::from_str(req.amount.as_str()).map_err(|_| Status::invalid_argument("invalid amount"))?;
+        if amount.round_dp(prec) != amount {
+            return Err(Status::invalid_argument("invalid amount precision"));
+        }
+        self.check_account_lock(real, user_id, asset)?;
+        self.check_withdraw_limits(real, user_id, asset, &amount)?;
+        let detail: serde_json::Value = if req.detail.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(req.detail.as_str()).map_err(|_| Status::invalid_argument("invalid detail"))?
+        };
+        let market_price = self.market_price(real, asset);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .withdraw_request(
+                &mut self.balance_manager,
+                persistor,
+                WithdrawParams {
+                    user_id: account_id,
+                    asset: asset.to_owned(),
+                    business: req.business.clone(),
+                    business_id: req.business_id,
+                    market_price,
+                    amount,
+                    detail,
+                },
+            )
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        let create_time = self.update_controller.pending_withdraws[&(req.business.clone(), req.business_id)].create_time;
+        self.withdraw_quota_manager.record(user_id, asset, create_time, amount);
+        if real {
+            self.append_operation_log(OPERATION_WITHDRAW_REQUEST, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    pub fn withdraw_confirm(&mut self, real: bool, req: WithdrawConfirmRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .withdraw_confirm(&mut self.balance_manager, persistor, &req.business, req.business_id)
+            .map_err(|e| Status::not_found(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_WITHDRAW_CONFIRM, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    pub fn withdraw_reject(&mut self, real: bool, req: WithdrawRejectRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let pending = self
+            .update_controller
+            .pending_withdraws
+            .get(&(req.business.clone(), req.business_id))
+            .cloned();
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .withdraw_reject(&mut self.balance_manager, persistor, &req.business, req.business_id)
+            .map_err(|e| Status::not_found(format!("{}", e)))?;
+        // a rejected withdrawal does not count against the quota
+        if let Some(withdraw) = pending {
+            let owner = self.sub_account_manager.owner(&withdraw.user_id);
+            self.withdraw_quota_manager
+                .release(owner, &withdraw.asset, withdraw.create_time, withdraw.amount);
+        }
+        if real {
+            self.append_operation_log(OPERATION_WITHDRAW_REJECT, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    // admins may give a user another daily withdrawal quota than the asset's, an empty quota drops it
+    pub fn withdraw_quota_set(&mut self, real: bool, req: WithdrawQuotaSetRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        if !self.balance_manager.asset_manager.asset_exist(&req.asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let target = Uuid::parse_str(&req.user_id).map_err(|_| Status::invalid_argument("invalid user"))?;
+        let quota = if req.quota.is_empty() {
+            None
+        } else {
+            let quota = Decimal::from_str(&req.quota).map_err(|_| Status::invalid_argument("invalid quota"))?;
+            if quota.is_sign_negative() {
+                return Err(Status::invalid_argument("invalid quota"));
+            }
+            Some(quota)
+        };
+        self.withdraw_quota_manager.set_override(target, &req.asset, quota);
+        if real {
+            self.append_operation_log(OPERATION_WITHDRAW_QUOTA_SET, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    // a locked allocation, unlocked linearly after a cliff or at the listed dates
+    pub fn vesting_create(&mut self, real: bool, req: VestingCreateRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let asset = &req.asset;
+        if !self.balance_manager.asset_manager.asset_exist(asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let account_id = self.account_id(user_id, &req.sub_account)?;
+        let prec = self.balance_manager.asset_manager.asset_prec_show(asset);
+        let parse_amount = |amount: &str| match Decimal::from_str(amount) {
+            Ok(amount) if amount.round_dp(prec) == amount => Ok(amount),
+            _ => Err(Status::invalid_argument("invalid amount")),
+        };
+        let amount = parse_amount(&req.amount)?;
+        let schedule = if req.unlocks.is_empty() {
+            VestingSchedule::Linear {
+                cliff_time: req.cliff_time,
+                start_time: req.start_time,
+                end_time: req.end_time,
+            }
+        } else {
+            VestingSchedule::Dates(
+                req.unlocks
+                    .iter()
+                    .map(|unlock| Ok((unlock.time, parse_amount(&unlock.amount)?)))
+                    .collect::<Result<Vec<_>, Status>>()?,
+            )
+        };
+        let detail: serde_json::Value = if req.detail.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(req.detail.as_str()).map_err(|_| Status::invalid_argument("invalid detail"))?
+        };
+        let market_price = self.market_price(real, asset);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .vesting_create(
+                &mut self.balance_manager,
+                persistor,
+                VestingParams {
+                    user_id: account_id,
+                    asset: asset.to_owned(),
+                    business: req.business.clone(),
+                    business_id: req.business_id,
+                    market_price,
+                    amount,
+                    schedule,
+                    detail,
+                },
+            )
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_VESTING_CREATE, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    // the release time is a part of the request, so a replay unlocks exactly the same amounts
+    pub fn vesting_release(&mut self, real: bool, req: VestingReleaseRequest, user_id: Uuid) -> Result<VestingReleaseResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        if !req.time.is_finite() {
+            return Err(Status::invalid_argument("invalid time"));
+        }
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let released = self
+            .update_controller
+            .vesting_release(&mut self.balance_manager, persistor, req.time) as u32;
+        // nothing to replay when nothing was released
+        if real && released > 0 {
+            self.append_operation_log(OPERATION_VESTING_RELEASE, &req, user_id);
+        }
+        Ok(VestingReleaseResponse { released })
+    }
+
+    // called by the engine itself on a timer
+    pub fn release_vestings(&mut self) {
+        let req = VestingReleaseRequest { time: clock::now() };
+        if let Err(e) = self.vesting_release(true, req, Uuid::nil()) {
+            log::error!("release vestings failed: {}", e);
+        }
+    }
+
+    // lock a user, or one asset of it, an empty asset means the whole account
+    pub fn account_freeze(&mut self, real: bool, req: AccountFreezeRequest, user_id: Uuid) -> Result<AccountFreezeResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        if !req.asset.is_empty() && !self.balance_manager.asset_manager.asset_exist(&req.asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let target = Uuid::parse_str(&req.user_id).map_err(|_| Status::invalid_argument("invalid user"))?;
+        self.account_lock_manager.lock(target, &req.asset, &req.reason);
+        let mut cancelled = 0;
+        let accounts: Vec<Uuid> = std::iter::once(target)
+            .chain(self.sub_account_manager.list(&target).into_iter().map(|(_, account_id)| account_id))
+            .collect();
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        for market in self.markets.values_mut() {
+            if !req.asset.is_empty() && market.base != req.asset && market.quote != req.asset {
+                continue;
+            }
+            for account_id in &accounts {
+                if req.cancel_orders {
+                    cancelled += market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, account_id.to_string()) as u32;
+                } else {
+                    // the market would still trigger the stops and reprice the pegged orders of the frozen account
+                    let order_ids: Vec<u64> = market
+                        .get_order_of_user(account_id)
+                        .into_iter()
+                        .filter(|order| order.is_pending_stop() || order.is_pegged())
+                        .map(|order| order.id)
+                        .collect();
+                    cancelled += market
+                        .cancel_orders((&mut self.balance_manager).into(), persistor, &order_ids)
+                        .len() as u32;
+                }
+            }
+        }
+        if real {
+            self.append_operation_log(OPERATION_ACCOUNT_FREEZE, &req, user_id);
+        }
+        Ok(AccountFreezeResponse { cancelled })
+    }
+
+    pub fn account_unfreeze(&mut self, real: bool, req: AccountUnfreezeRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let target = Uuid::parse_str(&req.user_id).map_err(|_| Status::invalid_argument("invalid user"))?;
+        self.account_lock_manager
+            .unlock(target, &req.asset)
+            .map_err(|e| Status::not_found(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_ACCOUNT_UNFREEZE, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    // The lock is on the master account and covers its sub-accounts as well. A logged operation
+    // passed it already, a replay does not check it again.
+    fn check_account_lock(&self, real: bool, user_id: Uuid, asset: &str) -> Result<(), Status> {
+        if real && self.account_lock_manager.is_locked(user_id, asset) {
+            return Err(Status::permission_denied("account is frozen"));
+        }
+        Ok(())
+    }
+    fn check_market_lock(&self, real: bool, user_id: Uuid, market_name: &str) -> Result<(), Status> {
+        if let Some(market) = self.markets.get(market_name) {
+            self.check_account_lock(real, user_id, market.base)?;
+            self.check_account_lock(real, user_id, market.quote)?;
+        }
+        Ok(())
+    }
+
+    // The limits can be changed without an operation log entry. A logged operation was within the
+    // ones of its time, so a replay does not check it against the current ones.
+    fn check_deposit_limits(&self, real: bool, asset: &str, amount: &Decimal) -> Result<(), Status> {
+        if !real {
+            return Ok(());
+        }
+        let asset_info = self.balance_manager.asset_manager.asset_get(asset).unwrap();
+        if *amount < asset_info.min_deposit {
+            return Err(Status::out_of_range("amount below the minimum deposit"));
+        }
+        Ok(())
+    }
+    // the quota is kept per master account, so sub-accounts can not be used to get around it
+    fn check_withdraw_limits(&self, real: bool, user_id: Uuid, asset: &str, amount: &Decimal) -> Result<(), Status> {
+        if !real {
+            return Ok(());
+        }
+        let asset_info = self.balance_manager.asset_manager.asset_get(asset).unwrap();
+        if *amount < asset_info.min_withdraw {
+            return Err(Status::out_of_range("amount below the minimum withdrawal"));
+        }
+        if !asset_info.max_withdraw.is_zero() && *amount > asset_info.max_withdraw {
+            return Err(Status::out_of_range("amount above the maximum withdrawal"));
+        }
+        let quota = self.withdraw_quota_manager.quota(user_id, asset, asset_info.withdraw_daily_quota);
+        if !quota.is_zero() && self.withdraw_quota_manager.used(user_id, asset, clock::now()) + amount > quota {
+            return Err(Status::resource_exhausted("daily withdrawal quota exceeded"));
+        }
+        Ok(())
+    }
+
+    pub fn transfer(&mut self, real: bool, req: TransferRequest, user_id: Uuid) -> Result<TransferResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let asset = &req.asset;
+        if !self.balance_manager.asset_manager.asset_exist(asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let from = Uuid::parse_str(&req.from).map_err(|_| Status::invalid_argument("invalid from user"))?;
+        let to = Uuid::parse_str(&req.to).map_err(|_| Status::invalid_argument("invalid to user"))?;
+        // moving funds between the accounts of the same user is fine even when it is frozen
+        if from != to {
+            self.check_account_lock(real, from, asset)?;
+        }
+        let from = self.account_id(from, &req.from_sub_account)?;
+        let to = self.account_id(to, &req.to_sub_account)?;
+        let prec = self.balance_manager.asset_manager.asset_prec_show(asset);
+        let amount = Decimal::from_str(req.delta.as_str()).map_err(|_| Status::invalid_argument("invalid amount"))?;
+        if amount.round_dp(prec) != amount {
+            return Err(Status::invalid_argument("invalid amount precision"));
+        }
+        let market_price = self.market_price(real, asset);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .transfer(
+                &mut self.balance_manager,
+                persistor,
+                TransferParams {
+                    from,
+                    to,
+                    asset: asset.to_owned(),
+                    business_id: req.business_id,
+                    market_price,
+                    amount,
+                    memo: req.memo.clone(),
+                },
+            )
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_TRANSFER, &req, user_id);
+        }
+        Ok(TransferResponse {
+            success: true,
+            asset: asset.to_owned(),
+            balance_from: self.balance_manager.get(from, BalanceType::AVAILABLE, asset).to_string(),
+        })
+    }
+
     pub fn order_put(&mut self, real: bool, req: OrderPutRequest, user_id: Uuid) -> Result<OrderInfo, Status> {
-        if !self.check_service_available() {
+        if !self.check_service_available(real) {
             return Err(Status::unavailable(""));
         }
-        let order = self.put_order(real, &req, user_id)?;
+        let _now = self.begin_operation(real);
+        let account_id = self.account_id(user_id, &req.sub_account)?;
+        self.check_market_lock(real, user_id, &req.market)?;
+        let order = self.put_order(real, &req, account_id)?;
         if real {
             self.append_operation_log(OPERATION_ORDER_PUT, &req, user_id);
         }
@@ -412,14 +1009,55 @@ impl Controller {
     }
 
     pub fn batch_order_put(&mut self, real: bool, req: BatchOrderPutRequest, user_id: Uuid) -> Result<BatchOrderPutResponse, Status> {
-        if !self.check_service_available() {
+        if !self.check_service_available(real) {
             return Err(Status::unavailable(""));
         }
+        let _now = self.begin_operation(real);
+        let account_id = self.account_id(user_id, &req.sub_account)?;
         let market_name = &req.market;
         if !self.markets.contains_key(market_name) {
             return Err(Status::invalid_argument("invalid market"));
         }
+        self.check_market_lock(real, user_id, market_name)?;
         let orders = &req.orders;
+        if req.atomic {
+            // validate the whole batch before touching anything, so it is placed all-or-nothing
+            if orders.iter().any(|order_req| market_name != &order_req.market) {
+                return Err(Status::invalid_argument("inconsistent order markets"));
+            }
+            let market = self.markets.get(market_name).unwrap();
+            let total_order_num: usize = self
+                .markets
+                .iter()
+                .map(|(_, market)| market.get_order_num_of_user(&account_id))
+                .sum();
+            let released_order_num = if req.reset { market.get_order_num_of_user(&account_id) } else { 0 };
+            if total_order_num - released_order_num + orders.len() > self.settings.user_order_num_limit {
+                return Err(Status::unavailable("too many active orders for user"));
+            }
+            let order_inputs = orders
+                .iter()
+                .cloned()
+                .map(OrderInput::try_from)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
+            market
+                .check_batch_orders((&mut self.balance_manager).into(), &order_inputs, account_id, req.reset)
+                .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        }
+        // The checks can not foresee everything the placement moves, like pegged orders repriced,
+        // stops triggered or the protection tripped by the trades of the batch. An atomic batch is
+        // placed on top of a copy of what it may change, which is put back if any order fails.
+        let mut checkpoint = if req.atomic {
+            Some((
+                self.markets.get(market_name).unwrap().clone(),
+                self.balance_manager.balances.clone(),
+                self.update_controller.ledger.clone(),
+                self.sequencer.clone(),
+            ))
+        } else {
+            None
+        };
         if req.reset {
             for order_req in orders {
                 if market_name != &order_req.market {
@@ -427,7 +1065,7 @@ impl Controller {
                 }
                 let market = self.markets.get_mut(market_name).unwrap();
                 let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-                market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, user_id.to_string());
+                market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, account_id.to_string());
             }
         }
         let mut result_code = ResultCode::Success;
@@ -438,9 +1076,20 @@ impl Controller {
                 return Err(Status::invalid_argument("inconsistent order markets"));
             }
 
-            match self.put_order(real, order_req, user_id) {
+            match self.put_order(real, order_req, account_id) {
                 Ok(order) => order_ids.push(order.id),
                 Err(error) => {
+                    if let Some((market, balances, ledger, sequencer)) = checkpoint.take() {
+                        // nothing of the batch happened, its messages are dropped and it is not logged
+                        self.markets.insert(market_name.clone(), market);
+                        self.balance_manager.balances = balances;
+                        self.update_controller.ledger = ledger;
+                        self.sequencer = sequencer;
+                        if real {
+                            self.persistor.discard_operation();
+                        }
+                        return Err(error);
+                    }
                     result_code = ResultCode::InternalError;
                     error_message = error.to_string();
                     break;
@@ -458,9 +1107,11 @@ impl Controller {
     }
 
     pub fn order_cancel(&mut self, real: bool, req: OrderCancelRequest, user_id: Uuid) -> Result<OrderInfo, tonic::Status> {
-        if !self.check_service_available() {
+        if !self.check_service_available(real) {
             return Err(Status::unavailable(""));
         }
+        let _now = self.begin_operation(real);
+        let account_id = self.account_id(user_id, &req.sub_account)?;
         let market = self
             .markets
             .get_mut(&req.market)
@@ -468,7 +1119,7 @@ impl Controller {
         let order = market
             .get(req.order_id)
             .ok_or_else(|| Status::invalid_argument("invalid order_id"))?;
-        if !order.user.eq(&user_id) {
+        if !order.user.eq(&account_id) {
             return Err(Status::invalid_argument("invalid user"));
         }
         let balance_manager = &mut self.balance_manager;
@@ -487,22 +1138,204 @@ impl Controller {
         req: OrderCancelAllRequest,
         user_id: Uuid,
     ) -> Result<OrderCancelAllResponse, tonic::Status> {
-        if !self.check_service_available() {
+        if !self.check_service_available(real) {
             return Err(Status::unavailable(""));
         }
+        let _now = self.begin_operation(real);
+        let account_id = self.account_id(user_id, &req.sub_account)?;
         let market = self
             .markets
             .get_mut(&req.market)
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
         //let persistor = self.get_persistor(real);
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-        let total = market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, user_id.to_string()) as u32;
+        let total = market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, account_id.to_string()) as u32;
         if real {
             self.append_operation_log(OPERATION_ORDER_CANCEL_ALL, &req, user_id);
         }
         Ok(OrderCancelAllResponse { total })
     }
 
+    // With order_ids only the listed orders are considered, otherwise all orders of the user
+    // in the market. The side and price filters narrow down either set.
+    pub fn order_bulk_cancel(
+        &mut self,
+        real: bool,
+        req: OrderBulkCancelRequest,
+        user_id: Uuid,
+    ) -> Result<OrderBulkCancelResponse, tonic::Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let account_id = self.account_id(user_id, &req.sub_account)?;
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let side = match req.side.map(OrderSide::from_i32) {
+            None => None,
+            Some(Some(OrderSide::Ask)) => Some(market::OrderSide::ASK),
+            Some(Some(OrderSide::Bid)) => Some(market::OrderSide::BID),
+            Some(None) => return Err(Status::invalid_argument("invalid side")),
+        };
+        let parse_price = |price: &str| -> Result<Option<Decimal>, Status> {
+            if price.is_empty() {
+                Ok(None)
+            } else {
+                Decimal::from_str(price)
+                    .map(Some)
+                    .map_err(|_| Status::invalid_argument("invalid price"))
+            }
+        };
+        let price_min = parse_price(&req.price_min)?;
+        let price_max = parse_price(&req.price_max)?;
+        if let (Some(price_min), Some(price_max)) = (price_min, price_max) {
+            if price_min > price_max {
+                return Err(Status::invalid_argument("price_min is greater than price_max"));
+            }
+        }
+
+        let mut not_found = Vec::new();
+        let order_ids = if req.order_ids.is_empty() {
+            market.select_user_orders(&account_id, side, price_min, price_max)
+        } else {
+            // an order filtered out would be reported neither as cancelled nor as not found
+            if side.is_some() || price_min.is_some() || price_max.is_some() {
+                return Err(Status::invalid_argument("order_ids cannot be combined with side or price filters"));
+            }
+            let mut order_ids = Vec::with_capacity(req.order_ids.len());
+            for order_id in req.order_ids.iter().copied().unique() {
+                match market.get(order_id) {
+                    // orders of other users are reported as gone, not as forbidden
+                    Some(order) if order.user.eq(&account_id) => order_ids.push(order_id),
+                    _ => not_found.push(order_id),
+                }
+            }
+            order_ids
+        };
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let cancelled = market.cancel_orders((&mut self.balance_manager).into(), persistor, &order_ids);
+        if real {
+            self.append_operation_log(OPERATION_ORDER_BULK_CANCEL, &req, user_id);
+        }
+        Ok(OrderBulkCancelResponse {
+            cancelled: cancelled.into_iter().map(OrderInfo::from).collect(),
+            not_found,
+        })
+    }
+
+    pub fn sub_account_create(&mut self, real: bool, req: SubAccountCreateRequest, user_id: Uuid) -> Result<SubAccountInfo, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let account_id = self
+            .sub_account_manager
+            .create(user_id, &req.name)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_SUB_ACCOUNT_CREATE, &req, user_id);
+        }
+        Ok(SubAccountInfo {
+            name: req.name,
+            account_id: account_id.to_string(),
+            balances: Vec::new(),
+        })
+    }
+
+    pub fn mmp_set(&mut self, real: bool, req: MmpSetRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let account_id = self.account_id(user_id, &req.sub_account)?;
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let qty_limit = str_to_decimal(&req.qty_limit, true).map_err(|_| Status::invalid_argument("invalid qty limit"))?;
+        if qty_limit.is_sign_negative() {
+            return Err(Status::invalid_argument("invalid qty limit"));
+        }
+        // both limits being zero turns the protection off
+        let config = if qty_limit.is_zero() && req.trade_count_limit == 0 {
+            None
+        } else {
+            if req.window_ms == 0 {
+                return Err(Status::invalid_argument("invalid window"));
+            }
+            Some(market::MmpConfig {
+                qty_limit,
+                trade_count_limit: req.trade_count_limit,
+                window: req.window_ms as f64 / 1000.0,
+            })
+        };
+        market.set_mmp(account_id, config);
+        if real {
+            self.append_operation_log(OPERATION_MMP_SET, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    pub fn mmp_reset(&mut self, real: bool, req: MmpResetRequest, user_id: Uuid) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available(real) {
+            return Err(Status::unavailable(""));
+        }
+        let _now = self.begin_operation(real);
+        let account_id = self.account_id(user_id, &req.sub_account)?;
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        if !market.reset_mmp(&account_id) {
+            return Err(Status::failed_precondition("market maker protection not configured"));
+        }
+        if real {
+            self.append_operation_log(OPERATION_MMP_RESET, &req, user_id);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    // check balances, orders and frozen funds against each other, mismatches are published as an alert
+    pub fn audit(&mut self) -> AuditReport {
+        let report = audit::audit(&self.balance_manager, &self.update_controller, self.markets.values());
+        if !report.is_clean() {
+            log::error!("ledger audit found {} mismatches: {:?}", report.mismatches.len(), report.mismatches);
+            self.persistor.put_audit(&report);
+        }
+        report
+    }
+
+    pub fn ledger_audit(&mut self, _req: LedgerAuditRequest) -> Result<LedgerAuditResponse, Status> {
+        let report = self.audit();
+        Ok(LedgerAuditResponse {
+            timestamp: report.timestamp,
+            mismatches: report
+                .mismatches
+                .into_iter()
+                .map(|mismatch| ledger_audit_response::Mismatch {
+                    check: format!("{:?}", mismatch.check),
+                    user_id: mismatch.user_id.map(|user_id| user_id.to_string()).unwrap_or_default(),
+                    asset: mismatch.asset,
+                    expected: mismatch.expected.to_string(),
+                    actual: mismatch.actual.to_string(),
+                })
+                .collect(),
+        })
+    }
+
+    // compare it between engines, or against the state_hash of a slice at the same operation log id
+    pub fn state_hash(&self, _req: StateHashRequest) -> Result<StateHashResponse, Status> {
+        let hash = StateHash::of(&StateSnapshot::take(current_timestamp() as i64, self));
+        Ok(StateHashResponse {
+            operation_log_id: self.sequencer.get_operation_log_id(),
+            root: hash.root_hex(),
+            balances: hex::encode(hash.balances),
+            markets: hash.markets.into_iter().map(|(market, root)| (market, hex::encode(root))).collect(),
+        })
+    }
+
     pub async fn debug_dump(&self, _req: DebugDumpRequest) -> Result<DebugDumpResponse, Status> {
         async {
             let mut connection = ConnectionType::connect(&self.settings.db_log).await?;
@@ -513,7 +1346,7 @@ impl Controller {
         Ok(DebugDumpResponse {})
     }
 
-    fn reset_state(&mut self) {
+    pub fn reset_state(&mut self) {
         self.sequencer.reset();
         for market in self.markets.values_mut() {
             market.reset();
@@ -521,6 +1354,9 @@ impl Controller {
         //self.log_handler.reset();
         self.update_controller.reset();
         self.balance_manager.reset();
+        self.sub_account_manager.reset();
+        self.withdraw_quota_manager.reset();
+        self.account_lock_manager.reset();
         //Ok(())
     }
 
@@ -627,6 +1463,14 @@ impl Controller {
         }
         .await
         .map_err(|err| Status::unknown(format!("{}", err)))?;
+        // the operation log starts over with the db
+        if let Some(wal) = self.wal.as_mut() {
+            wal.reset(1).map_err(|err| Status::unknown(format!("{}", err)))?;
+        }
+        // and so does the leader state
+        if let Some(lock) = self.leader_lock.take() {
+            self.take_over(lock).await.map_err(|err| Status::unknown(format!("{}", err)))?;
+        }
         Ok(DebugResetResponse {})
     }
 
@@ -642,24 +1486,184 @@ impl Controller {
         Ok(DebugReloadResponse {})
     }
 
+    // Replays a logged operation and publishes its messages again, with the ids they had the first
+    // time. Used at startup for the operations whose messages may not all have been delivered.
+    pub async fn replay_and_publish(&mut self, log: &models::OperationLog) -> SimpleResult {
+        // a replayed operation writes to the dummy persistor, it gets the real one meanwhile
+        std::mem::swap(&mut self.persistor, &mut self.dummy_persistor);
+        let ret = self.replay(log);
+        std::mem::swap(&mut self.persistor, &mut self.dummy_persistor);
+        ret?;
+        while !self.persistor.service_available() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        self.persistor.commit_operation(log.id as u64);
+        Ok(())
+    }
+
+    // records how far the messages are delivered, a restart publishes the ones after it again
+    pub async fn save_outbox_cursor(&self, operation_log_id: u64) -> SimpleResult {
+        let mut conn = self.db_pool.acquire().await?;
+        crate::persist::save_outbox_cursor(&mut conn, operation_log_id).await
+    }
+
+    // Turns a follower into the leader once it holds the leader lock and the lease of the old
+    // leader is over. The operations the old leader logged are applied first, then this engine
+    // logs and publishes its own.
+    pub async fn promote(&mut self, lock: LeaderLock) -> Result<PromoteResponse, Status> {
+        if self.role == config::Role::Leader {
+            return Err(Status::failed_precondition("already the leader"));
+        }
+        async {
+            let mut connection = ConnectionType::connect(&self.settings.db_log).await?;
+            crate::persist::follow_operation_log(&mut connection, self).await?;
+            let operation_log_id = self.sequencer.get_operation_log_id();
+            if crate::persist::get_last_operation_log_id(&mut connection).await? != operation_log_id {
+                bail!("operation log {} is missing, can not take over", operation_log_id + 1);
+            }
+            self.take_over(lock).await
+        }
+        .await
+        .map_err(|err: anyhow::Error| Status::unknown(format!("{}", err)))?;
+        self.persistor = create_persistor(&self.settings);
+        self.role = config::Role::Leader;
+        log::info!("promoted to leader at operation log {}", self.sequencer.get_operation_log_id());
+        Ok(PromoteResponse {
+            operation_log_id: self.sequencer.get_operation_log_id(),
+        })
+    }
+
+    // Starts a leader epoch after the operation log applied so far. Refused while the last leader
+    // may have answered operations from its wal that are not in the db yet, they would be lost;
+    // when this engine was that leader, its own wal has them and they are applied already.
+    pub async fn take_over(&mut self, mut lock: LeaderLock) -> SimpleResult {
+        let operation_log_id = self.sequencer.get_operation_log_id();
+        if let Some(state) = lock.state().await? {
+            let own_wal = self.wal.is_some() && crate::persist::wal::read_epoch(&self.settings.wal_dir)? == Some(state.epoch);
+            if !own_wal && state.wal_operation_log_id as u64 > operation_log_id {
+                bail!(
+                    "operation logs up to {} may be in the wal of the last leader only, restart it to write them to the db",
+                    state.wal_operation_log_id
+                );
+            }
+        }
+        let epoch = lock.take_over(operation_log_id).await?;
+        if !self.settings.wal_dir.is_empty() {
+            if self.wal.is_none() {
+                // a wal left by an earlier run of this engine is stale, the log went on without it
+                let mut wal = OperationWal::open(&self.settings.wal_dir, self.settings.wal_segment_size, operation_log_id + 1)?;
+                wal.reset(operation_log_id + 1)?;
+                self.wal = Some(wal);
+            }
+            crate::persist::wal::write_epoch(&self.settings.wal_dir, epoch)?;
+        }
+        self.leader_lock = Some(lock);
+        self.renew_leader_lease().await
+    }
+
+    // Called by the leader every follow interval. The lease outlives one missed renewal, the ids
+    // reserved for the wal are what a successor waits for.
+    pub async fn renew_leader_lease(&mut self) -> SimpleResult {
+        let reserved = self
+            .wal
+            .as_ref()
+            .map(|_| self.sequencer.get_operation_log_id() + self.settings.operation_log_reserve);
+        let lease = std::time::Duration::from_secs_f64(self.settings.follow_interval * 2.0);
+        match self.leader_lock.as_mut() {
+            Some(lock) => lock.renew(reserved, lease).await,
+            None => Ok(()),
+        }
+    }
+
+    // On leaving, the leader stops answering and reserves nothing beyond what it has logged, so a
+    // successor can take over once those logs are in the db.
+    pub async fn release_leader_lease(&mut self) -> SimpleResult {
+        let reserved = self.wal.as_ref().map(|_| self.sequencer.get_operation_log_id());
+        match self.leader_lock.as_mut() {
+            Some(lock) => lock.renew(reserved, std::time::Duration::from_secs(0)).await,
+            None => Ok(()),
+        }
+    }
+
+    // Replays a logged operation at the time it was executed. Logs from before the inputs were
+    // recorded replay at the current time.
+    pub fn replay(&mut self, log: &models::OperationLog) -> SimpleResult {
+        let user_id = log.user_id.parse()?;
+        if log.inputs.is_empty() {
+            self.inputs = OperationInputs::default();
+            return self.replay_operation(user_id, &log.method, &log.params);
+        }
+        self.inputs = serde_json::from_str(&log.inputs)?;
+        let _now = clock::pin(self.inputs.time);
+        self.replay_operation(user_id, &log.method, &log.params)
+    }
+
     // reload 1000 in batch and replay
-    pub fn replay(&mut self, user_id: Uuid, method: &str, params: &str) -> SimpleResult {
+    fn replay_operation(&mut self, user_id: Uuid, method: &str, params: &str) -> SimpleResult {
         match method {
             OPERATION_BALANCE_UPDATE => {
                 self.update_balance(false, serde_json::from_str(params)?, user_id)?;
             }
+            OPERATION_TRANSFER => {
+                self.transfer(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_DEPOSIT_REQUEST => {
+                self.deposit_request(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_DEPOSIT_CONFIRM => {
+                self.deposit_confirm(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_DEPOSIT_CANCEL => {
+                self.deposit_cancel(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_WITHDRAW_REQUEST => {
+                self.withdraw_request(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_WITHDRAW_CONFIRM => {
+                self.withdraw_confirm(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_WITHDRAW_REJECT => {
+                self.withdraw_reject(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_WITHDRAW_QUOTA_SET => {
+                self.withdraw_quota_set(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_ACCOUNT_FREEZE => {
+                self.account_freeze(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_ACCOUNT_UNFREEZE => {
+                self.account_unfreeze(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_VESTING_CREATE => {
+                self.vesting_create(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_VESTING_RELEASE => {
+                self.vesting_release(false, serde_json::from_str(params)?, user_id)?;
+            }
             OPERATION_ORDER_CANCEL => {
                 self.order_cancel(false, serde_json::from_str(params)?, user_id)?;
             }
             OPERATION_ORDER_CANCEL_ALL => {
                 self.order_cancel_all(false, serde_json::from_str(params)?, user_id)?;
             }
+            OPERATION_ORDER_BULK_CANCEL => {
+                self.order_bulk_cancel(false, serde_json::from_str(params)?, user_id)?;
+            }
             OPERATION_ORDER_PUT => {
                 self.order_put(false, serde_json::from_str(params)?, user_id)?;
             }
             OPERATION_BATCH_ORDER_PUT => {
                 self.batch_order_put(false, serde_json::from_str(params)?, user_id)?;
             }
+            OPERATION_SUB_ACCOUNT_CREATE => {
+                self.sub_account_create(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_MMP_SET => {
+                self.mmp_set(false, serde_json::from_str(params)?, user_id)?;
+            }
+            OPERATION_MMP_RESET => {
+                self.mmp_reset(false, serde_json::from_str(params)?, user_id)?;
+            }
             _ => bail!("invalid operation {}", method),
         }
         Ok(())
@@ -689,6 +1693,41 @@ impl Controller {
             )
             .map_err(|e| Status::unknown(format!("{}", e)))
     }
+    // the account that owns balances and orders, the user itself or one of its sub-accounts
+    fn account_id(&self, user_id: Uuid, sub_account: &str) -> Result<Uuid, Status> {
+        self.sub_account_manager
+            .resolve(user_id, sub_account)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))
+    }
+    // Pins the engine time of a new operation and clears the inputs of the last one. A replay has
+    // set both from the log already.
+    fn begin_operation(&mut self, real: bool) -> Option<PinnedTime> {
+        if !real {
+            return None;
+        }
+        self.persistor.discard_operation();
+        self.inputs = OperationInputs {
+            time: clock::now(),
+            market_prices: BTreeMap::new(),
+        };
+        Some(clock::pin(self.inputs.time))
+    }
+    // the USDT price of the asset, as logged when replaying
+    fn market_price(&mut self, real: bool, asset: &str) -> Decimal {
+        if !real {
+            if let Some(price) = self.inputs.market_prices.get(asset) {
+                return *price;
+            }
+        }
+        let price = match self.asset_market_names.get(&(asset.to_owned(), "USDT".to_owned())) {
+            Some(market_name) => self.markets.get(market_name).unwrap().price,
+            None => Decimal::zero(),
+        };
+        if real {
+            self.inputs.market_prices.insert(asset.to_owned(), price);
+        }
+        price
+    }
     fn append_operation_log<Operation>(&mut self, method: &str, req: &Operation, user_id: Uuid)
     where
         Operation: Serialize,
@@ -697,11 +1736,24 @@ impl Controller {
         let operation_log = models::OperationLog {
             id: self.sequencer.next_operation_log_id() as i64,
             user_id: user_id.to_string(),
-            time: FTimestamp(current_timestamp()).into(),
+            time: FTimestamp(self.inputs.time).into(),
             method: method.to_owned(),
             params,
+            inputs: serde_json::to_string(&self.inputs).unwrap(),
+            epoch: self.leader_lock.as_ref().map_or(0, LeaderLock::epoch),
         };
+        // The operation is already applied in memory and is about to be answered, an operation
+        // that can not be made durable must not be seen by anyone. The db gets it afterwards.
+        if let Some(wal) = self.wal.as_mut() {
+            if let Err(e) = wal.append(&operation_log) {
+                log::error!("append operation log {} to wal fail: {:?}", operation_log.id, e);
+                log::logger().flush();
+                std::process::abort();
+            }
+        }
+        let operation_log_id = operation_log.id as u64;
         (*self.log_handler).append_operation_log(operation_log).ok();
+        self.persistor.commit_operation(operation_log_id);
     }
 }
 
@@ -1,4 +1,5 @@
 use crate::market;
+use crate::types::TrailingType;
 
 use anyhow::{anyhow, Result};
 use fluidex_common::rust_decimal::{self, prelude::Zero, Decimal};
@@ -42,6 +43,15 @@ impl From<market::Order> for OrderInfo {
             finished_quote: o.finished_quote.to_string(),
             finished_fee: o.finished_fee.to_string(),
             post_only: o.post_only,
+            trigger_price: o.trailing_stop.map(|stop| stop.trigger_price.to_string()).unwrap_or_default(),
+            peg_reference: match o.peg.map(|peg| peg.reference) {
+                None => PegReference::None as i32,
+                Some(market::PegReference::SAMESIDE) => PegReference::SameSide as i32,
+                Some(market::PegReference::OPPOSITE) => PegReference::Opposite as i32,
+                Some(market::PegReference::MID) => PegReference::Mid as i32,
+            },
+            peg_offset: o.peg.map(|peg| peg.offset.to_string()).unwrap_or_default(),
+            peg_limit: o.peg.map(|peg| peg.limit_price.to_string()).unwrap_or_default(),
         }
     }
 }
@@ -50,6 +60,33 @@ impl TryFrom<OrderPutRequest> for market::OrderInput {
     type Error = anyhow::Error;
 
     fn try_from(req: OrderPutRequest) -> std::result::Result<Self, Self::Error> {
+        let trailing_stop = match (req.trail_offset.is_empty(), req.trail_percentage.is_empty()) {
+            (true, true) => None,
+            (false, true) => Some(market::TrailingStop::new(
+                TrailingType::OFFSET,
+                str_to_decimal(&req.trail_offset, false).map_err(|_| anyhow!("invalid trail offset"))?,
+            )),
+            (true, false) => Some(market::TrailingStop::new(
+                TrailingType::PERCENTAGE,
+                str_to_decimal(&req.trail_percentage, false).map_err(|_| anyhow!("invalid trail percentage"))?,
+            )),
+            (false, false) => return Err(anyhow!("trail offset and trail percentage cannot be both set")),
+        };
+        let peg_reference = match PegReference::from_i32(req.peg_reference) {
+            Some(PegReference::None) => None,
+            Some(PegReference::SameSide) => Some(market::PegReference::SAMESIDE),
+            Some(PegReference::Opposite) => Some(market::PegReference::OPPOSITE),
+            Some(PegReference::Mid) => Some(market::PegReference::MID),
+            None => return Err(anyhow!("invalid peg reference")),
+        };
+        let peg = match peg_reference {
+            Some(reference) => Some(market::Peg {
+                reference,
+                offset: str_to_decimal(&req.peg_offset, true).map_err(|_| anyhow!("invalid peg offset"))?,
+                limit_price: str_to_decimal(&req.peg_limit, true).map_err(|_| anyhow!("invalid peg limit"))?,
+            }),
+            None => None,
+        };
         Ok(market::OrderInput {
             side: if req.order_side == OrderSide::Ask as i32 {
                 market::OrderSide::ASK
@@ -62,12 +99,15 @@ impl TryFrom<OrderPutRequest> for market::OrderInput {
                 market::OrderType::MARKET
             },
             amount: str_to_decimal(&req.amount, false).map_err(|_| anyhow!("invalid amount"))?,
-            price: str_to_decimal(&req.price, req.order_type == OrderType::Market as i32).map_err(|_| anyhow!("invalid price"))?,
+            price: str_to_decimal(&req.price, req.order_type == OrderType::Market as i32 || peg.is_some())
+                .map_err(|_| anyhow!("invalid price"))?,
             quote_limit: str_to_decimal(&req.quote_limit, true).map_err(|_| anyhow!("invalid quote limit"))?,
             taker_fee: str_to_decimal(&req.taker_fee, true).map_err(|_| anyhow!("invalid taker fee"))?,
             maker_fee: str_to_decimal(&req.maker_fee, true).map_err(|_| anyhow!("invalid maker fee"))?,
             market: req.market.clone(),
             post_only: req.post_only,
+            trailing_stop,
+            peg,
         })
     }
 }
@@ -9,6 +9,7 @@ use fluidex_common::utils::timeutil::FTimestamp;
 type BalanceWriter = DatabaseWriter<models::BalanceHistory>;
 type OrderWriter = DatabaseWriter<models::OrderHistory>;
 type TradeWriter = DatabaseWriter<models::UserTrade>;
+type InternalTxWriter = DatabaseWriter<models::InternalTx>;
 
 pub trait HistoryWriter: Sync + Send {
     fn is_block(&self) -> bool;
@@ -17,6 +18,7 @@ pub trait HistoryWriter: Sync + Send {
     fn append_order_history(&mut self, order: &market::Order);
     fn append_expired_order_history(&mut self, _order: &market::Order);
     fn append_pair_user_trade(&mut self, trade: &Trade);
+    fn append_internal_tx(&mut self, tx: models::InternalTx);
 }
 
 pub struct DummyHistoryWriter;
@@ -25,6 +27,7 @@ impl HistoryWriter for DummyHistoryWriter {
     fn append_order_history(&mut self, _order: &market::Order) {}
     fn append_expired_order_history(&mut self, _order: &market::Order) {}
     fn append_pair_user_trade(&mut self, _trade: &Trade) {}
+    fn append_internal_tx(&mut self, _tx: models::InternalTx) {}
     fn is_block(&self) -> bool {
         false
     }
@@ -34,6 +37,7 @@ pub struct DatabaseHistoryWriter {
     pub balance_writer: BalanceWriter,
     pub trade_writer: TradeWriter,
     pub order_writer: OrderWriter,
+    pub internal_tx_writer: InternalTxWriter,
 }
 
 impl DatabaseHistoryWriter {
@@ -42,6 +46,7 @@ impl DatabaseHistoryWriter {
             balance_writer: BalanceWriter::new(config).start_schedule(pool)?,
             trade_writer: TradeWriter::new(config).start_schedule(pool)?,
             order_writer: OrderWriter::new(config).start_schedule(pool)?,
+            internal_tx_writer: InternalTxWriter::new(config).start_schedule(pool)?,
         })
     }
 }
@@ -77,7 +82,7 @@ impl<'r> From<&'r market::Order> for models::OrderHistory {
 
 impl HistoryWriter for DatabaseHistoryWriter {
     fn is_block(&self) -> bool {
-        self.balance_writer.is_block() || self.trade_writer.is_block() || self.order_writer.is_block()
+        self.balance_writer.is_block() || self.trade_writer.is_block() || self.order_writer.is_block() || self.internal_tx_writer.is_block()
     }
     fn append_balance_history(&mut self, data: models::BalanceHistory) {
         self.balance_writer.append(data).ok();
@@ -125,4 +130,7 @@ impl HistoryWriter for DatabaseHistoryWriter {
         self.trade_writer.append(ask_trade).ok();
         self.trade_writer.append(bid_trade).ok();
     }
+    fn append_internal_tx(&mut self, tx: models::InternalTx) {
+        self.internal_tx_writer.append(tx).ok();
+    }
 }
@@ -0,0 +1,84 @@
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+// market maker protection: once the resting orders of a user are filled beyond
+// the limits inside the window, all orders of that user in the market are pulled
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct MmpConfig {
+    // filled base amount, zero means no limit
+    pub qty_limit: Decimal,
+    // number of trades, zero means no limit
+    pub trade_count_limit: u32,
+    // seconds
+    pub window: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MmpState {
+    pub config: MmpConfig,
+    // (timestamp, filled base amount) of the fills inside the window
+    fills: VecDeque<(f64, Decimal)>,
+    // new orders are rejected until the user resets it
+    pub triggered: bool,
+}
+
+impl MmpState {
+    pub fn new(config: MmpConfig) -> Self {
+        Self {
+            config,
+            fills: VecDeque::new(),
+            triggered: false,
+        }
+    }
+
+    // record a maker fill, returns true if the protection trips with it
+    pub fn on_fill(&mut self, timestamp: f64, amount: Decimal) -> bool {
+        if self.triggered {
+            return false;
+        }
+        self.fills.push_back((timestamp, amount));
+        while let Some((t, _)) = self.fills.front() {
+            if timestamp - t > self.config.window {
+                self.fills.pop_front();
+            } else {
+                break;
+            }
+        }
+        let filled_qty: Decimal = self.fills.iter().map(|(_, amount)| *amount).sum();
+        let qty_reached = !self.config.qty_limit.is_zero() && filled_qty >= self.config.qty_limit;
+        let count_reached = self.config.trade_count_limit > 0 && self.fills.len() >= self.config.trade_count_limit as usize;
+        if qty_reached || count_reached {
+            self.triggered = true;
+            self.fills.clear();
+        }
+        self.triggered
+    }
+
+    pub fn reset(&mut self) {
+        self.triggered = false;
+        self.fills.clear();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_mmp_window() {
+    use fluidex_common::rust_decimal_macros::*;
+
+    let mut state = MmpState::new(MmpConfig {
+        qty_limit: dec!(10),
+        trade_count_limit: 3,
+        window: 1.0,
+    });
+    assert!(!state.on_fill(100.0, dec!(4)));
+    assert!(!state.on_fill(100.5, dec!(4)));
+    // the first fill is out of the window now
+    assert!(!state.on_fill(101.2, dec!(4)));
+    assert!(state.on_fill(101.3, dec!(1)));
+    assert!(state.triggered);
+    state.reset();
+    assert!(!state.triggered);
+    assert!(!state.on_fill(102.0, dec!(9)));
+}
@@ -1,29 +1,33 @@
 #![allow(clippy::if_same_then_else)]
 use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController, BalanceUpdateParams, BusinessType};
+use crate::clock;
 use crate::config;
+use crate::message::MmpMessage;
 use crate::persist::PersistExector;
 use crate::sequencer::Sequencer;
 use crate::types::{self, MarketRole, OrderEventType};
 
-use std::cmp::min;
-use std::collections::BTreeMap;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet};
 use std::iter::Iterator;
 
 use anyhow::{bail, Result};
 use fluidex_common::rust_decimal::prelude::Zero;
 use fluidex_common::rust_decimal::{Decimal, RoundingStrategy};
-use fluidex_common::utils::timeutil::current_timestamp;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-pub use types::{OrderSide, OrderType};
+pub use types::{OrderSide, OrderType, PegReference};
 
 mod order;
 pub use order::*;
 mod trade;
 pub use trade::*;
+mod mmp;
+pub use mmp::*;
 
+#[derive(Clone)]
 pub struct Market {
     pub name: &'static str,
     pub base: &'static str,
@@ -36,11 +40,19 @@ pub struct Market {
     pub min_amount: Decimal,
     pub price: Decimal,
 
-    pub orders: BTreeMap<u64, OrderRc>,
-    pub users: BTreeMap<Uuid, BTreeMap<u64, OrderRc>>,
+    // a persistent map, so a slice snapshot can hold a copy of it for cheap, the other maps
+    // only index the ids of these orders
+    pub orders: im::OrdMap<u64, Order>,
+    pub users: BTreeMap<Uuid, BTreeSet<u64>>,
 
-    pub asks: BTreeMap<MarketKeyAsk, OrderRc>,
-    pub bids: BTreeMap<MarketKeyBid, OrderRc>,
+    pub asks: BTreeMap<MarketKeyAsk, u64>,
+    pub bids: BTreeMap<MarketKeyBid, u64>,
+    // trailing stop orders waiting for their trigger, not part of the orderbook
+    pub stops: BTreeSet<u64>,
+    // ids of pegged orders in the orderbook
+    pub pegged_orders: BTreeSet<u64>,
+    // market maker protection of the users who have configured it
+    pub mmp: BTreeMap<Uuid, MmpState>,
 
     pub trade_count: u64,
 
@@ -119,10 +131,13 @@ impl Market {
             fee_prec: market_conf.fee_prec,
             min_amount: market_conf.min_amount,
             price: Decimal::zero(),
-            orders: BTreeMap::new(),
+            orders: im::OrdMap::new(),
             users: BTreeMap::new(),
             asks: BTreeMap::new(),
             bids: BTreeMap::new(),
+            stops: BTreeSet::new(),
+            pegged_orders: BTreeSet::new(),
+            mmp: BTreeMap::new(),
             trade_count: 0,
             disable_self_trade: global_settings.disable_self_trade,
             disable_market_order: global_settings.disable_market_order,
@@ -134,6 +149,9 @@ impl Market {
         log::debug!("market {} reset", self.name);
         self.bids.clear();
         self.asks.clear();
+        self.stops.clear();
+        self.pegged_orders.clear();
+        self.mmp.clear();
         self.users.clear();
         self.orders.clear();
     }
@@ -157,29 +175,34 @@ impl Market {
         mut balance_manager: BalanceManagerWrapper<'_>,
         balance_update_controller: &mut BalanceUpdateController,
         persistor: &mut impl PersistExector,
-        order_input: OrderInput,
+        mut order_input: OrderInput,
         user_id: Uuid,
     ) -> Result<Order> {
-        if order_input.type_ == OrderType::MARKET && self.disable_market_order {
-            bail!("market orders disabled");
+        if self.is_mmp_triggered(&user_id) {
+            bail!("market maker protection triggered, reset it before placing new orders");
         }
-        if order_input.amount.lt(&self.min_amount) {
-            bail!("invalid amount");
-        }
-        // fee_prec == 0 means no fee allowed
-        if self.fee_prec == 0 && (!order_input.taker_fee.is_zero() || !order_input.maker_fee.is_zero()) {
-            bail!("only 0 fee is supported now");
-        }
-        let amount = order_input
-            .amount
-            .round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero);
-        if amount != order_input.amount {
-            bail!("invalid amount precision");
+        if let Some(peg) = order_input.peg {
+            if order_input.type_ != OrderType::LIMIT {
+                bail!("pegged order must be a limit order");
+            }
+            if order_input.trailing_stop.is_some() {
+                bail!("pegged order cannot be a trailing stop");
+            }
+            if peg.offset.round_dp(self.price_prec) != peg.offset || peg.limit_price.round_dp(self.price_prec) != peg.limit_price {
+                bail!("invalid peg precision");
+            }
+            if peg.limit_price.is_sign_negative() {
+                bail!("invalid peg limit price");
+            }
+            order_input.price = match self.pegged_price(order_input.side, &peg) {
+                Some(price) => price,
+                None => bail!("no reference price for pegged order"),
+            };
         }
-        let price = order_input.price.round_dp(self.price_prec);
-        if price != order_input.price {
-            bail!("invalid price precision");
+        if order_input.type_ == OrderType::MARKET && self.disable_market_order {
+            bail!("market orders disabled");
         }
+        self.check_order_precision(&order_input)?;
         if order_input.type_ == OrderType::MARKET {
             if !order_input.price.is_zero() {
                 bail!("market order should not have a price");
@@ -187,12 +210,38 @@ impl Market {
             if order_input.post_only {
                 bail!("market order cannot be post only");
             }
-            if order_input.side == OrderSide::ASK && self.bids.is_empty() || order_input.side == OrderSide::BID && self.asks.is_empty() {
+            // a trailing stop only needs counter orders once it is triggered
+            if order_input.trailing_stop.is_none()
+                && (order_input.side == OrderSide::ASK && self.bids.is_empty()
+                    || order_input.side == OrderSide::BID && self.asks.is_empty())
+            {
                 bail!("no counter orders");
             }
         } else if order_input.price.is_zero() {
             bail!("invalid price for limit order");
         }
+        let trailing_stop = match order_input.trailing_stop {
+            Some(mut stop) => {
+                if order_input.post_only {
+                    bail!("trailing stop order cannot be post only");
+                }
+                if !stop.trail_value.is_sign_positive() || stop.trail_value.is_zero() {
+                    bail!("invalid trail value");
+                }
+                if stop.trail_type == types::TrailingType::PERCENTAGE && stop.trail_value >= Decimal::new(100, 0) {
+                    bail!("invalid trail percentage");
+                }
+                if self.price.is_zero() {
+                    bail!("no last price to trail");
+                }
+                stop.trigger_price = stop.trigger_for(order_input.side, self.price).round_dp(self.price_prec);
+                if !stop.trigger_price.is_sign_positive() || stop.trigger_price.is_zero() {
+                    bail!("invalid trail value");
+                }
+                Some(stop)
+            }
+            None => None,
+        };
 
         if order_input.side == OrderSide::ASK {
             if balance_manager
@@ -246,7 +295,7 @@ impl Market {
             Decimal::zero()
         };
 
-        let t = current_timestamp();
+        let t = clock::now();
         let order = Order {
             id: sequencer.next_order_id(),
             type_: order_input.type_,
@@ -267,7 +316,17 @@ impl Market {
             finished_quote: Decimal::zero(),
             finished_fee: Decimal::zero(),
             post_only: order_input.post_only,
+            trailing_stop,
+            peg: order_input.peg,
         };
+        // the the older version, PUT means being inserted into orderbook
+        // so if an order is matched instantly, only 'FINISH' event will occur, no 'PUT' event
+        // now PUT means being created
+        // we can revisit this decision later
+        persistor.put_order(&order, OrderEventType::PUT);
+        if order.is_pending_stop() {
+            return Ok(self.insert_stop_order(order));
+        }
         let order = self.execute_order(
             sequencer,
             &mut balance_manager,
@@ -276,9 +335,317 @@ impl Market {
             order,
             &quote_limit,
         );
+        self.trigger_stop_orders(sequencer, &mut balance_manager, balance_update_controller, persistor);
+        self.reprice_pegged_orders(&mut balance_manager, persistor);
         Ok(order)
     }
 
+    fn check_order_precision(&self, order_input: &OrderInput) -> Result<()> {
+        if order_input.amount.lt(&self.min_amount) {
+            bail!("invalid amount");
+        }
+        // fee_prec == 0 means no fee allowed
+        if self.fee_prec == 0 && (!order_input.taker_fee.is_zero() || !order_input.maker_fee.is_zero()) {
+            bail!("only 0 fee is supported now");
+        }
+        let amount = order_input
+            .amount
+            .round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero);
+        if amount != order_input.amount {
+            bail!("invalid amount precision");
+        }
+        let price = order_input.price.round_dp(self.price_prec);
+        if price != order_input.price {
+            bail!("invalid price precision");
+        }
+        Ok(())
+    }
+
+    // Validate a whole batch up front so that it can be placed all-or-nothing.
+    // Only plain limit orders are accepted, their fills can never need more balance
+    // than the amount they freeze, so once the batch passes here every put_order succeeds.
+    // With `release_user_orders` the current orders of the user are expected to be
+    // cancelled before placing the batch, and their frozen balance is counted as available.
+    pub fn check_batch_orders(
+        &self,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        order_inputs: &[OrderInput],
+        user_id: Uuid,
+        release_user_orders: bool,
+    ) -> Result<()> {
+        if self.is_mmp_triggered(&user_id) {
+            bail!("market maker protection triggered, reset it before placing new orders");
+        }
+        let mut base_needed = Decimal::zero();
+        let mut quote_needed = Decimal::zero();
+        for (idx, order_input) in order_inputs.iter().enumerate() {
+            if order_input.type_ != OrderType::LIMIT || order_input.peg.is_some() || order_input.trailing_stop.is_some() {
+                bail!("order {}: only plain limit orders are supported in an atomic batch", idx);
+            }
+            self.check_order_precision(order_input)
+                .map_err(|e| anyhow::anyhow!("order {}: {}", idx, e))?;
+            if order_input.price.is_zero() {
+                bail!("order {}: invalid price for limit order", idx);
+            }
+            // the batch must not trade against itself
+            let crosses_batch = order_inputs.iter().any(|other| {
+                other.side != order_input.side
+                    && if order_input.side == OrderSide::ASK {
+                        order_input.price <= other.price
+                    } else {
+                        order_input.price >= other.price
+                    }
+            });
+            if crosses_batch {
+                bail!("order {}: crosses another order of the batch", idx);
+            }
+            if self.disable_self_trade && !release_user_orders {
+                let crosses_own_order = self.user_orders(&user_id).any(|order| {
+                    !order.is_pending_stop()
+                        && order.side != order_input.side
+                        && if order_input.side == OrderSide::ASK {
+                            order_input.price <= order.price
+                        } else {
+                            order_input.price >= order.price
+                        }
+                });
+                if crosses_own_order {
+                    bail!("order {}: would trade against an own order", idx);
+                }
+            }
+            if order_input.post_only {
+                let crosses_book = if order_input.side == OrderSide::ASK {
+                    self.best_price(&self.bids, false).map_or(false, |bid| order_input.price <= bid)
+                } else {
+                    self.best_price(&self.asks, false).map_or(false, |ask| order_input.price >= ask)
+                };
+                if crosses_book {
+                    bail!("order {}: post only order would trade immediately", idx);
+                }
+            }
+            if order_input.side == OrderSide::ASK {
+                base_needed += order_input.amount;
+            } else {
+                quote_needed += order_input.amount * order_input.price;
+            }
+        }
+
+        let (mut base_released, mut quote_released) = (Decimal::zero(), Decimal::zero());
+        if release_user_orders {
+            for order in self.user_orders(&user_id) {
+                if order.is_ask() {
+                    base_released += order.frozen;
+                } else {
+                    quote_released += order.frozen;
+                }
+            }
+        }
+        let base_available = balance_manager.balance_get(user_id.to_string(), BalanceType::AVAILABLE, self.base) + base_released;
+        if base_available < base_needed {
+            bail!(
+                "balance not enough: {} available({}) < needed({})",
+                self.base,
+                base_available,
+                base_needed
+            );
+        }
+        let quote_available = balance_manager.balance_get(user_id.to_string(), BalanceType::AVAILABLE, self.quote) + quote_released;
+        if quote_available < quote_needed {
+            bail!(
+                "balance not enough: {} available({}) < needed({})",
+                self.quote,
+                quote_available,
+                quote_needed
+            );
+        }
+        Ok(())
+    }
+
+    fn best_price<K>(&self, orderbook: &BTreeMap<K, u64>, skip_pegged: bool) -> Option<Decimal> {
+        orderbook
+            .values()
+            .map(|order_id| &self.orders[order_id])
+            .find(|order| !(skip_pegged && order.is_pegged()))
+            .map(|order| order.price)
+    }
+
+    // pegged orders never act as references, so they cannot chase each other
+    fn peg_reference_price(&self, side: OrderSide, reference: PegReference) -> Option<Decimal> {
+        let best_ask = self.best_price(&self.asks, true);
+        let best_bid = self.best_price(&self.bids, true);
+        let (same_side, opposite) = if side == OrderSide::ASK {
+            (best_ask, best_bid)
+        } else {
+            (best_bid, best_ask)
+        };
+        match reference {
+            PegReference::SAMESIDE => same_side,
+            PegReference::OPPOSITE => opposite,
+            PegReference::MID => match (best_ask, best_bid) {
+                (Some(ask), Some(bid)) => Some((ask + bid) / Decimal::new(2, 0)),
+                _ => None,
+            },
+        }
+    }
+
+    // price = reference + offset, bounded by the limit cap and kept one tick
+    // away from the counter side, so a pegged order never takes liquidity
+    pub fn pegged_price(&self, side: OrderSide, peg: &Peg) -> Option<Decimal> {
+        let reference = self.peg_reference_price(side, peg.reference)?;
+        let tick = Decimal::new(1, self.price_prec);
+        let mut price = (reference + peg.offset).round_dp(self.price_prec);
+        if side == OrderSide::BID {
+            if !peg.limit_price.is_zero() {
+                price = min(price, peg.limit_price);
+            }
+            if let Some(best_ask) = self.best_price(&self.asks, false) {
+                price = min(price, best_ask - tick);
+            }
+        } else {
+            price = max(price, peg.limit_price);
+            if let Some(best_bid) = self.best_price(&self.bids, false) {
+                price = max(price, best_bid + tick);
+            }
+        }
+        if price.is_sign_positive() && !price.is_zero() {
+            Some(price)
+        } else {
+            None
+        }
+    }
+
+    fn reprice_pegged_orders(&mut self, balance_manager: &mut BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector) {
+        let order_ids: Vec<u64> = self.pegged_orders.iter().copied().collect();
+        for order_id in order_ids {
+            let order = self.orders[&order_id];
+            let price = match self.pegged_price(order.side, &order.peg.unwrap()) {
+                Some(price) => price,
+                None => continue,
+            };
+            if price == order.price {
+                continue;
+            }
+            // a bid needs its frozen quote to follow the price
+            let frozen = if order.is_ask() { order.remain } else { order.remain * price };
+            if frozen > order.frozen
+                && balance_manager
+                    .balance_get(order.user.to_string(), BalanceType::AVAILABLE, self.quote)
+                    .lt(&(frozen - order.frozen))
+            {
+                log::debug!("balance not enough to reprice pegged order {}", order_id);
+                continue;
+            }
+            if order.is_ask() {
+                self.asks.remove(&order.get_ask_key()).unwrap();
+            } else {
+                self.bids.remove(&order.get_bid_key()).unwrap();
+            }
+            if frozen > order.frozen {
+                balance_manager.balance_frozen(order.user.to_string(), self.quote, &(frozen - order.frozen));
+            } else if frozen < order.frozen {
+                balance_manager.balance_unfrozen(order.user.to_string(), self.quote, &(order.frozen - frozen));
+            }
+            let repriced = Order {
+                price,
+                frozen,
+                update_time: clock::now(),
+                ..order
+            };
+            self.orders.insert(order_id, repriced);
+            if repriced.is_ask() {
+                self.asks.insert(repriced.get_ask_key(), order_id);
+            } else {
+                self.bids.insert(repriced.get_bid_key(), order_id);
+            }
+            persistor.put_order(&repriced, OrderEventType::UPDATE);
+        }
+    }
+
+    // Re-evaluate trailing stops against the last trade price. Triggered orders are executed
+    // as ordinary market or limit orders, which may move the price and trigger further stops.
+    fn trigger_stop_orders(
+        &mut self,
+        sequencer: &mut Sequencer,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+    ) {
+        loop {
+            if self.price.is_zero() || self.stops.is_empty() {
+                return;
+            }
+            let price = self.price;
+            let mut triggered = Vec::new();
+            for order_id in &self.stops {
+                let order = self.orders.get_mut(order_id).unwrap();
+                let side = order.side;
+                let stop = order.trailing_stop.as_mut().unwrap();
+                if stop.follow(side, price, self.price_prec) {
+                    triggered.push(order.id);
+                }
+            }
+            if triggered.is_empty() {
+                return;
+            }
+            for order_id in triggered {
+                self.activate_stop_order(sequencer, balance_manager, balance_update_controller, persistor, order_id);
+            }
+        }
+    }
+
+    fn activate_stop_order(
+        &mut self,
+        sequencer: &mut Sequencer,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+        order_id: u64,
+    ) {
+        self.stops.remove(&order_id);
+        let mut order = self.orders.remove(&order_id).unwrap();
+        self.users.get_mut(&order.user).unwrap().remove(&order_id);
+        log::debug!("trailing stop triggered {:?}", order);
+        order.trailing_stop = None;
+        order.update_time = clock::now();
+
+        // balances may have changed since the stop was placed, so check them again
+        let is_market_order = order.type_ == OrderType::MARKET;
+        let available = if order.is_ask() {
+            balance_manager.balance_get(order.user.to_string(), BalanceType::AVAILABLE, self.base)
+        } else {
+            balance_manager.balance_get(order.user.to_string(), BalanceType::AVAILABLE, self.quote)
+        };
+        let no_counter_orders = if order.is_ask() {
+            self.bids.is_empty()
+        } else {
+            self.asks.is_empty()
+        };
+        let balance_enough = if order.is_ask() {
+            available >= order.remain
+        } else {
+            is_market_order || available >= order.remain * order.price
+        };
+        if (is_market_order && no_counter_orders) || !balance_enough {
+            log::debug!("trailing stop {} cannot be executed, finish it", order_id);
+            persistor.put_order(&order, OrderEventType::FINISH);
+            return;
+        }
+        persistor.put_order(&order, OrderEventType::UPDATE);
+        let quote_limit = if is_market_order && !order.is_ask() {
+            available
+        } else {
+            Decimal::zero()
+        };
+        self.execute_order(
+            sequencer,
+            balance_manager,
+            balance_update_controller,
+            persistor,
+            order,
+            &quote_limit,
+        );
+    }
+
     // the last parameter `quote_limit`, is only used for market bid order,
     // it indicates the `quote` balance of the user,
     // so the sum of all the trades' quote amount cannot exceed this value
@@ -293,12 +660,6 @@ impl Market {
     ) -> Order {
         log::debug!("execute_order {:?}", taker);
 
-        // the the older version, PUT means being inserted into orderbook
-        // so if an order is matched instantly, only 'FINISH' event will occur, no 'PUT' event
-        // now PUT means being created
-        // we can revisit this decision later
-        persistor.put_order(&taker, OrderEventType::PUT);
-
         let taker_is_ask = taker.side == OrderSide::ASK;
         let taker_is_bid = !taker_is_ask;
         let maker_is_bid = taker_is_ask;
@@ -310,21 +671,26 @@ impl Market {
         let mut quote_sum = Decimal::zero();
 
         let mut finished_orders = Vec::new();
+        let mut mmp_triggered_users = Vec::new();
 
-        let counter_orders: Box<dyn Iterator<Item = &mut OrderRc>> = if maker_is_bid {
-            Box::new(self.bids.values_mut())
+        let counter_orders: Box<dyn Iterator<Item = &u64>> = if maker_is_bid {
+            Box::new(self.bids.values())
         } else {
-            Box::new(self.asks.values_mut())
+            Box::new(self.asks.values())
         };
 
         // TODO: find a more elegant way to handle this
         let mut need_cancel = false;
-        for maker_ref in counter_orders {
+        for maker_id in counter_orders {
             // Step1: get ask and bid
-            let mut maker = maker_ref.borrow_mut();
+            let maker = self.orders.get_mut(maker_id).unwrap();
             if taker.remain.is_zero() {
                 break;
             }
+            // the protection of this maker tripped earlier in this sweep, its orders are pulled below
+            if self.mmp.get(&maker.user).map_or(false, |mmp| mmp.triggered) {
+                continue;
+            }
             let (ask_fee_rate, bid_fee_rate) = if taker_is_ask {
                 (taker.taker_fee, maker.maker_fee)
             } else {
@@ -379,7 +745,7 @@ impl Market {
             let bid_fee = (traded_base_amount * bid_fee_rate).round_dp_with_strategy(self.base_prec, RoundingStrategy::ToZero);
             let ask_fee = (traded_quote_amount * ask_fee_rate).round_dp_with_strategy(self.quote_prec, RoundingStrategy::ToZero);
 
-            let timestamp = current_timestamp();
+            let timestamp = clock::now();
             ask_order.update_time = timestamp;
             bid_order.update_time = timestamp;
 
@@ -387,7 +753,7 @@ impl Market {
             let trade_id = sequencer.next_trade_id();
             let trade = Trade {
                 id: trade_id,
-                timestamp: current_timestamp(),
+                timestamp: clock::now(),
                 market: self.name.to_string(),
                 base: self.base.into(),
                 quote: self.quote.into(),
@@ -535,6 +901,11 @@ impl Market {
             };
             persistor.put_trade(&trade);
             //}
+            if let Some(mmp) = self.mmp.get_mut(&maker.user) {
+                if mmp.on_fill(timestamp, traded_base_amount) {
+                    mmp_triggered_users.push(maker.user);
+                }
+            }
             maker.frozen -= if maker_is_bid { traded_quote_amount } else { traded_base_amount };
 
             let maker_finished = maker.remain.is_zero();
@@ -553,6 +924,9 @@ impl Market {
         for item in finished_orders.iter() {
             self.order_finish(&mut *balance_manager, persistor, item);
         }
+        for user_id in mmp_triggered_users {
+            self.trip_mmp(balance_manager, persistor, user_id);
+        }
 
         if need_cancel {
             // Now both self trade orders and immediately triggered post_only
@@ -588,26 +962,42 @@ impl Market {
         debug_assert_eq!(order.type_, OrderType::LIMIT);
         debug_assert!(!self.orders.contains_key(&order.id));
         // log::debug!("order insert {}", &order.id);
-        let order_rc = OrderRc::new(order);
-        let order = order_rc.borrow();
-        self.orders.insert(order.id, order_rc.clone());
-        let user_map = self.users.entry(order.user).or_insert_with(BTreeMap::new);
-        debug_assert!(!user_map.contains_key(&order.id));
-        user_map.insert(order.id, order_rc.clone());
+        self.orders.insert(order.id, order);
+        let user_set = self.users.entry(order.user).or_insert_with(BTreeSet::new);
+        debug_assert!(!user_set.contains(&order.id));
+        user_set.insert(order.id);
         if order.side == OrderSide::ASK {
             let key = order.get_ask_key();
             debug_assert!(!self.asks.contains_key(&key));
-            self.asks.insert(key, order_rc.clone());
+            self.asks.insert(key, order.id);
         } else {
             let key = order.get_bid_key();
             debug_assert!(!self.bids.contains_key(&key));
-            self.bids.insert(key, order_rc.clone());
+            self.bids.insert(key, order.id);
+        }
+        if order.is_pegged() {
+            self.pegged_orders.insert(order.id);
         }
-        order_rc.deep()
+        order
+    }
+
+    pub fn insert_stop_order(&mut self, order: Order) -> Order {
+        debug_assert!(order.is_pending_stop());
+        debug_assert!(!self.orders.contains_key(&order.id));
+        self.orders.insert(order.id, order);
+        let user_set = self.users.entry(order.user).or_insert_with(BTreeSet::new);
+        debug_assert!(!user_set.contains(&order.id));
+        user_set.insert(order.id);
+        self.stops.insert(order.id);
+        order
     }
 
     fn order_finish(&mut self, balance_manager: &mut BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector, order: &Order) {
-        if order.side == OrderSide::ASK {
+        if order.is_pending_stop() {
+            // nothing is frozen for a stop order before it is triggered
+            debug_assert!(self.stops.contains(&order.id));
+            self.stops.remove(&order.id);
+        } else if order.side == OrderSide::ASK {
             let key = &order.get_ask_key();
             debug_assert!(self.asks.contains_key(key));
             self.asks.remove(key);
@@ -616,13 +1006,16 @@ impl Market {
             debug_assert!(self.bids.contains_key(key));
             self.bids.remove(key);
         }
-        self.unfrozen_balance(balance_manager, order);
+        if !order.is_pending_stop() {
+            self.unfrozen_balance(balance_manager, order);
+        }
+        self.pegged_orders.remove(&order.id);
         debug_assert!(self.orders.contains_key(&order.id));
         // log::debug!("order finish {}", &order.id);
         self.orders.remove(&order.id);
-        let user_map = self.users.get_mut(&order.user).unwrap();
-        debug_assert!(user_map.contains_key(&order.id));
-        user_map.remove(&order.id);
+        let user_set = self.users.get_mut(&order.user).unwrap();
+        debug_assert!(user_set.contains(&order.id));
+        user_set.remove(&order.id);
 
         persistor.put_order(order, OrderEventType::FINISH);
     }
@@ -682,9 +1075,9 @@ impl Market {
         }
     }
     pub fn cancel(&mut self, mut balance_manager: BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector, order_id: u64) -> Order {
-        let order = self.orders.get(&order_id).unwrap();
-        let order_struct = order.deep();
+        let order_struct = self.orders[&order_id];
         self.order_finish(&mut balance_manager, persistor, &order_struct);
+        self.reprice_pegged_orders(&mut balance_manager, persistor);
         order_struct
     }
     pub fn cancel_all_for_user(
@@ -693,49 +1086,128 @@ impl Market {
         persistor: &mut impl PersistExector,
         user_id: String,
     ) -> usize {
-        // TODO: can we mutate while iterate?
-        let order_ids: Vec<u64> = self
-            .users
-            .get(&user_id.parse().unwrap())
-            .unwrap_or(&BTreeMap::new())
-            .keys()
-            .copied()
-            .collect();
-        let total = order_ids.len();
+        let total = self.finish_user_orders(&mut balance_manager, persistor, &user_id.parse().unwrap());
+        self.reprice_pegged_orders(&mut balance_manager, persistor);
+        total
+    }
+    // cancel several orders at once, pegged orders are repriced after all of them are gone
+    pub fn cancel_orders(
+        &mut self,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        order_ids: &[u64],
+    ) -> Vec<Order> {
+        let mut cancelled = Vec::with_capacity(order_ids.len());
         for order_id in order_ids {
-            let order = self.orders.get(&order_id).unwrap();
-            let order_struct = order.deep();
+            let order_struct = self.orders[order_id];
             self.order_finish(&mut balance_manager, persistor, &order_struct);
+            cancelled.push(order_struct);
+        }
+        self.reprice_pegged_orders(&mut balance_manager, persistor);
+        cancelled
+    }
+    // ids of the user's orders on `side` with a price inside [price_min, price_max],
+    // `None` means no restriction. Pending stops are matched by their limit price.
+    pub fn select_user_orders(
+        &self,
+        user_id: &Uuid,
+        side: Option<OrderSide>,
+        price_min: Option<Decimal>,
+        price_max: Option<Decimal>,
+    ) -> Vec<u64> {
+        self.user_orders(user_id)
+            .filter(|order| {
+                side.map_or(true, |side| order.side == side)
+                    && price_min.map_or(true, |price_min| order.price >= price_min)
+                    && price_max.map_or(true, |price_max| order.price <= price_max)
+            })
+            .map(|order| order.id)
+            .collect()
+    }
+    fn finish_user_orders(
+        &mut self,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        user_id: &Uuid,
+    ) -> usize {
+        // TODO: can we mutate while iterate?
+        let orders: Vec<Order> = self.user_orders(user_id).collect();
+        let total = orders.len();
+        for order in orders {
+            self.order_finish(balance_manager, persistor, &order);
         }
         total
     }
+    // `None` removes the protection, the triggered state is kept when the config is updated
+    pub fn set_mmp(&mut self, user_id: Uuid, config: Option<MmpConfig>) {
+        match config {
+            Some(config) => self.mmp.entry(user_id).or_insert_with(|| MmpState::new(config)).config = config,
+            None => {
+                self.mmp.remove(&user_id);
+            }
+        }
+    }
+    pub fn reset_mmp(&mut self, user_id: &Uuid) -> bool {
+        match self.mmp.get_mut(user_id) {
+            Some(mmp) => {
+                mmp.reset();
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn is_mmp_triggered(&self, user_id: &Uuid) -> bool {
+        self.mmp.get(user_id).map(|mmp| mmp.triggered).unwrap_or(false)
+    }
+    fn trip_mmp(&mut self, balance_manager: &mut BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector, user_id: Uuid) {
+        let cancelled_orders = self.finish_user_orders(balance_manager, persistor, &user_id);
+        let config = self.mmp.get(&user_id).unwrap().config;
+        log::info!(
+            "market maker protection of user {} triggered in market {}, {} orders cancelled",
+            user_id,
+            self.name,
+            cancelled_orders
+        );
+        persistor.put_mmp(&MmpMessage {
+            timestamp: clock::now(),
+            user_id,
+            market: self.name.to_string(),
+            qty_limit: config.qty_limit.to_string(),
+            trade_count_limit: config.trade_count_limit,
+            window: config.window,
+            cancelled_orders,
+        });
+    }
     pub fn get(&self, order_id: u64) -> Option<Order> {
-        self.orders.get(&order_id).map(OrderRc::deep)
+        self.orders.get(&order_id).copied()
     }
     pub fn get_order_num_of_user(&self, user_id: &Uuid) -> usize {
         self.users.get(user_id).map(|m| m.len()).unwrap_or(0)
     }
     pub fn get_order_of_user(&self, user_id: &Uuid) -> Vec<Order> {
+        self.user_orders(user_id).collect()
+    }
+    // the orders of the user by id
+    pub fn user_orders<'a>(&'a self, user_id: &Uuid) -> impl Iterator<Item = Order> + 'a {
         self.users
             .get(user_id)
-            .unwrap_or(&BTreeMap::new())
-            .values()
-            .map(OrderRc::deep)
-            .collect()
+            .into_iter()
+            .flatten()
+            .map(move |order_id| self.orders[order_id])
     }
     pub fn print(&self) {
         log::info!("orders:");
         for (k, v) in self.orders.iter() {
-            log::info!("{}, {:?}", k, v.borrow())
+            log::info!("{}, {:?}", k, v)
         }
     }
     pub fn status(&self) -> MarketStatus {
         MarketStatus {
             name: self.name.to_string(),
             ask_count: self.asks.len(),
-            ask_amount: self.asks.values().map(|item| item.borrow().remain).sum(),
+            ask_amount: self.asks.values().map(|order_id| self.orders[order_id].remain).sum(),
             bid_count: self.bids.len(),
-            bid_amount: self.bids.values().map(|item| item.borrow().remain).sum(),
+            bid_amount: self.bids.values().map(|order_id| self.orders[order_id].remain).sum(),
             trade_count: self.trade_count,
         }
     }
@@ -743,31 +1215,32 @@ impl Market {
         if interval.is_zero() {
             let id_fn = |order: &Order| -> Decimal { order.price };
             MarketDepth {
-                asks: Self::group_ordebook_by_fn(&self.asks, limit, id_fn),
-                bids: Self::group_ordebook_by_fn(&self.bids, limit, id_fn),
+                asks: self.group_ordebook_by_fn(&self.asks, limit, id_fn),
+                bids: self.group_ordebook_by_fn(&self.bids, limit, id_fn),
             }
         } else {
             let ask_group_fn = |order: &Order| -> Decimal { (order.price / interval).ceil() * interval };
             let bid_group_fn = |order: &Order| -> Decimal { (order.price / interval).floor() * interval };
             MarketDepth {
-                asks: Self::group_ordebook_by_fn(&self.asks, limit, ask_group_fn),
-                bids: Self::group_ordebook_by_fn(&self.bids, limit, bid_group_fn),
+                asks: self.group_ordebook_by_fn(&self.asks, limit, ask_group_fn),
+                bids: self.group_ordebook_by_fn(&self.bids, limit, bid_group_fn),
             }
         }
     }
 
-    fn group_ordebook_by_fn<K, F>(orderbook: &BTreeMap<K, OrderRc>, limit: usize, f: F) -> Vec<PriceInfo>
+    fn group_ordebook_by_fn<K, F>(&self, orderbook: &BTreeMap<K, u64>, limit: usize, f: F) -> Vec<PriceInfo>
     where
         F: Fn(&Order) -> Decimal,
     {
         orderbook
             .values()
-            .group_by(|order_rc| -> Decimal { f(&order_rc.borrow()) })
+            .map(|order_id| &self.orders[order_id])
+            .group_by(|order| -> Decimal { f(order) })
             .into_iter()
             .take(limit)
             .map(|(price, group)| PriceInfo {
                 price,
-                amount: group.map(|order_rc| order_rc.borrow().remain).sum(),
+                amount: group.map(|order| order.remain).sum(),
             })
             .collect::<Vec<PriceInfo>>()
     }
@@ -835,7 +1308,11 @@ mod tests {
             Ok(b) => Box::new(crate::persist::MessengerBasedPersistor::new(Box::new(
                 crate::message::FullOrderMessageManager::new_and_run(&b).unwrap(),
             ))),
-            Err(_) => Box::new(crate::persist::FileBasedPersistor::new("market_test_output.txt")),
+            Err(_) => {
+                let mut config = crate::persist::MessageFileConfig::from_settings(&crate::config::Settings::default());
+                config.dir = "market_test_output".to_string();
+                Box::new(crate::persist::FileBasedPersistor::new(config))
+            }
         };
         //let persistor = &mut persistor;
         let mut update_controller = BalanceUpdateController::new();
@@ -899,6 +1376,8 @@ mod tests {
                 maker_fee: dec!(0),
                 market: market.name.to_string(),
                 post_only: false,
+                trailing_stop: None,
+                peg: None,
             };
             market
                 .put_order(
@@ -957,6 +1436,8 @@ mod tests {
             maker_fee: dec!(0.001),
             market: market.name.to_string(),
             post_only: false,
+            trailing_stop: None,
+            peg: None,
         };
         let ask_order = market
             .put_order(
@@ -982,6 +1463,8 @@ mod tests {
             maker_fee: dec!(0.001),
             market: market.name.to_string(),
             post_only: false,
+            trailing_stop: None,
+            peg: None,
         };
         let bid_order = market
             .put_order(
@@ -1090,6 +1573,8 @@ mod tests {
             maker_fee: dec!(0.001),
             market: market.name.to_string(),
             post_only: true,
+            trailing_stop: None,
+            peg: None,
         };
         let ask_order = market
             .put_order(
@@ -1116,6 +1601,8 @@ mod tests {
             maker_fee: dec!(0.001),
             market: market.name.to_string(),
             post_only: true,
+            trailing_stop: None,
+            peg: None,
         };
         let bid_order = market
             .put_order(
@@ -1188,4 +1675,483 @@ mod tests {
             dec!(0)
         );
     }
+
+    #[test]
+    fn test_trailing_stop_order() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let ask_user_id = Uuid::from_str("5f4cf5c8-3a4f-4d3e-9d6b-4d9c1d1f3a01").unwrap();
+        let bid_user_id = Uuid::from_str("2b0d4b7e-6a43-4c84-9b1a-8b2e93f1c402").unwrap();
+        let stop_user_id = Uuid::from_str("c0a5e1f4-09a8-45f1-8d37-2a6cfa5e2e03").unwrap();
+        balance_manager.add(ask_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(bid_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(10000));
+        balance_manager.add(stop_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let order_input = |side, type_, amount, price, trailing_stop| OrderInput {
+            side,
+            type_,
+            amount,
+            price,
+            quote_limit: dec!(0),
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            market: String::from("ETH_USDT"),
+            post_only: false,
+            trailing_stop,
+            peg: None,
+        };
+        let mut put = |market: &mut Market, balance_manager: &mut BalanceManager, input, user_id| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &mut persistor,
+                    input,
+                    user_id,
+                )
+                .unwrap()
+        };
+
+        // no last price yet, nothing to trail
+        let trailing = Some(TrailingStop::new(types::TrailingType::OFFSET, dec!(5)));
+        assert!(market
+            .put_order(
+                &mut Sequencer::default(),
+                balance_manager.into(),
+                &mut BalanceUpdateController::new(),
+                &mut crate::persist::DummyPersistor::default(),
+                order_input(OrderSide::ASK, OrderType::MARKET, dec!(1), dec!(0), trailing),
+                stop_user_id,
+            )
+            .is_err());
+
+        put(
+            &mut market,
+            balance_manager,
+            order_input(OrderSide::ASK, OrderType::LIMIT, dec!(1), dec!(100), None),
+            ask_user_id,
+        );
+        put(
+            &mut market,
+            balance_manager,
+            order_input(OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(100), None),
+            bid_user_id,
+        );
+        assert_eq!(market.price, dec!(100));
+
+        let stop_order = put(
+            &mut market,
+            balance_manager,
+            order_input(OrderSide::ASK, OrderType::MARKET, dec!(1), dec!(0), trailing),
+            stop_user_id,
+        );
+        assert_eq!(stop_order.trailing_stop.unwrap().trigger_price, dec!(95));
+        assert_eq!(market.stops.len(), 1);
+        assert!(market.asks.is_empty());
+        assert_eq!(
+            balance_manager.get(stop_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()),
+            dec!(0)
+        );
+
+        // the price goes up, the trigger follows it
+        put(
+            &mut market,
+            balance_manager,
+            order_input(OrderSide::ASK, OrderType::LIMIT, dec!(1), dec!(110), None),
+            ask_user_id,
+        );
+        put(
+            &mut market,
+            balance_manager,
+            order_input(OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(110), None),
+            bid_user_id,
+        );
+        let stop_order = market.get(stop_order.id).unwrap();
+        assert_eq!(stop_order.trailing_stop.unwrap().trigger_price, dec!(105));
+
+        // the price reverses below the trigger, the stop becomes a market order
+        put(
+            &mut market,
+            balance_manager,
+            order_input(OrderSide::BID, OrderType::LIMIT, dec!(2), dec!(104), None),
+            bid_user_id,
+        );
+        put(
+            &mut market,
+            balance_manager,
+            order_input(OrderSide::ASK, OrderType::LIMIT, dec!(1), dec!(104), None),
+            ask_user_id,
+        );
+        assert!(market.get(stop_order.id).is_none());
+        assert!(market.stops.is_empty());
+        assert!(market.bids.is_empty());
+        assert_eq!(
+            balance_manager.get(stop_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(9)
+        );
+        assert_eq!(
+            balance_manager.get(stop_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(104)
+        );
+    }
+
+    #[test]
+    fn test_pegged_order() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let maker_id = Uuid::from_str("0d5b5c1e-7f58-4a0e-a1a4-3c3f4b2d6e11").unwrap();
+        let pegged_id = Uuid::from_str("a7e2f3c4-1b2d-4e5f-8a9b-0c1d2e3f4a12").unwrap();
+        balance_manager.add(maker_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(maker_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(10000));
+        balance_manager.add(pegged_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let order_input = |side, price, peg| OrderInput {
+            side,
+            type_: OrderType::LIMIT,
+            amount: dec!(1),
+            price,
+            quote_limit: dec!(0),
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            market: String::from("ETH_USDT"),
+            post_only: false,
+            trailing_stop: None,
+            peg,
+        };
+
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::ASK, dec!(110), None),
+                maker_id,
+            )
+            .unwrap();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::BID, dec!(100), None),
+                maker_id,
+            )
+            .unwrap();
+        let peg = Peg {
+            reference: PegReference::SAMESIDE,
+            offset: dec!(0.01),
+            limit_price: dec!(105),
+        };
+        let pegged_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::BID, dec!(0), Some(peg)),
+                pegged_id,
+            )
+            .unwrap();
+        assert_eq!(pegged_order.price, dec!(100.01));
+
+        // a better bid moves the reference, the pegged order follows
+        let better_bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::BID, dec!(102), None),
+                maker_id,
+            )
+            .unwrap();
+        assert_eq!(market.get(pegged_order.id).unwrap().price, dec!(102.01));
+        assert_eq!(*market.bids.values().next().unwrap(), pegged_order.id);
+        match persistor.messages.last().unwrap() {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.event, OrderEventType::UPDATE);
+                assert_eq!(msg.order.id, pegged_order.id);
+                assert_eq!(msg.order.price, dec!(102.01));
+            }
+            _ => panic!("expect OrderMessage only"),
+        }
+
+        // the limit cap bounds the price
+        let high_bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::BID, dec!(108), None),
+                maker_id,
+            )
+            .unwrap();
+        assert_eq!(market.get(pegged_order.id).unwrap().price, dec!(105));
+        assert_eq!(
+            balance_manager.get(pegged_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
+            dec!(105)
+        );
+
+        market.cancel(balance_manager.into(), &mut persistor, high_bid.id);
+        assert_eq!(market.get(pegged_order.id).unwrap().price, dec!(102.01));
+        assert_eq!(
+            balance_manager.get(pegged_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
+            dec!(102.01)
+        );
+        assert_eq!(
+            balance_manager.get(pegged_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(897.99)
+        );
+
+        market.cancel(balance_manager.into(), &mut persistor, better_bid.id);
+        assert_eq!(market.get(pegged_order.id).unwrap().price, dec!(100.01));
+    }
+
+    #[test]
+    fn test_market_maker_protection() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let maker_id = Uuid::from_str("6e1f0c2a-5b3d-4f7e-9a8b-1c2d3e4f5a13").unwrap();
+        let taker_id = Uuid::from_str("d3c2b1a0-9f8e-4d7c-8b6a-5f4e3d2c1b14").unwrap();
+        balance_manager.add(maker_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(taker_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(10000));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        market.set_mmp(
+            maker_id,
+            Some(MmpConfig {
+                qty_limit: dec!(0),
+                trade_count_limit: 2,
+                window: 10.0,
+            }),
+        );
+        let order_input = |side, amount, price| OrderInput {
+            side,
+            type_: OrderType::LIMIT,
+            amount,
+            price,
+            quote_limit: dec!(0),
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            market: String::from("ETH_USDT"),
+            post_only: false,
+            trailing_stop: None,
+            peg: None,
+        };
+        for price in [dec!(100), dec!(101), dec!(102)] {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &mut persistor,
+                    order_input(OrderSide::ASK, dec!(1), price),
+                    maker_id,
+                )
+                .unwrap();
+        }
+        let taker_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::BID, dec!(3), dec!(102)),
+                taker_id,
+            )
+            .unwrap();
+
+        // the second fill trips the protection and pulls the remaining ask, the taker does not fill it
+        assert!(market.is_mmp_triggered(&maker_id));
+        assert_eq!(taker_order.finished_base, dec!(2));
+        assert_eq!(market.get(taker_order.id).unwrap().remain, dec!(1));
+        assert!(market.asks.is_empty());
+        assert_eq!(market.get_order_num_of_user(&maker_id), 0);
+        assert_eq!(balance_manager.get(maker_id, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+        assert_eq!(
+            balance_manager.get(maker_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(98)
+        );
+        let mmp_message = persistor
+            .messages
+            .iter()
+            .find_map(|msg| match msg {
+                Message::MmpMessage(msg) => Some(msg),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(mmp_message.user_id, maker_id);
+        assert_eq!(mmp_message.cancelled_orders, 1);
+
+        assert!(market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::ASK, dec!(1), dec!(103)),
+                maker_id,
+            )
+            .is_err());
+        assert!(market.reset_mmp(&maker_id));
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::ASK, dec!(1), dec!(103)),
+                maker_id,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_check_batch_orders() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let maker_id = Uuid::from_str("3c1d0e2f-5a6b-4c7d-8e9f-a0b1c2d3e4f5").unwrap();
+        let user_id = Uuid::from_str("9f8e7d6c-5b4a-4392-8170-6f5e4d3c2b1a").unwrap();
+        balance_manager.add(maker_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(2));
+        balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let order_input = |side, price, post_only| OrderInput {
+            side,
+            type_: OrderType::LIMIT,
+            amount: dec!(1),
+            price,
+            quote_limit: dec!(0),
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            market: String::from("ETH_USDT"),
+            post_only,
+            trailing_stop: None,
+            peg: None,
+        };
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input(OrderSide::ASK, dec!(110), false),
+                maker_id,
+            )
+            .unwrap();
+
+        let ladder = vec![
+            order_input(OrderSide::BID, dec!(100), true),
+            order_input(OrderSide::BID, dec!(99), true),
+            order_input(OrderSide::BID, dec!(98), true),
+            order_input(OrderSide::ASK, dec!(120), true),
+        ];
+        assert!(market.check_batch_orders(balance_manager.into(), &ladder, user_id, false).is_ok());
+        // the combined quote needed is 397 > 300
+        let mut too_large = ladder.clone();
+        too_large.push(order_input(OrderSide::BID, dec!(100), true));
+        assert!(market
+            .check_batch_orders(balance_manager.into(), &too_large, user_id, false)
+            .is_err());
+        // a post only bid crossing the best ask
+        let mut crossing = ladder.clone();
+        crossing[0].price = dec!(110);
+        assert!(market
+            .check_batch_orders(balance_manager.into(), &crossing, user_id, false)
+            .is_err());
+        // orders of the batch crossing each other
+        let mut self_crossing = ladder;
+        self_crossing[3].price = dec!(99);
+        self_crossing[3].post_only = false;
+        assert!(market
+            .check_batch_orders(balance_manager.into(), &self_crossing, user_id, false)
+            .is_err());
+        // the checks never change the market or the balances
+        assert_eq!(market.get_order_num_of_user(&user_id), 0);
+        assert_eq!(
+            balance_manager.get(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(300)
+        );
+    }
+
+    #[test]
+    fn test_bulk_cancel() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let user_id = Uuid::from_str("5e4d3c2b-1a09-4f8e-9d7c-6b5a4f3e2d1c").unwrap();
+        balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(10000));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let mut put = |market: &mut Market, side, price| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &mut persistor,
+                    OrderInput {
+                        side,
+                        type_: OrderType::LIMIT,
+                        amount: dec!(1),
+                        price,
+                        quote_limit: dec!(0),
+                        taker_fee: dec!(0),
+                        maker_fee: dec!(0),
+                        market: String::from("ETH_USDT"),
+                        post_only: false,
+                        trailing_stop: None,
+                        peg: None,
+                    },
+                    user_id,
+                )
+                .unwrap()
+                .id
+        };
+        let bid_low = put(&mut market, OrderSide::BID, dec!(90));
+        let bid_high = put(&mut market, OrderSide::BID, dec!(95));
+        let ask_low = put(&mut market, OrderSide::ASK, dec!(105));
+        let ask_high = put(&mut market, OrderSide::ASK, dec!(110));
+
+        assert_eq!(
+            market.select_user_orders(&user_id, Some(OrderSide::BID), None, None),
+            vec![bid_low, bid_high]
+        );
+        assert_eq!(
+            market.select_user_orders(&user_id, None, Some(dec!(95)), Some(dec!(105))),
+            vec![bid_high, ask_low]
+        );
+        assert_eq!(
+            market.select_user_orders(&user_id, Some(OrderSide::ASK), Some(dec!(108)), None),
+            vec![ask_high]
+        );
+
+        let copy = market.clone();
+        let order_ids = market.select_user_orders(&user_id, Some(OrderSide::BID), None, None);
+        let cancelled = market.cancel_orders(balance_manager.into(), &mut persistor, &order_ids);
+        assert_eq!(cancelled.len(), 2);
+        assert_eq!(market.get_order_num_of_user(&user_id), 2);
+        assert_eq!(balance_manager.get(user_id, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(0));
+        // the copy is not changed along with the market
+        assert_eq!(copy.get_order_num_of_user(&user_id), 4);
+        assert_eq!(copy.bids.len(), 2);
+        assert_eq!(copy.get(bid_low).unwrap().remain, dec!(1));
+    }
 }
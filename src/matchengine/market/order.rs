@@ -1,19 +1,18 @@
-use crate::types::{OrderSide, OrderType};
+use crate::types::{OrderSide, OrderType, PegReference, TrailingType};
 use crate::utils::InternedString;
+use fluidex_common::rust_decimal::prelude::Zero;
 use fluidex_common::types::Decimal;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::sync::Arc;
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use uuid::Uuid;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct MarketKeyAsk {
     pub order_price: Decimal,
     pub order_id: u64,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone)]
 pub struct MarketKeyBid {
     pub order_price: Decimal,
     pub order_id: u64,
@@ -106,6 +105,73 @@ pub struct Order {
     pub finished_quote: Decimal,
     pub finished_fee: Decimal,
     pub update_time: f64,
+
+    // only set while a trailing stop order is waiting for its trigger,
+    // the order is kept out of the orderbook until then
+    #[serde(default)]
+    pub trailing_stop: Option<TrailingStop>,
+    // set for pegged limit orders, their price is maintained by the engine
+    #[serde(default)]
+    pub peg: Option<Peg>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Peg {
+    pub reference: PegReference,
+    // signed distance added to the reference price
+    pub offset: Decimal,
+    // the worst price the order can be repriced to, zero means no cap
+    pub limit_price: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TrailingStop {
+    pub trail_type: TrailingType,
+    // price distance for OFFSET, percentage of the last price for PERCENTAGE
+    pub trail_value: Decimal,
+    // current trigger level, only moves in favor of the order
+    pub trigger_price: Decimal,
+}
+
+impl TrailingStop {
+    pub fn new(trail_type: TrailingType, trail_value: Decimal) -> Self {
+        TrailingStop {
+            trail_type,
+            trail_value,
+            trigger_price: Decimal::zero(),
+        }
+    }
+
+    // an ask stop trails below the price and a bid stop trails above it
+    pub fn trigger_for(&self, side: OrderSide, price: Decimal) -> Decimal {
+        let distance = match self.trail_type {
+            TrailingType::OFFSET => self.trail_value,
+            TrailingType::PERCENTAGE => price * self.trail_value / Decimal::new(100, 0),
+        };
+        if side == OrderSide::ASK {
+            price - distance
+        } else {
+            price + distance
+        }
+    }
+
+    // returns true when the price has reversed to the trigger level,
+    // otherwise the trigger follows the price if it moved in favor of the order
+    pub fn follow(&mut self, side: OrderSide, price: Decimal, price_prec: u32) -> bool {
+        let reached = if side == OrderSide::ASK {
+            price <= self.trigger_price
+        } else {
+            price >= self.trigger_price
+        };
+        if reached {
+            return true;
+        }
+        let candidate = self.trigger_for(side, price).round_dp(price_prec);
+        if side == OrderSide::ASK && candidate > self.trigger_price || side == OrderSide::BID && candidate < self.trigger_price {
+            self.trigger_price = candidate;
+        }
+        false
+    }
 }
 
 /*
@@ -130,34 +196,15 @@ impl Order {
     pub fn is_ask(&self) -> bool {
         self.side == OrderSide::ASK
     }
-}
-
-#[derive(Clone, Debug)]
-pub struct OrderRc(Arc<RwLock<Order>>);
-
-/*
-    simulate behavior like RefCell, the syncing is ensured by locking in higher rank
-    here we use RwLock only for avoiding unsafe tag, we can just use raw pointer
-    casted from ARc rather than RwLock here if we do not care about unsafe
-*/
-impl OrderRc {
-    pub(super) fn new(order: Order) -> Self {
-        OrderRc(Arc::new(RwLock::new(order)))
-    }
-
-    pub fn borrow(&self) -> RwLockReadGuard<'_, Order> {
-        self.0.try_read().expect("Lock for parent entry ensure it")
-    }
-
-    pub(super) fn borrow_mut(&mut self) -> RwLockWriteGuard<'_, Order> {
-        self.0.try_write().expect("Lock for parent entry ensure it")
+    pub fn is_pending_stop(&self) -> bool {
+        self.trailing_stop.is_some()
     }
-
-    pub fn deep(&self) -> Order {
-        *self.borrow()
+    pub fn is_pegged(&self) -> bool {
+        self.peg.is_some()
     }
 }
 
+#[derive(Clone)]
 pub struct OrderInput {
     pub side: OrderSide,
     pub type_: OrderType,
@@ -168,6 +215,11 @@ pub struct OrderInput {
     pub maker_fee: Decimal,
     pub market: String,
     pub post_only: bool,
+    // the order waits as a trailing stop and becomes `type_` once triggered,
+    // trigger_price is decided by the engine
+    pub trailing_stop: Option<TrailingStop>,
+    // a pegged order is priced by the engine, `price` is ignored
+    pub peg: Option<Peg>,
 }
 
 pub struct OrderCommitment {
@@ -35,6 +35,11 @@ pub fn get_simple_asset_config(prec: u32) -> Vec<config::Asset> {
             name: MockAsset::USDT.name(),
             prec_save: prec,
             prec_show: prec,
+            deposit_confirmations: 0,
+            min_deposit: dec!(0),
+            min_withdraw: dec!(0),
+            max_withdraw: dec!(0),
+            withdraw_daily_quota: dec!(0),
         },
         config::Asset {
             id: MockAsset::ETH.id(),
@@ -42,6 +47,11 @@ pub fn get_simple_asset_config(prec: u32) -> Vec<config::Asset> {
             name: MockAsset::ETH.name(),
             prec_save: prec,
             prec_show: prec,
+            deposit_confirmations: 0,
+            min_deposit: dec!(0),
+            min_withdraw: dec!(0),
+            max_withdraw: dec!(0),
+            withdraw_daily_quota: dec!(0),
         },
     ]
 }
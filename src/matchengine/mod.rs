@@ -1,5 +1,7 @@
 pub mod asset;
+pub mod audit;
 pub mod authentication;
+pub mod clock;
 pub mod controller;
 pub mod dto;
 pub mod history;
@@ -7,5 +9,6 @@ pub mod market;
 pub mod persist;
 pub mod sequencer;
 pub mod server;
+pub mod state_hash;
 
 mod mock;
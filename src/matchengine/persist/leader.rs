@@ -0,0 +1,159 @@
+use crate::models::tablenames;
+use crate::types::ConnectionType;
+use anyhow::{bail, Result};
+use sqlx::Connection;
+use std::time::{Duration, Instant};
+
+// Only the holder of this Postgres advisory lock executes operations. The lock belongs to the
+// session, so it is held as long as the connection lives and released when the process dies.
+const LEADER_LOCK_KEY: i64 = 0x6469_6e67_6972;
+
+// The lock alone does not stop a leader that lost it without noticing yet. On taking over, an
+// engine bumps the epoch in `leader_state` from the lock session, the db rejects the operation
+// logs of older epochs past that point. The leader answers writes only within a lease renewed
+// from the same session, and only up to the operation log id it has reserved there, so a
+// successor knows which ids may still be in the wal of the old leader.
+pub struct LeaderLock {
+    conn: ConnectionType,
+    epoch: i64,
+    lease_until: Instant,
+    // none without a wal, nothing is answered from outside the db then
+    reserved: Option<u64>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct LeaderState {
+    pub epoch: i64,
+    pub start_operation_log_id: i64,
+    pub wal_operation_log_id: i64,
+}
+
+#[cfg(sqlxverf)]
+fn sqlverf_leader_lock() -> impl std::any::Any {
+    let epoch: i64 = 0;
+    let operation_log_id: i64 = 0;
+    (
+        sqlx::query!("select pg_try_advisory_lock($1)", LEADER_LOCK_KEY),
+        sqlx::query!("select epoch, start_operation_log_id, wal_operation_log_id from leader_state where id = 1"),
+        sqlx::query!(
+            "insert into leader_state (id, epoch, start_operation_log_id, wal_operation_log_id) values (1, 1, $1, $1)
+            on conflict (id) do update set epoch = leader_state.epoch + 1, start_operation_log_id = $1, wal_operation_log_id = $1, update_time = now()
+            returning epoch",
+            operation_log_id
+        ),
+        sqlx::query!(
+            "update leader_state set wal_operation_log_id = $2, update_time = now() where id = 1 and epoch = $1",
+            epoch,
+            operation_log_id
+        ),
+    )
+}
+
+#[test]
+fn utest_leader_lock() {
+    assert_eq!(
+        format!(
+            "select epoch, start_operation_log_id, wal_operation_log_id from {} where id = 1",
+            tablenames::LEADERSTATE
+        ),
+        "select epoch, start_operation_log_id, wal_operation_log_id from leader_state where id = 1"
+    );
+    assert_eq!(
+        format!(
+            "insert into {} (id, epoch, start_operation_log_id, wal_operation_log_id) values (1, 1, $1, $1)
+            on conflict (id) do update set epoch = {}.epoch + 1, start_operation_log_id = $1, wal_operation_log_id = $1, update_time = now()
+            returning epoch",
+            tablenames::LEADERSTATE,
+            tablenames::LEADERSTATE
+        ),
+        "insert into leader_state (id, epoch, start_operation_log_id, wal_operation_log_id) values (1, 1, $1, $1)
+            on conflict (id) do update set epoch = leader_state.epoch + 1, start_operation_log_id = $1, wal_operation_log_id = $1, update_time = now()
+            returning epoch"
+    );
+    assert_eq!(
+        format!(
+            "update {} set wal_operation_log_id = $2, update_time = now() where id = 1 and epoch = $1",
+            tablenames::LEADERSTATE
+        ),
+        "update leader_state set wal_operation_log_id = $2, update_time = now() where id = 1 and epoch = $1"
+    );
+}
+
+impl LeaderLock {
+    // none when another engine is the leader
+    pub async fn try_acquire(db: &str) -> Result<Option<Self>> {
+        let mut conn = ConnectionType::connect(db).await?;
+        let acquired: bool = sqlx::query_scalar("select pg_try_advisory_lock($1)")
+            .bind(LEADER_LOCK_KEY)
+            .fetch_one(&mut conn)
+            .await?;
+        if !acquired {
+            return Ok(None);
+        }
+        log::info!("leader lock acquired");
+        Ok(Some(Self {
+            conn,
+            epoch: 0,
+            lease_until: Instant::now(),
+            reserved: None,
+        }))
+    }
+
+    pub fn epoch(&self) -> i64 {
+        self.epoch
+    }
+
+    // what the last leader left, none before any engine took over
+    pub async fn state(&mut self) -> Result<Option<LeaderState>> {
+        Ok(sqlx::query_as(&format!(
+            "select epoch, start_operation_log_id, wal_operation_log_id from {} where id = 1",
+            tablenames::LEADERSTATE
+        ))
+        .fetch_optional(&mut self.conn)
+        .await?)
+    }
+
+    // Starts a new epoch after `operation_log_id`, the logs of older epochs past it are rejected
+    // from now on. Writes are answered once the lease is renewed.
+    pub async fn take_over(&mut self, operation_log_id: u64) -> Result<i64> {
+        self.epoch = sqlx::query_scalar(&format!(
+            "insert into {} (id, epoch, start_operation_log_id, wal_operation_log_id) values (1, 1, $1, $1)
+            on conflict (id) do update set epoch = {}.epoch + 1, start_operation_log_id = $1, wal_operation_log_id = $1, update_time = now()
+            returning epoch",
+            tablenames::LEADERSTATE,
+            tablenames::LEADERSTATE
+        ))
+        .bind(operation_log_id as i64)
+        .fetch_one(&mut self.conn)
+        .await?;
+        log::info!("leader epoch {} starts after operation log {}", self.epoch, operation_log_id);
+        Ok(self.epoch)
+    }
+
+    // Extends the lease and reserves the operation log ids up to `reserved` for the wal. Fails
+    // once the session holding the lock is gone or another engine took over, this one must stop
+    // writing then.
+    pub async fn renew(&mut self, reserved: Option<u64>, lease: Duration) -> Result<()> {
+        let started = Instant::now();
+        let updated = sqlx::query(&format!(
+            "update {} set wal_operation_log_id = $2, update_time = now() where id = 1 and epoch = $1",
+            tablenames::LEADERSTATE
+        ))
+        .bind(self.epoch)
+        .bind(reserved.unwrap_or(0) as i64)
+        .execute(&mut self.conn)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            bail!("leader epoch {} is over", self.epoch);
+        }
+        self.lease_until = started + lease;
+        self.reserved = reserved;
+        Ok(())
+    }
+
+    // whether the operation with this log id can be answered now
+    pub fn may_answer(&self, operation_log_id: u64) -> bool {
+        Instant::now() < self.lease_until && self.reserved.map_or(true, |reserved| operation_log_id <= reserved)
+    }
+}
@@ -0,0 +1,499 @@
+use crate::config;
+use crate::message::{Message, MessageId};
+use anyhow::{bail, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+// The file persistor writes the messages as json lines into a directory of numbered segments. A
+// segment is closed once it reaches the size or the age limit, then it is gzipped if asked to and
+// an entry describing it is appended to the index, both off the engine thread. A segment missing
+// from the index is the one being written, or one a crash left open, it is read as it is.
+const SEGMENT_SUFFIX: &str = "jsonl";
+const COMPRESSED_SUFFIX: &str = "jsonl.gz";
+const INDEX_FILE: &str = "index.jsonl";
+// held by the writer, opening closes the segments of the directory as left by a crash, and one
+// of them would be the segment another writer is on
+const LOCK_FILE: &str = "lock";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRecord {
+    // none for a message no operation caused
+    pub id: Option<MessageId>,
+    pub time: f64,
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SegmentIndex {
+    pub segment: u64,
+    pub count: u64,
+    pub first_time: f64,
+    pub last_time: f64,
+    // the ids of the messages caused by operations
+    pub first_id: Option<MessageId>,
+    pub last_id: Option<MessageId>,
+    pub compressed: bool,
+}
+
+impl SegmentIndex {
+    fn add(&mut self, record: &MessageRecord) {
+        if self.count == 0 {
+            self.first_time = record.time;
+        }
+        self.count += 1;
+        self.last_time = record.time;
+        if let Some(id) = record.id {
+            self.first_id.get_or_insert(id);
+            self.last_id = Some(id);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageFileConfig {
+    pub dir: String,
+    pub segment_size: u64,
+    // seconds of messages a segment holds
+    pub segment_interval: f64,
+    pub compress: bool,
+}
+
+impl MessageFileConfig {
+    pub fn from_settings(settings: &config::Settings) -> Self {
+        Self {
+            dir: settings.message_file_dir.clone(),
+            segment_size: settings.message_file_segment_size,
+            segment_interval: settings.message_file_segment_interval as f64,
+            compress: settings.message_file_compress,
+        }
+    }
+}
+
+fn segment_path<P: AsRef<Path>>(dir: P, segment: u64, compressed: bool) -> PathBuf {
+    let suffix = if compressed { COMPRESSED_SUFFIX } else { SEGMENT_SUFFIX };
+    dir.as_ref().join(format!("{:020}.{}", segment, suffix))
+}
+
+// the segment numbers found in the directory, oldest first
+fn segment_files<P: AsRef<Path>>(dir: P) -> Result<BTreeSet<u64>> {
+    let mut segments = BTreeSet::new();
+    if !dir.as_ref().exists() {
+        return Ok(segments);
+    }
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        let stem = name
+            .strip_suffix(&format!(".{}", COMPRESSED_SUFFIX))
+            .or_else(|| name.strip_suffix(&format!(".{}", SEGMENT_SUFFIX)));
+        if let Some(segment) = stem.and_then(|stem| stem.parse().ok()) {
+            segments.insert(segment);
+        }
+    }
+    Ok(segments)
+}
+
+fn read_index<P: AsRef<Path>>(dir: P) -> Result<Vec<SegmentIndex>> {
+    let path = dir.as_ref().join(INDEX_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut index = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            index.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(index)
+}
+
+// the plain file while it exists, it is only deleted once the gzipped one is complete
+fn open_segment<P: AsRef<Path>>(dir: P, segment: u64) -> Result<Box<dyn BufRead>> {
+    let plain = segment_path(&dir, segment, false);
+    if plain.exists() {
+        return Ok(Box::new(BufReader::new(File::open(plain)?)));
+    }
+    let compressed = File::open(segment_path(&dir, segment, true))?;
+    Ok(Box::new(BufReader::new(GzDecoder::new(compressed))))
+}
+
+// A record is a complete line. The last line of the segment being written may be cut short,
+// reading ends there.
+fn read_record(reader: &mut dyn BufRead) -> Result<Option<MessageRecord>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+fn scan_segment<P: AsRef<Path>>(dir: P, segment: u64) -> Result<SegmentIndex> {
+    let mut index = SegmentIndex {
+        segment,
+        ..Default::default()
+    };
+    let mut reader = open_segment(&dir, segment)?;
+    while let Some(record) = read_record(&mut *reader)? {
+        index.add(&record);
+    }
+    Ok(index)
+}
+
+// gzips a closed segment if asked to, then indexes it
+fn close_segment<P: AsRef<Path>>(dir: P, mut index: SegmentIndex, compress: bool) -> Result<()> {
+    let plain = segment_path(&dir, index.segment, false);
+    if compress && plain.exists() {
+        let compressed = segment_path(&dir, index.segment, true);
+        let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+        io::copy(&mut File::open(&plain)?, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+        fs::remove_file(&plain)?;
+    }
+    index.compressed = !plain.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(dir.as_ref().join(INDEX_FILE))?;
+    writeln!(file, "{}", serde_json::to_string(&index)?)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+pub struct MessageFileWriter {
+    config: MessageFileConfig,
+    file: BufWriter<File>,
+    index: SegmentIndex,
+    written: u64,
+    closer: Option<crossbeam_channel::Sender<SegmentIndex>>,
+    closer_thread: Option<std::thread::JoinHandle<()>>,
+    // released when the writer is dropped or the process dies
+    _lock: File,
+}
+
+#[cfg(not(target_family = "windows"))]
+fn lock_dir(dir: &str) -> Result<File> {
+    use std::os::unix::io::AsRawFd;
+    let file = OpenOptions::new().create(true).write(true).open(Path::new(dir).join(LOCK_FILE))?;
+    if nix::fcntl::flock(file.as_raw_fd(), nix::fcntl::FlockArg::LockExclusiveNonblock).is_err() {
+        bail!("message file directory {} is used by another writer", dir);
+    }
+    Ok(file)
+}
+
+#[cfg(target_family = "windows")]
+fn lock_dir(dir: &str) -> Result<File> {
+    // a file opened without sharing can not be opened again until it is closed
+    use std::os::windows::fs::OpenOptionsExt;
+    match OpenOptions::new()
+        .create(true)
+        .write(true)
+        .share_mode(0)
+        .open(Path::new(dir).join(LOCK_FILE))
+    {
+        Ok(file) => Ok(file),
+        Err(_) => bail!("message file directory {} is used by another writer", dir),
+    }
+}
+
+impl MessageFileWriter {
+    // Starts a new segment after the existing ones. The ones a crash left out of the index are
+    // closed first. Fails while another writer has the directory.
+    pub fn open(config: MessageFileConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let lock = lock_dir(&config.dir)?;
+        let indexed: BTreeSet<u64> = read_index(&config.dir)?.iter().map(|index| index.segment).collect();
+        let segments = segment_files(&config.dir)?;
+        for segment in segments.difference(&indexed) {
+            let index = scan_segment(&config.dir, *segment)?;
+            if index.count == 0 {
+                for compressed in [false, true] {
+                    let path = segment_path(&config.dir, *segment, compressed);
+                    if path.exists() {
+                        fs::remove_file(path)?;
+                    }
+                }
+                continue;
+            }
+            log::info!("message file segment {} was left open, {} messages", segment, index.count);
+            close_segment(&config.dir, index, config.compress)?;
+        }
+
+        let (closer, closed) = crossbeam_channel::unbounded::<SegmentIndex>();
+        let (dir, compress) = (config.dir.clone(), config.compress);
+        let closer_thread = std::thread::spawn(move || {
+            for index in closed {
+                let segment = index.segment;
+                if let Err(e) = close_segment(&dir, index, compress) {
+                    log::error!("closing message file segment {} failed: {}", segment, e);
+                }
+            }
+        });
+
+        let segment = segments.iter().next_back().map_or(1, |last| last + 1);
+        let file = BufWriter::new(File::create(segment_path(&config.dir, segment, false))?);
+        Ok(Self {
+            config,
+            file,
+            index: SegmentIndex {
+                segment,
+                ..Default::default()
+            },
+            written: 0,
+            closer: Some(closer),
+            closer_thread: Some(closer_thread),
+            _lock: lock,
+        })
+    }
+
+    pub fn write(&mut self, record: &MessageRecord) -> Result<()> {
+        if self.index.count > 0
+            && (self.written >= self.config.segment_size || record.time - self.index.first_time >= self.config.segment_interval)
+        {
+            self.rotate()?;
+        }
+        let line = serde_json::to_string(record)? + "\n";
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        self.index.add(record);
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    // flushes and makes what is written so far survive a crash of the machine
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.sync()?;
+        let segment = self.index.segment + 1;
+        self.file = BufWriter::new(File::create(segment_path(&self.config.dir, segment, false))?);
+        self.written = 0;
+        let closed = std::mem::replace(
+            &mut self.index,
+            SegmentIndex {
+                segment,
+                ..Default::default()
+            },
+        );
+        if let Some(closer) = self.closer.as_ref() {
+            closer.send(closed).ok();
+        }
+        Ok(())
+    }
+}
+
+// the segment being written stays out of the index, the next open closes it
+impl Drop for MessageFileWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.flush() {
+            log::error!("flushing message file segment {} failed: {}", self.index.segment, e);
+        }
+        self.closer.take();
+        if let Some(closer_thread) = self.closer_thread.take() {
+            closer_thread.join().ok();
+        }
+    }
+}
+
+// which messages a reader yields, every one by default
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    pub topics: Vec<String>,
+    pub user: Option<Uuid>,
+    pub market: Option<String>,
+    pub start_time: Option<f64>,
+    pub end_time: Option<f64>,
+    // skips the messages with an id up to this one, to resume where an earlier read stopped
+    pub after_id: Option<MessageId>,
+}
+
+impl MessageFilter {
+    pub fn matches(&self, record: &MessageRecord) -> bool {
+        (self.topics.is_empty() || self.topics.iter().any(|topic| topic == record.message.topic()))
+            && self.user.map_or(true, |user| record.message.users().contains(&user))
+            && self
+                .market
+                .as_ref()
+                .map_or(true, |market| record.message.market() == Some(market.as_str()))
+            && self.start_time.map_or(true, |time| record.time >= time)
+            && self.end_time.map_or(true, |time| record.time < time)
+            && self.after_id.map_or(true, |after| record.id.map_or(true, |id| id > after))
+    }
+
+    // whether the segment may hold a message matching
+    fn overlaps(&self, index: &SegmentIndex) -> bool {
+        self.start_time.map_or(true, |time| index.last_time >= time)
+            && self.end_time.map_or(true, |time| index.first_time < time)
+            && self.after_id.map_or(true, |after| index.last_id.map_or(true, |id| id > after))
+    }
+}
+
+pub struct MessageFileReader {
+    dir: PathBuf,
+}
+
+impl MessageFileReader {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    // the closed segments
+    pub fn index(&self) -> Result<Vec<SegmentIndex>> {
+        read_index(&self.dir)
+    }
+
+    // Streams the matching messages in the order they were written. Closed segments the index
+    // tells nothing matches in are not opened.
+    pub fn read(&self, filter: MessageFilter) -> Result<MessageIter> {
+        let index = self.index()?;
+        let indexed: BTreeSet<u64> = index.iter().map(|index| index.segment).collect();
+        let mut segments: Vec<u64> = index
+            .iter()
+            .filter(|index| filter.overlaps(index))
+            .map(|index| index.segment)
+            .collect();
+        segments.extend(segment_files(&self.dir)?.difference(&indexed));
+        segments.sort_unstable();
+        segments.dedup();
+        Ok(MessageIter {
+            dir: self.dir.clone(),
+            segments: segments.into(),
+            reader: None,
+            filter,
+        })
+    }
+}
+
+pub struct MessageIter {
+    dir: PathBuf,
+    segments: VecDeque<u64>,
+    reader: Option<Box<dyn BufRead>>,
+    filter: MessageFilter,
+}
+
+impl Iterator for MessageIter {
+    type Item = Result<MessageRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.reader.is_none() {
+                let segment = self.segments.pop_front()?;
+                match open_segment(&self.dir, segment) {
+                    Ok(reader) => self.reader = Some(reader),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            match read_record(&mut **self.reader.as_mut().unwrap()) {
+                Ok(Some(record)) if self.filter.matches(&record) => return Some(Ok(record)),
+                Ok(Some(_)) => (),
+                Ok(None) => self.reader = None,
+                Err(e) => {
+                    self.reader = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_message_file() {
+    use crate::message::{AuditReport, MmpMessage};
+
+    let dir = std::env::temp_dir().join(format!("dingir_message_file_test_{}", std::process::id()));
+    let user = Uuid::from_u128(1);
+    let record = |operation_log_id: u64, time: f64, market: &str| MessageRecord {
+        id: Some(MessageId { operation_log_id, seq: 0 }),
+        time,
+        message: Message::MmpMessage(Box::new(MmpMessage {
+            timestamp: time,
+            user_id: user,
+            market: market.to_string(),
+            qty_limit: "1".to_string(),
+            trade_count_limit: 1,
+            window: 1.0,
+            cancelled_orders: 0,
+        })),
+    };
+    let config = MessageFileConfig {
+        dir: dir.to_str().unwrap().to_string(),
+        segment_size: u64::MAX,
+        segment_interval: 10.0,
+        compress: true,
+    };
+    let mut writer = MessageFileWriter::open(config.clone()).unwrap();
+    // one writer at a time
+    assert!(MessageFileWriter::open(config.clone()).is_err());
+    // a segment per 10 seconds
+    for id in 1..=5 {
+        writer
+            .write(&record(id, id as f64 * 5.0, if id % 2 == 0 { "ETH_USDT" } else { "BTC_USDT" }))
+            .unwrap();
+    }
+    writer
+        .write(&MessageRecord {
+            id: None,
+            time: 26.0,
+            message: Message::AuditMessage(Box::new(AuditReport {
+                timestamp: 26.0,
+                mismatches: Vec::new(),
+            })),
+        })
+        .unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+    // the last segment was left open, opening again closes it
+    drop(MessageFileWriter::open(config).unwrap());
+    let reader = MessageFileReader::new(&dir);
+    assert_eq!(reader.index().unwrap().len(), 3);
+    assert!(reader.index().unwrap().iter().all(|index| index.compressed));
+
+    let ids = |filter: MessageFilter| -> Vec<Option<u64>> {
+        reader
+            .read(filter)
+            .unwrap()
+            .map(|record| record.unwrap().id.map(|id| id.operation_log_id))
+            .collect()
+    };
+    assert_eq!(
+        ids(MessageFilter::default()),
+        vec![Some(1), Some(2), Some(3), Some(4), Some(5), None]
+    );
+    let market = MessageFilter {
+        market: Some("ETH_USDT".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(ids(market), vec![Some(2), Some(4)]);
+    let range = MessageFilter {
+        topics: vec!["mmp".to_string()],
+        start_time: Some(10.0),
+        end_time: Some(25.0),
+        ..Default::default()
+    };
+    assert_eq!(ids(range), vec![Some(2), Some(3), Some(4)]);
+    let resume = MessageFilter {
+        after_id: Some(MessageId {
+            operation_log_id: 3,
+            seq: 0,
+        }),
+        user: Some(user),
+        ..Default::default()
+    };
+    assert_eq!(ids(resume), vec![Some(4), Some(5)]);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,3 +2,17 @@ mod state_save_load;
 pub use state_save_load::*;
 mod persistor;
 pub use persistor::*;
+mod snapshot;
+pub use snapshot::*;
+mod snapshot_file;
+pub use snapshot_file::*;
+mod retention;
+pub use retention::*;
+mod leader;
+pub use leader::*;
+mod outbox;
+pub use outbox::*;
+mod message_file;
+pub use message_file::*;
+pub mod wal;
+pub use wal::OperationWal;
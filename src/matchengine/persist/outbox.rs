@@ -0,0 +1,56 @@
+use crate::models::tablenames;
+use crate::types::ConnectionType;
+
+#[cfg(sqlxverf)]
+fn sqlverf_outbox_cursor() -> impl std::any::Any {
+    let operation_log_id: i64 = 0;
+    (
+        sqlx::query!("select operation_log_id from outbox_cursor where id = 1"),
+        sqlx::query!(
+            "insert into outbox_cursor (id, operation_log_id) values (1, $1)
+            on conflict (id) do update set operation_log_id = greatest(outbox_cursor.operation_log_id, excluded.operation_log_id), update_time = now()",
+            operation_log_id
+        ),
+    )
+}
+
+#[test]
+fn utest_outbox_cursor() {
+    assert_eq!(
+        format!("select operation_log_id from {} where id = 1", tablenames::OUTBOXCURSOR),
+        "select operation_log_id from outbox_cursor where id = 1"
+    );
+    assert_eq!(
+        format!(
+            "insert into {} (id, operation_log_id) values (1, $1)
+            on conflict (id) do update set operation_log_id = greatest({}.operation_log_id, excluded.operation_log_id), update_time = now()",
+            tablenames::OUTBOXCURSOR,
+            tablenames::OUTBOXCURSOR
+        ),
+        "insert into outbox_cursor (id, operation_log_id) values (1, $1)
+            on conflict (id) do update set operation_log_id = greatest(outbox_cursor.operation_log_id, excluded.operation_log_id), update_time = now()"
+    );
+}
+
+// The operation log id up to which every message is delivered. None before anything was, then no
+// message is published again.
+pub async fn get_outbox_cursor(conn: &mut ConnectionType) -> anyhow::Result<Option<u64>> {
+    let id: Option<i64> = sqlx::query_scalar(&format!("select operation_log_id from {} where id = 1", tablenames::OUTBOXCURSOR))
+        .fetch_optional(conn)
+        .await?;
+    Ok(id.map(|id| id as u64))
+}
+
+// never moves the cursor back, a restarted engine reports less until its messages are out
+pub async fn save_outbox_cursor(conn: &mut ConnectionType, operation_log_id: u64) -> anyhow::Result<()> {
+    sqlx::query(&format!(
+        "insert into {} (id, operation_log_id) values (1, $1)
+            on conflict (id) do update set operation_log_id = greatest({}.operation_log_id, excluded.operation_log_id), update_time = now()",
+        tablenames::OUTBOXCURSOR,
+        tablenames::OUTBOXCURSOR
+    ))
+    .bind(operation_log_id as i64)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
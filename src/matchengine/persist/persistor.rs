@@ -1,8 +1,9 @@
+use super::message_file::{MessageFileConfig, MessageFileWriter, MessageRecord};
 use crate::history::HistoryWriter;
 use crate::matchengine::market::{Order, Trade};
-use crate::message::{self, MessageManager, OrderMessage};
+use crate::message::{self, AuditReport, DepositMessage, MessageId, MessageManager, MmpMessage, OrderMessage, WithdrawMessage};
 pub use crate::models::{BalanceHistory, InternalTx};
-use crate::types::OrderEventType;
+use crate::types::{DepositStatus, OrderEventType, WithdrawStatus};
 
 ///////////////////////////// PersistExector interface ////////////////////////////
 
@@ -17,11 +18,22 @@ pub trait PersistExector: Send + Sync {
     fn real_persist(&self) -> bool {
         true
     }
+    // The messages of an operation are held back until it is in the operation log, then they go
+    // out with ids made of its log id. The ones of an operation that failed are dropped.
+    fn commit_operation(&mut self, _operation_log_id: u64) {}
+    fn discard_operation(&mut self) {}
+    // the operation log id up to which every message is delivered, none when it is not tracked
+    fn delivered_operation_log_id(&self) -> Option<u64> {
+        None
+    }
     fn put_balance(&mut self, balance: &BalanceHistory);
-    fn put_deposit(&mut self, balance: &BalanceHistory);
-    fn put_withdraw(&mut self, balance: &BalanceHistory);
+    fn put_deposit(&mut self, balance: &BalanceHistory, status: DepositStatus);
+    fn put_withdraw(&mut self, balance: &BalanceHistory, status: WithdrawStatus);
     fn put_order(&mut self, order: &Order, at_step: OrderEventType);
     fn put_trade(&mut self, trade: &Trade);
+    fn put_mmp(&mut self, mmp: &MmpMessage);
+    fn put_transfer(&mut self, tx: &InternalTx);
+    fn put_audit(&mut self, report: &AuditReport);
 }
 
 impl PersistExector for Box<dyn PersistExector + '_> {
@@ -31,14 +43,23 @@ impl PersistExector for Box<dyn PersistExector + '_> {
     fn real_persist(&self) -> bool {
         self.as_ref().real_persist()
     }
+    fn commit_operation(&mut self, operation_log_id: u64) {
+        self.as_mut().commit_operation(operation_log_id)
+    }
+    fn discard_operation(&mut self) {
+        self.as_mut().discard_operation()
+    }
+    fn delivered_operation_log_id(&self) -> Option<u64> {
+        self.as_ref().delivered_operation_log_id()
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
         self.as_mut().put_balance(balance)
     }
-    fn put_deposit(&mut self, balance: &BalanceHistory) {
-        self.as_mut().put_deposit(balance)
+    fn put_deposit(&mut self, balance: &BalanceHistory, status: DepositStatus) {
+        self.as_mut().put_deposit(balance, status)
     }
-    fn put_withdraw(&mut self, balance: &BalanceHistory) {
-        self.as_mut().put_withdraw(balance)
+    fn put_withdraw(&mut self, balance: &BalanceHistory, status: WithdrawStatus) {
+        self.as_mut().put_withdraw(balance, status)
     }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
         self.as_mut().put_order(order, at_step)
@@ -46,6 +67,15 @@ impl PersistExector for Box<dyn PersistExector + '_> {
     fn put_trade(&mut self, trade: &Trade) {
         self.as_mut().put_trade(trade)
     }
+    fn put_mmp(&mut self, mmp: &MmpMessage) {
+        self.as_mut().put_mmp(mmp)
+    }
+    fn put_transfer(&mut self, tx: &InternalTx) {
+        self.as_mut().put_transfer(tx)
+    }
+    fn put_audit(&mut self, report: &AuditReport) {
+        self.as_mut().put_audit(report)
+    }
 }
 
 impl PersistExector for &mut Box<dyn PersistExector + '_> {
@@ -55,14 +85,23 @@ impl PersistExector for &mut Box<dyn PersistExector + '_> {
     fn real_persist(&self) -> bool {
         self.as_ref().real_persist()
     }
+    fn commit_operation(&mut self, operation_log_id: u64) {
+        self.as_mut().commit_operation(operation_log_id)
+    }
+    fn discard_operation(&mut self) {
+        self.as_mut().discard_operation()
+    }
+    fn delivered_operation_log_id(&self) -> Option<u64> {
+        self.as_ref().delivered_operation_log_id()
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
         self.as_mut().put_balance(balance)
     }
-    fn put_deposit(&mut self, balance: &BalanceHistory) {
-        self.as_mut().put_deposit(balance)
+    fn put_deposit(&mut self, balance: &BalanceHistory, status: DepositStatus) {
+        self.as_mut().put_deposit(balance, status)
     }
-    fn put_withdraw(&mut self, balance: &BalanceHistory) {
-        self.as_mut().put_withdraw(balance)
+    fn put_withdraw(&mut self, balance: &BalanceHistory, status: WithdrawStatus) {
+        self.as_mut().put_withdraw(balance, status)
     }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
         self.as_mut().put_order(order, at_step)
@@ -70,6 +109,15 @@ impl PersistExector for &mut Box<dyn PersistExector + '_> {
     fn put_trade(&mut self, trade: &Trade) {
         self.as_mut().put_trade(trade)
     }
+    fn put_mmp(&mut self, mmp: &MmpMessage) {
+        self.as_mut().put_mmp(mmp)
+    }
+    fn put_transfer(&mut self, tx: &InternalTx) {
+        self.as_mut().put_transfer(tx)
+    }
+    fn put_audit(&mut self, report: &AuditReport) {
+        self.as_mut().put_audit(report)
+    }
 }
 
 ///////////////////////////// DummyPersistor  ////////////////////////////
@@ -91,10 +139,13 @@ impl PersistExector for DummyPersistor {
         false
     }
     fn put_balance(&mut self, _balance: &BalanceHistory) {}
-    fn put_deposit(&mut self, _balance: &BalanceHistory) {}
-    fn put_withdraw(&mut self, _balance: &BalanceHistory) {}
+    fn put_deposit(&mut self, _balance: &BalanceHistory, _status: DepositStatus) {}
+    fn put_withdraw(&mut self, _balance: &BalanceHistory, _status: WithdrawStatus) {}
     fn put_order(&mut self, _order: &Order, _as_step: OrderEventType) {}
     fn put_trade(&mut self, _trade: &Trade) {}
+    fn put_mmp(&mut self, _mmp: &MmpMessage) {}
+    fn put_transfer(&mut self, _tx: &InternalTx) {}
+    fn put_audit(&mut self, _report: &AuditReport) {}
 }
 
 impl PersistExector for &mut DummyPersistor {
@@ -102,10 +153,13 @@ impl PersistExector for &mut DummyPersistor {
         false
     }
     fn put_balance(&mut self, _balance: &BalanceHistory) {}
-    fn put_deposit(&mut self, _balance: &BalanceHistory) {}
-    fn put_withdraw(&mut self, _balance: &BalanceHistory) {}
+    fn put_deposit(&mut self, _balance: &BalanceHistory, _status: DepositStatus) {}
+    fn put_withdraw(&mut self, _balance: &BalanceHistory, _status: WithdrawStatus) {}
     fn put_order(&mut self, _order: &Order, _as_step: OrderEventType) {}
     fn put_trade(&mut self, _trade: &Trade) {}
+    fn put_mmp(&mut self, _mmp: &MmpMessage) {}
+    fn put_transfer(&mut self, _tx: &InternalTx) {}
+    fn put_audit(&mut self, _report: &AuditReport) {}
 }
 
 ///////////////////////////// MemBasedPersistor ////////////////////////////
@@ -131,51 +185,100 @@ impl PersistExector for MemBasedPersistor {
     fn put_balance(&mut self, balance: &BalanceHistory) {
         self.messages.push(message::Message::BalanceMessage(Box::new(balance.into())));
     }
-    fn put_deposit(&mut self, balance: &BalanceHistory) {
-        self.messages.push(message::Message::DepositMessage(Box::new(balance.into())));
+    fn put_deposit(&mut self, balance: &BalanceHistory, status: DepositStatus) {
+        self.messages
+            .push(message::Message::DepositMessage(Box::new(DepositMessage::new(balance, status))));
     }
-    fn put_withdraw(&mut self, balance: &BalanceHistory) {
-        self.messages.push(message::Message::WithdrawMessage(Box::new(balance.into())));
+    fn put_withdraw(&mut self, balance: &BalanceHistory, status: WithdrawStatus) {
+        self.messages
+            .push(message::Message::WithdrawMessage(Box::new(WithdrawMessage::new(balance, status))));
+    }
+    fn put_mmp(&mut self, mmp: &MmpMessage) {
+        self.messages.push(message::Message::MmpMessage(Box::new(mmp.clone())));
+    }
+    fn put_transfer(&mut self, tx: &InternalTx) {
+        self.messages.push(message::Message::TransferMessage(Box::new(tx.into())));
+    }
+    fn put_audit(&mut self, report: &AuditReport) {
+        self.messages.push(message::Message::AuditMessage(Box::new(report.clone())));
     }
 }
 
 ///////////////////////////// FileBasedPersistor ////////////////////////////
 
 pub struct FileBasedPersistor {
-    output_file: std::fs::File,
+    writer: MessageFileWriter,
+    // messages of the running operation, written once it is logged
+    pending: Vec<message::Message>,
+    // the messages are synced with their operation, so they are delivered up to the last one
+    committed: u64,
 }
 impl FileBasedPersistor {
-    pub fn new(output_file_name: &str) -> Self {
-        let output_file = std::fs::File::create(output_file_name).unwrap();
-        Self { output_file }
+    pub fn new(config: MessageFileConfig) -> Self {
+        let writer = MessageFileWriter::open(config).unwrap();
+        Self {
+            writer,
+            pending: Vec::new(),
+            committed: 0,
+        }
     }
-    pub fn write_msg(&mut self, msg: message::Message) {
-        use std::io::Write;
-        let s = serde_json::to_string(&msg).unwrap();
-        self.output_file.write_fmt(format_args!("{}\n", s)).unwrap();
+    pub fn write_msg(&mut self, msg: message::Message, id: Option<MessageId>) {
+        let record = MessageRecord {
+            id,
+            time: crate::clock::now(),
+            message: msg,
+        };
+        self.writer.write(&record).unwrap();
     }
 }
 
 impl PersistExector for FileBasedPersistor {
+    fn commit_operation(&mut self, operation_log_id: u64) {
+        for (seq, mut msg) in std::mem::take(&mut self.pending).into_iter().enumerate() {
+            let id = MessageId {
+                operation_log_id,
+                seq: seq as u32,
+            };
+            msg.set_operation_log_id(operation_log_id);
+            self.write_msg(msg, Some(id));
+        }
+        // delivered means on disk, the cursor must not get past messages a power loss would drop
+        self.writer.sync().unwrap();
+        self.committed = operation_log_id;
+    }
+    fn discard_operation(&mut self) {
+        self.pending.clear();
+    }
+    fn delivered_operation_log_id(&self) -> Option<u64> {
+        Some(self.committed)
+    }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
-        let msg = message::Message::OrderMessage(Box::new(OrderMessage::from_order(order, at_step)));
-        self.write_msg(msg);
+        self.pending
+            .push(message::Message::OrderMessage(Box::new(OrderMessage::from_order(order, at_step))));
     }
     fn put_trade(&mut self, trade: &Trade) {
-        let msg = message::Message::TradeMessage(Box::new(trade.clone()));
-        self.write_msg(msg);
+        self.pending.push(message::Message::TradeMessage(Box::new(trade.clone())));
     }
     fn put_balance(&mut self, balance: &BalanceHistory) {
-        let msg = message::Message::BalanceMessage(Box::new(balance.into()));
-        self.write_msg(msg);
+        self.pending.push(message::Message::BalanceMessage(Box::new(balance.into())));
+    }
+    fn put_deposit(&mut self, balance: &BalanceHistory, status: DepositStatus) {
+        self.pending
+            .push(message::Message::DepositMessage(Box::new(DepositMessage::new(balance, status))));
+    }
+    fn put_withdraw(&mut self, balance: &BalanceHistory, status: WithdrawStatus) {
+        self.pending
+            .push(message::Message::WithdrawMessage(Box::new(WithdrawMessage::new(balance, status))));
     }
-    fn put_deposit(&mut self, balance: &BalanceHistory) {
-        let msg = message::Message::DepositMessage(Box::new(balance.into()));
-        self.write_msg(msg);
+    fn put_mmp(&mut self, mmp: &MmpMessage) {
+        self.pending.push(message::Message::MmpMessage(Box::new(mmp.clone())));
     }
-    fn put_withdraw(&mut self, balance: &BalanceHistory) {
-        let msg = message::Message::WithdrawMessage(Box::new(balance.into()));
-        self.write_msg(msg);
+    fn put_transfer(&mut self, tx: &InternalTx) {
+        self.pending.push(message::Message::TransferMessage(Box::new(tx.into())));
+    }
+    fn put_audit(&mut self, report: &AuditReport) {
+        self.write_msg(message::Message::AuditMessage(Box::new(report.clone())), None);
+        self.writer.flush().unwrap();
     }
 }
 
@@ -183,11 +286,16 @@ impl PersistExector for FileBasedPersistor {
 
 pub struct MessengerBasedPersistor {
     inner: Box<dyn MessageManager>,
+    // the outbox: messages of the running operation, not logged yet
+    pending: Vec<message::Message>,
 }
 
 impl MessengerBasedPersistor {
     pub fn new(inner: Box<dyn MessageManager>) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            pending: Vec::new(),
+        }
     }
 }
 
@@ -199,20 +307,52 @@ impl PersistExector for MessengerBasedPersistor {
         }
         true
     }
+    fn commit_operation(&mut self, operation_log_id: u64) {
+        for (seq, mut msg) in self.pending.drain(..).enumerate() {
+            let id = MessageId {
+                operation_log_id,
+                seq: seq as u32,
+            };
+            msg.set_operation_log_id(operation_log_id);
+            self.inner.push_message(&msg, id);
+        }
+    }
+    fn discard_operation(&mut self) {
+        if !self.pending.is_empty() {
+            log::warn!("{} messages of an operation not logged are dropped", self.pending.len());
+            self.pending.clear();
+        }
+    }
+    fn delivered_operation_log_id(&self) -> Option<u64> {
+        self.inner.delivered_operation_log_id()
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
-        self.inner.push_balance_message(&balance.into());
+        self.pending.push(message::Message::BalanceMessage(Box::new(balance.into())));
     }
-    fn put_deposit(&mut self, balance: &BalanceHistory) {
-        self.inner.push_deposit_message(&balance.into());
+    fn put_deposit(&mut self, balance: &BalanceHistory, status: DepositStatus) {
+        self.pending
+            .push(message::Message::DepositMessage(Box::new(DepositMessage::new(balance, status))));
     }
-    fn put_withdraw(&mut self, balance: &BalanceHistory) {
-        self.inner.push_withdraw_message(&balance.into());
+    fn put_withdraw(&mut self, balance: &BalanceHistory, status: WithdrawStatus) {
+        self.pending
+            .push(message::Message::WithdrawMessage(Box::new(WithdrawMessage::new(balance, status))));
     }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
-        self.inner.push_order_message(&OrderMessage::from_order(order, at_step));
+        self.pending
+            .push(message::Message::OrderMessage(Box::new(OrderMessage::from_order(order, at_step))));
     }
     fn put_trade(&mut self, trade: &Trade) {
-        self.inner.push_trade_message(trade);
+        self.pending.push(message::Message::TradeMessage(Box::new(trade.clone())));
+    }
+    fn put_mmp(&mut self, mmp: &MmpMessage) {
+        self.pending.push(message::Message::MmpMessage(Box::new(mmp.clone())));
+    }
+    fn put_transfer(&mut self, tx: &InternalTx) {
+        self.pending.push(message::Message::TransferMessage(Box::new(tx.into())));
+    }
+    // an audit is no operation, the report goes out at once
+    fn put_audit(&mut self, report: &AuditReport) {
+        self.inner.push_audit_message(report);
     }
 }
 
@@ -239,10 +379,10 @@ impl PersistExector for DBBasedPersistor {
     fn put_balance(&mut self, balance: &BalanceHistory) {
         self.inner.append_balance_history(balance.clone());
     }
-    fn put_deposit(&mut self, _balance: &BalanceHistory) {
+    fn put_deposit(&mut self, _balance: &BalanceHistory, _status: DepositStatus) {
         // TODO
     }
-    fn put_withdraw(&mut self, _balance: &BalanceHistory) {
+    fn put_withdraw(&mut self, _balance: &BalanceHistory, _status: WithdrawStatus) {
         // TODO
     }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
@@ -257,6 +397,15 @@ impl PersistExector for DBBasedPersistor {
     fn put_trade(&mut self, trade: &Trade) {
         self.inner.append_pair_user_trade(trade);
     }
+    fn put_mmp(&mut self, _mmp: &MmpMessage) {
+        // not a part of history
+    }
+    fn put_transfer(&mut self, tx: &InternalTx) {
+        self.inner.append_internal_tx(tx.clone());
+    }
+    fn put_audit(&mut self, _report: &AuditReport) {
+        // not a part of history
+    }
 }
 
 ///////////////////////////// CompositePersistor  ////////////////////////////
@@ -281,19 +430,34 @@ impl PersistExector for CompositePersistor {
         }
         true
     }
+    fn commit_operation(&mut self, operation_log_id: u64) {
+        for p in &mut self.persistors {
+            p.commit_operation(operation_log_id);
+        }
+    }
+    fn discard_operation(&mut self) {
+        for p in &mut self.persistors {
+            p.discard_operation();
+        }
+    }
+    // The slowest of the tracked ones. Every message stream and the message files are tracked,
+    // only the history db writer is not, it is disabled.
+    fn delivered_operation_log_id(&self) -> Option<u64> {
+        self.persistors.iter().filter_map(|p| p.delivered_operation_log_id()).min()
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
         for p in &mut self.persistors {
             p.put_balance(balance);
         }
     }
-    fn put_deposit(&mut self, balance: &BalanceHistory) {
+    fn put_deposit(&mut self, balance: &BalanceHistory, status: DepositStatus) {
         for p in &mut self.persistors {
-            p.put_deposit(balance);
+            p.put_deposit(balance, status);
         }
     }
-    fn put_withdraw(&mut self, balance: &BalanceHistory) {
+    fn put_withdraw(&mut self, balance: &BalanceHistory, status: WithdrawStatus) {
         for p in &mut self.persistors {
-            p.put_withdraw(balance);
+            p.put_withdraw(balance, status);
         }
     }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
@@ -306,4 +470,19 @@ impl PersistExector for CompositePersistor {
             p.put_trade(trade);
         }
     }
+    fn put_mmp(&mut self, mmp: &MmpMessage) {
+        for p in &mut self.persistors {
+            p.put_mmp(mmp);
+        }
+    }
+    fn put_transfer(&mut self, tx: &InternalTx) {
+        for p in &mut self.persistors {
+            p.put_transfer(tx);
+        }
+    }
+    fn put_audit(&mut self, report: &AuditReport) {
+        for p in &mut self.persistors {
+            p.put_audit(report);
+        }
+    }
 }
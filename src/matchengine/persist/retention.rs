@@ -0,0 +1,230 @@
+use super::state_save_load::delete_slice;
+use crate::config::{self, OperationLogRetention};
+use crate::models::{tablenames, SliceHistory};
+use crate::types::ConnectionType;
+use sqlx::Connection;
+use std::collections::BTreeSet;
+
+const DAY: i64 = 86400;
+const WEEK: i64 = 7 * DAY;
+
+// Which slices are kept: the latest `recent` ones, then the latest slice of each of the last
+// `daily` days and of each of the last `weekly` weeks. The latest slice is always kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    pub recent: usize,
+    pub daily: i64,
+    pub weekly: i64,
+}
+
+impl RetentionPolicy {
+    pub fn from_settings(settings: &config::Settings) -> Self {
+        Self {
+            recent: settings.slice_keep_recent.max(1),
+            daily: settings.slice_keep_daily as i64,
+            weekly: settings.slice_keep_weekly as i64,
+        }
+    }
+
+    // slice ids are unix times, `now` is one too
+    pub fn kept(&self, slice_ids: &[i64], now: i64) -> BTreeSet<i64> {
+        let mut slice_ids = slice_ids.to_vec();
+        slice_ids.sort_unstable_by(|a, b| b.cmp(a));
+        let mut kept: BTreeSet<i64> = slice_ids.iter().take(self.recent).copied().collect();
+        for (period, count) in [(DAY, self.daily), (WEEK, self.weekly)] {
+            let mut periods = BTreeSet::new();
+            for slice_id in &slice_ids {
+                let age = now.div_euclid(period) - slice_id.div_euclid(period);
+                // newest first, so the first slice seen in a period is its latest
+                if age < count && periods.insert(age) {
+                    kept.insert(*slice_id);
+                }
+            }
+        }
+        kept
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RetentionReport {
+    pub kept: Vec<i64>,
+    pub deleted: Vec<i64>,
+    // rows of slices that have no slice_history entry, left by a slice that failed half way
+    pub orphan_rows: u64,
+    // the operation logs up to this id are covered by every kept slice
+    pub operation_log_id: u64,
+    pub archived_operation_logs: u64,
+    pub pruned_operation_logs: u64,
+}
+
+const SLICE_TABLES: [&str; 12] = [
+    tablenames::BALANCESLICE,
+    tablenames::ORDERSLICE,
+    tablenames::MMPSLICE,
+    tablenames::SUBACCOUNTSLICE,
+    tablenames::BUSINESSIDSLICE,
+    tablenames::ASSETLEDGERSLICE,
+    tablenames::WITHDRAWQUOTASLICE,
+    tablenames::WITHDRAWUSAGESLICE,
+    tablenames::ACCOUNTLOCKSLICE,
+    tablenames::VESTINGSLICE,
+    tablenames::DEPOSITSLICE,
+    tablenames::WITHDRAWSLICE,
+];
+
+#[cfg(sqlxverf)]
+fn sqlverf_apply_retention() -> impl std::any::Any {
+    let slice_id: i64 = 0;
+    let operation_log_id: i64 = 0;
+    (
+        sqlx::query!("select * from slice_history order by time desc"),
+        sqlx::query!(
+            "delete from balance_slice where slice_id < $1 and slice_id not in (select time from slice_history)",
+            slice_id
+        ),
+        sqlx::query!(
+            "insert into operation_log_archive select * from operation_log where id <= $1",
+            operation_log_id
+        ),
+        sqlx::query!("delete from operation_log where id <= $1", operation_log_id),
+    )
+}
+
+#[test]
+fn utest_apply_retention() {
+    assert_eq!(
+        format!("select * from {} order by time desc", tablenames::SLICEHISTORY),
+        "select * from slice_history order by time desc"
+    );
+    assert_eq!(
+        format!(
+            "delete from {} where slice_id < $1 and slice_id not in (select time from {})",
+            tablenames::BALANCESLICE,
+            tablenames::SLICEHISTORY
+        ),
+        "delete from balance_slice where slice_id < $1 and slice_id not in (select time from slice_history)"
+    );
+    assert_eq!(
+        format!(
+            "insert into {} select * from {} where id <= $1",
+            tablenames::OPERATIONLOGARCHIVE,
+            tablenames::OPERATIONLOG
+        ),
+        "insert into operation_log_archive select * from operation_log where id <= $1"
+    );
+    assert_eq!(
+        format!("delete from {} where id <= $1", tablenames::OPERATIONLOG),
+        "delete from operation_log where id <= $1"
+    );
+}
+
+// Applies the retention policy to the slices in the db. With `dry_run` nothing is deleted, the
+// report tells what would be.
+pub async fn apply_retention(conn: &mut ConnectionType, settings: &config::Settings, dry_run: bool) -> anyhow::Result<RetentionReport> {
+    let mut report = RetentionReport::default();
+    let slices: Vec<SliceHistory> = sqlx::query_as(&format!("select * from {} order by time desc", tablenames::SLICEHISTORY))
+        .fetch_all(&mut *conn)
+        .await?;
+    let latest = match slices.first() {
+        Some(slice) => slice.time,
+        None => return Ok(report),
+    };
+    let now = fluidex_common::utils::timeutil::current_timestamp() as i64;
+    let kept = RetentionPolicy::from_settings(settings).kept(&slices.iter().map(|slice| slice.time).collect::<Vec<_>>(), now);
+    report.operation_log_id = slices
+        .iter()
+        .filter(|slice| kept.contains(&slice.time))
+        .map(|slice| slice.end_operation_log_id as u64)
+        .min()
+        .unwrap_or(0);
+    for slice in &slices {
+        if kept.contains(&slice.time) {
+            report.kept.push(slice.time);
+        } else {
+            report.deleted.push(slice.time);
+        }
+    }
+    if dry_run {
+        log::info!("slice retention dry run: {:?}", report);
+        return Ok(report);
+    }
+
+    for slice_id in &report.deleted {
+        delete_slice(&mut *conn, *slice_id).await?;
+    }
+    // a slice is written before its slice_history entry, the ones after the latest may still be in progress
+    for table in SLICE_TABLES {
+        report.orphan_rows += sqlx::query(&format!(
+            "delete from {} where slice_id < $1 and slice_id not in (select time from {})",
+            table,
+            tablenames::SLICEHISTORY
+        ))
+        .bind(latest)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+    }
+
+    if settings.operation_log_retention != OperationLogRetention::Keep {
+        let operation_log_id = report.operation_log_id as i64;
+        let mut tx = conn.begin().await?;
+        if settings.operation_log_retention == OperationLogRetention::Archive {
+            report.archived_operation_logs = sqlx::query(&format!(
+                "insert into {} select * from {} where id <= $1",
+                tablenames::OPERATIONLOGARCHIVE,
+                tablenames::OPERATIONLOG
+            ))
+            .bind(operation_log_id)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        }
+        report.pruned_operation_logs = sqlx::query(&format!("delete from {} where id <= $1", tablenames::OPERATIONLOG))
+            .bind(operation_log_id)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+    }
+    log::info!(
+        "slice retention: kept {:?}, deleted {:?}, {} orphan slice rows deleted, operation logs up to {}: {} archived, {} pruned",
+        report.kept,
+        report.deleted,
+        report.orphan_rows,
+        report.operation_log_id,
+        report.archived_operation_logs,
+        report.pruned_operation_logs
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+#[test]
+fn test_retention_policy() {
+    let policy = RetentionPolicy {
+        recent: 2,
+        daily: 3,
+        weekly: 2,
+    };
+    let now = 100 * WEEK + 3 * DAY + 12 * 3600;
+    let hour = 3600;
+    let slices = [
+        now - hour,
+        now - 2 * hour,
+        now - 3 * hour,
+        // yesterday, the latest of the day is kept
+        now - DAY - hour,
+        now - DAY - 2 * hour,
+        // beyond the daily ones, only the latest of last week is kept
+        now - 4 * DAY,
+        now - 7 * DAY,
+        now - 8 * DAY,
+        // too old
+        now - 3 * WEEK,
+    ];
+    let kept = policy.kept(&slices, now);
+    let expected: BTreeSet<i64> = [now - hour, now - 2 * hour, now - DAY - hour, now - 4 * DAY].into_iter().collect();
+    assert_eq!(kept, expected);
+    // the latest slice is kept however old it is
+    assert_eq!(policy.kept(&[now - 3 * WEEK], now).len(), 1);
+}
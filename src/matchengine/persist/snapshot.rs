@@ -0,0 +1,540 @@
+use super::retention::apply_retention;
+use super::state_save_load::{dump_records, truncate_wal, DUMPING_SET_LIMIT};
+use crate::asset::{self, BalanceMapKey};
+use crate::config;
+use crate::controller::Controller;
+use crate::market::{MmpConfig, Order, Peg, TrailingStop};
+use crate::models;
+use crate::sqlxextend::*;
+use crate::state_hash::StateHash;
+use crate::types::{ConnectionType, SimpleResult};
+use anyhow::bail;
+use fluidex_common::rust_decimal::Decimal;
+use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
+use models::{
+    AccountLockSlice, AssetLedgerSlice, BalanceSliceInsert, BusinessIdSlice, DepositSlice, MmpSlice, OrderSlice, SliceHistory,
+    SubAccountSlice, VestingSlice, WithdrawQuotaSlice, WithdrawSlice, WithdrawUsageSlice,
+};
+use sqlx::Connection;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+// A consistent copy of the state at one operation log id. It owns all it needs, so it can be
+// written by a background task while the engine goes on serving writes. The balances and the
+// orders are persistent maps shared with the engine until either side changes, so capturing them
+// takes no time, everything else is copied into slice records while the engine holds its write
+// lock.
+pub struct StateSnapshot {
+    pub slice_history: SliceHistory,
+    // only kept in snapshot files, the db has its own market and asset tables
+    pub markets: Vec<config::Market>,
+    pub assets: Vec<config::Asset>,
+    pub balances: im::HashMap<BalanceMapKey, Decimal>,
+    pub orders: Vec<OrderSlice>,
+    // the books of a captured snapshot, not in `orders` yet
+    order_books: Vec<im::OrdMap<u64, Order>>,
+    pub mmps: Vec<MmpSlice>,
+    pub sub_accounts: Vec<SubAccountSlice>,
+    pub business_ids: Vec<BusinessIdSlice>,
+    pub asset_ledgers: Vec<AssetLedgerSlice>,
+    pub withdraw_quotas: Vec<WithdrawQuotaSlice>,
+    pub withdraw_usages: Vec<WithdrawUsageSlice>,
+    pub account_locks: Vec<AccountLockSlice>,
+    pub vestings: Vec<VestingSlice>,
+    pub deposits: Vec<DepositSlice>,
+    pub withdraws: Vec<WithdrawSlice>,
+}
+
+impl StateSnapshot {
+    pub fn new(slice_history: SliceHistory) -> Self {
+        Self {
+            slice_history,
+            markets: Vec::new(),
+            assets: Vec::new(),
+            balances: im::HashMap::new(),
+            orders: Vec::new(),
+            order_books: Vec::new(),
+            mmps: Vec::new(),
+            sub_accounts: Vec::new(),
+            business_ids: Vec::new(),
+            asset_ledgers: Vec::new(),
+            withdraw_quotas: Vec::new(),
+            withdraw_usages: Vec::new(),
+            account_locks: Vec::new(),
+            vestings: Vec::new(),
+            deposits: Vec::new(),
+            withdraws: Vec::new(),
+        }
+    }
+
+    pub fn take(slice_id: i64, controller: &Controller) -> Self {
+        let mut snapshot = Self::capture(slice_id, controller);
+        snapshot.fill_orders();
+        snapshot
+    }
+
+    // Like `take`, but the orders are left in the persistent maps of the books, which are shared
+    // with the markets until either side changes. `fill_orders` turns them into slice records.
+    pub fn capture(slice_id: i64, controller: &Controller) -> Self {
+        let sequencer = &controller.sequencer;
+        let update_controller = &controller.update_controller;
+        Self {
+            slice_history: SliceHistory {
+                time: slice_id,
+                end_operation_log_id: sequencer.get_operation_log_id() as i64,
+                end_order_id: sequencer.get_order_id() as i64,
+                end_trade_id: sequencer.get_trade_id() as i64,
+                state_hash: None,
+            },
+            markets: controller
+                .markets
+                .values()
+                .map(|market| config::Market {
+                    name: market.name.to_string(),
+                    base: market.base.to_string(),
+                    quote: market.quote.to_string(),
+                    amount_prec: market.amount_prec,
+                    price_prec: market.price_prec,
+                    fee_prec: market.fee_prec,
+                    min_amount: market.min_amount,
+                })
+                .collect(),
+            assets: controller.settings.assets.clone(),
+            balances: controller.balance_manager.balances.clone(),
+            orders: Vec::new(),
+            order_books: controller.markets.values().map(|market| market.orders.clone()).collect(),
+            mmps: controller
+                .markets
+                .values()
+                .flat_map(|market| {
+                    market.mmp.iter().map(move |(user_id, mmp)| MmpSlice {
+                        slice_id,
+                        user_id: user_id.to_string(),
+                        market: market.name.to_string(),
+                        qty_limit: mmp.config.qty_limit,
+                        trade_count_limit: mmp.config.trade_count_limit as i32,
+                        time_window: mmp.config.window,
+                        triggered: mmp.triggered,
+                    })
+                })
+                .collect(),
+            sub_accounts: controller
+                .sub_account_manager
+                .accounts
+                .iter()
+                .flat_map(|(owner, sub_accounts)| {
+                    sub_accounts.iter().map(move |(name, account_id)| SubAccountSlice {
+                        slice_id,
+                        owner: owner.to_string(),
+                        name: name.clone(),
+                        account_id: account_id.to_string(),
+                    })
+                })
+                .collect(),
+            business_ids: update_controller
+                .applied_iter()
+                .map(|(time, key)| BusinessIdSlice {
+                    slice_id,
+                    user_id: key.user_id.to_string(),
+                    asset: key.asset.clone(),
+                    business: key.business.clone(),
+                    business_id: key.business_id as i64,
+                    time: FTimestamp(*time).into(),
+                })
+                .collect(),
+            asset_ledgers: update_controller
+                .ledger
+                .iter()
+                .map(|(asset, ledger)| AssetLedgerSlice {
+                    slice_id,
+                    asset: asset.clone(),
+                    deposit: ledger.deposit,
+                    withdraw: ledger.withdraw,
+                    fee: ledger.fee,
+                })
+                .collect(),
+            withdraw_quotas: controller
+                .withdraw_quota_manager
+                .overrides
+                .iter()
+                .map(|((user_id, asset), quota)| WithdrawQuotaSlice {
+                    slice_id,
+                    user_id: user_id.to_string(),
+                    asset: asset.clone(),
+                    quota: *quota,
+                })
+                .collect(),
+            withdraw_usages: controller
+                .withdraw_quota_manager
+                .usage
+                .iter()
+                .flat_map(|((user_id, asset), withdraws)| {
+                    withdraws.iter().map(move |(time, amount)| WithdrawUsageSlice {
+                        slice_id,
+                        user_id: user_id.to_string(),
+                        asset: asset.clone(),
+                        time: FTimestamp(*time).into(),
+                        amount: *amount,
+                    })
+                })
+                .collect(),
+            account_locks: controller
+                .account_lock_manager
+                .locks
+                .iter()
+                .map(|((user_id, asset), reason)| AccountLockSlice {
+                    slice_id,
+                    user_id: user_id.to_string(),
+                    asset: asset.clone(),
+                    reason: reason.clone(),
+                })
+                .collect(),
+            vestings: update_controller
+                .vestings
+                .values()
+                .map(|vesting| VestingSlice {
+                    slice_id,
+                    user_id: vesting.user_id.to_string(),
+                    asset: vesting.asset.clone(),
+                    business: vesting.business.clone(),
+                    business_id: vesting.business_id as i64,
+                    total: vesting.total,
+                    released: vesting.released,
+                    schedule: serde_json::to_string(&vesting.schedule).unwrap(),
+                    detail: vesting.detail.to_string(),
+                })
+                .collect(),
+            deposits: update_controller
+                .pending_deposits
+                .values()
+                .map(|deposit| DepositSlice {
+                    slice_id,
+                    user_id: deposit.user_id.to_string(),
+                    asset: deposit.asset.clone(),
+                    business: deposit.business.clone(),
+                    business_id: deposit.business_id as i64,
+                    market_price: deposit.market_price,
+                    amount: deposit.amount,
+                    confirmations: deposit.confirmations as i32,
+                    create_time: FTimestamp(deposit.create_time).into(),
+                    detail: deposit.detail.to_string(),
+                })
+                .collect(),
+            withdraws: update_controller
+                .pending_withdraws
+                .values()
+                .map(|withdraw| WithdrawSlice {
+                    slice_id,
+                    user_id: withdraw.user_id.to_string(),
+                    asset: withdraw.asset.clone(),
+                    business: withdraw.business.clone(),
+                    business_id: withdraw.business_id as i64,
+                    market_price: withdraw.market_price,
+                    amount: withdraw.amount,
+                    create_time: FTimestamp(withdraw.create_time).into(),
+                    detail: withdraw.detail.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn slice_id(&self) -> i64 {
+        self.slice_history.time
+    }
+
+    pub fn fill_orders(&mut self) {
+        let slice_id = self.slice_id();
+        for book in std::mem::take(&mut self.order_books) {
+            self.orders.extend(book.values().map(|order| OrderSlice {
+                id: order.id as i64,
+                slice_id,
+                order_type: order.type_,
+                order_side: order.side,
+                create_time: FTimestamp(order.create_time).into(),
+                update_time: FTimestamp(order.update_time).into(),
+                user_id: order.user.to_string(),
+                market: order.market.to_string(),
+                price: order.price,
+                amount: order.amount,
+                taker_fee: order.taker_fee,
+                maker_fee: order.maker_fee,
+                remain: order.remain,
+                frozen: order.frozen,
+                finished_base: order.finished_base,
+                finished_quote: order.finished_quote,
+                finished_fee: order.finished_fee,
+                post_only: order.post_only,
+                trail_type: order.trailing_stop.map(|stop| stop.trail_type),
+                trail_value: order.trailing_stop.map(|stop| stop.trail_value),
+                trigger_price: order.trailing_stop.map(|stop| stop.trigger_price),
+                peg_reference: order.peg.map(|peg| peg.reference),
+                peg_offset: order.peg.map(|peg| peg.offset),
+                peg_limit: order.peg.map(|peg| peg.limit_price),
+            }));
+        }
+    }
+
+    // the slice history goes last, a slice without it is never loaded
+    pub async fn dump(self, conn: &mut ConnectionType) -> SimpleResult {
+        log::info!("persisting orders and balances to db");
+        let slice_id = self.slice_id();
+        let insert_count = dump_records(self.orders.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} orders done", insert_count);
+        let balances_iter = self.balances.iter().map(|(k, v)| BalanceSliceInsert {
+            slice_id,
+            user_id: k.user_id.to_string(),
+            asset: k.asset.clone(),
+            t: k.balance_type as i16,
+            balance: *v,
+        });
+        let insert_count = dump_records(balances_iter, DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} balances done", insert_count);
+        let insert_count = dump_records(self.mmps.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} mmp configs done", insert_count);
+        let insert_count = dump_records(self.sub_accounts.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} sub-accounts done", insert_count);
+        let insert_count = dump_records(self.business_ids.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} business ids done", insert_count);
+        let insert_count = dump_records(self.asset_ledgers.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} asset ledgers done", insert_count);
+        let insert_count = dump_records(self.withdraw_quotas.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} withdraw quotas done", insert_count);
+        let insert_count = dump_records(self.withdraw_usages.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} withdraw usages done", insert_count);
+        let insert_count = dump_records(self.account_locks.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} account locks done", insert_count);
+        let insert_count = dump_records(self.vestings.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} vestings done", insert_count);
+        let insert_count = dump_records(self.deposits.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} pending deposits done", insert_count);
+        let insert_count = dump_records(self.withdraws.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+        log::debug!("persist {} pending withdraws done", insert_count);
+        self.slice_history.sql_query(conn).await?;
+        Ok(())
+    }
+
+    // load the snapshot into a controller with fresh state, returns the last operation log id it covers
+    pub fn restore(self, controller: &mut Controller) -> anyhow::Result<u64> {
+        for (key, amount) in self.balances.iter() {
+            controller.balance_manager.set_by_key(key.clone(), amount);
+        }
+        for order in &self.orders {
+            let market = match controller.markets.get_mut(&order.market) {
+                Some(market) => market,
+                None => bail!("unknown market {} in slice", order.market),
+            };
+            let order = Order {
+                id: order.id as u64,
+                type_: order.order_type,
+                side: order.order_side,
+                create_time: FTimestamp::from(&order.create_time).0,
+                update_time: FTimestamp::from(&order.update_time).0,
+                market: market.name.into(),
+                base: market.base.into(),
+                quote: market.quote.into(),
+                user: order.user_id.parse()?,
+                price: order.price,
+                amount: order.amount,
+                taker_fee: order.taker_fee,
+                maker_fee: order.maker_fee,
+                remain: order.remain,
+                frozen: order.frozen,
+                finished_base: order.finished_base,
+                finished_quote: order.finished_quote,
+                finished_fee: order.finished_fee,
+                post_only: order.post_only,
+                trailing_stop: match (order.trail_type, order.trail_value, order.trigger_price) {
+                    (Some(trail_type), Some(trail_value), Some(trigger_price)) => Some(TrailingStop {
+                        trail_type,
+                        trail_value,
+                        trigger_price,
+                    }),
+                    _ => None,
+                },
+                peg: match (order.peg_reference, order.peg_offset, order.peg_limit) {
+                    (Some(reference), Some(offset), Some(limit_price)) => Some(Peg {
+                        reference,
+                        offset,
+                        limit_price,
+                    }),
+                    _ => None,
+                },
+            };
+            if order.is_pending_stop() {
+                market.insert_stop_order(order);
+            } else {
+                market.insert_order_into_orderbook(order);
+            }
+        }
+        for mmp in self.mmps {
+            let market = match controller.markets.get_mut(&mmp.market) {
+                Some(market) => market,
+                None => bail!("unknown market {} in slice", mmp.market),
+            };
+            let user_id = mmp.user_id.parse()?;
+            market.set_mmp(
+                user_id,
+                Some(MmpConfig {
+                    qty_limit: mmp.qty_limit,
+                    trade_count_limit: mmp.trade_count_limit as u32,
+                    window: mmp.time_window,
+                }),
+            );
+            market.mmp.get_mut(&user_id).unwrap().triggered = mmp.triggered;
+        }
+        for sub_account in self.sub_accounts {
+            controller
+                .sub_account_manager
+                .accounts
+                .entry(sub_account.owner.parse()?)
+                .or_default()
+                .insert(sub_account.name, sub_account.account_id.parse()?);
+        }
+        // business ids must come oldest first
+        for business_id in self.business_ids {
+            controller.update_controller.restore_applied(
+                asset::BalanceUpdateKey {
+                    user_id: business_id.user_id.parse()?,
+                    asset: business_id.asset,
+                    business: business_id.business,
+                    business_id: business_id.business_id as u64,
+                },
+                FTimestamp::from(&business_id.time).0,
+            );
+        }
+        // the DEPOSITING, WITHDRAWING and LOCKED balances come with the balances
+        for deposit in self.deposits {
+            let business_id = deposit.business_id as u64;
+            controller.update_controller.pending_deposits.insert(
+                (deposit.business.clone(), business_id),
+                asset::PendingDeposit {
+                    user_id: deposit.user_id.parse()?,
+                    asset: deposit.asset,
+                    business: deposit.business,
+                    business_id,
+                    market_price: deposit.market_price,
+                    amount: deposit.amount,
+                    confirmations: deposit.confirmations as u32,
+                    create_time: FTimestamp::from(&deposit.create_time).0,
+                    detail: serde_json::from_str(&deposit.detail)?,
+                },
+            );
+        }
+        for withdraw in self.withdraws {
+            let business_id = withdraw.business_id as u64;
+            controller.update_controller.pending_withdraws.insert(
+                (withdraw.business.clone(), business_id),
+                asset::PendingWithdraw {
+                    user_id: withdraw.user_id.parse()?,
+                    asset: withdraw.asset,
+                    business: withdraw.business,
+                    business_id,
+                    market_price: withdraw.market_price,
+                    amount: withdraw.amount,
+                    create_time: FTimestamp::from(&withdraw.create_time).0,
+                    detail: serde_json::from_str(&withdraw.detail)?,
+                },
+            );
+        }
+        for vesting in self.vestings {
+            let business_id = vesting.business_id as u64;
+            controller.update_controller.vestings.insert(
+                (vesting.business.clone(), business_id),
+                asset::Vesting {
+                    user_id: vesting.user_id.parse()?,
+                    asset: vesting.asset,
+                    business: vesting.business,
+                    business_id,
+                    total: vesting.total,
+                    released: vesting.released,
+                    schedule: serde_json::from_str(&vesting.schedule)?,
+                    detail: serde_json::from_str(&vesting.detail)?,
+                },
+            );
+        }
+        for quota in self.withdraw_quotas {
+            controller
+                .withdraw_quota_manager
+                .set_override(quota.user_id.parse()?, &quota.asset, Some(quota.quota));
+        }
+        for usage in self.withdraw_usages {
+            controller
+                .withdraw_quota_manager
+                .record(usage.user_id.parse()?, &usage.asset, FTimestamp::from(&usage.time).0, usage.amount);
+        }
+        for lock in self.account_locks {
+            controller
+                .account_lock_manager
+                .lock(lock.user_id.parse()?, &lock.asset, &lock.reason);
+        }
+        if self.asset_ledgers.is_empty() {
+            // slices made before the ledger existed, take the current holdings as opening deposits
+            for (key, amount) in controller.balance_manager.balances.iter() {
+                if key.balance_type != asset::BalanceType::DEPOSITING {
+                    controller.update_controller.ledger.entry(key.asset.clone()).or_default().deposit += amount;
+                }
+            }
+        }
+        for ledger in self.asset_ledgers {
+            controller.update_controller.ledger.insert(
+                ledger.asset,
+                asset::AssetLedger {
+                    deposit: ledger.deposit,
+                    withdraw: ledger.withdraw,
+                    fee: ledger.fee,
+                },
+            );
+        }
+
+        let slice = self.slice_history;
+        controller.sequencer.set_order_id(slice.end_order_id as u64);
+        controller.sequencer.set_trade_id(slice.end_trade_id as u64);
+        log::info!("set order_id and trade_id to {} {}", slice.end_order_id, slice.end_trade_id);
+        Ok(slice.end_operation_log_id as u64)
+    }
+}
+
+pub async fn make_slice_from_snapshot(mut snapshot: StateSnapshot, settings: &config::Settings) -> SimpleResult {
+    let slice_id = snapshot.slice_id();
+    let end_operation_log_id = snapshot.slice_history.end_operation_log_id as u64;
+    let timing = Instant::now();
+    snapshot.fill_orders();
+    snapshot.slice_history.state_hash = Some(StateHash::of(&snapshot).root_hex());
+    let mut conn = ConnectionType::connect(&settings.db_log).await?;
+    match settings.slice_storage {
+        config::SliceStorage::Db => {
+            snapshot.dump(&mut conn).await?;
+            apply_retention(&mut conn, settings, false).await?;
+        }
+        config::SliceStorage::File => snapshot.write_file(&settings.slice_file)?,
+    }
+    truncate_wal(&mut conn, settings, end_operation_log_id).await?;
+    log::info!(
+        "make slice done, slice_id {}, use {} secs",
+        slice_id,
+        timing.elapsed().as_secs_f32()
+    );
+    Ok(())
+}
+
+static SLICE_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+// Takes the snapshot right away and writes it from a background task, must be called inside
+// the tokio runtime. A slice still being written from the last call makes this one a no-op.
+pub fn spawn_make_slice(controller: &Controller) {
+    if SLICE_IN_PROGRESS.swap(true, Ordering::AcqRel) {
+        log::warn!("last slice is still being written, skip this one");
+        return;
+    }
+    let timing = Instant::now();
+    let snapshot = StateSnapshot::capture(current_timestamp() as i64, controller);
+    // the engine serves no write while it is taken
+    log::info!("take snapshot, paused {} secs", timing.elapsed().as_secs_f32());
+    let settings = controller.settings.clone();
+    tokio::spawn(async move {
+        if let Err(e) = make_slice_from_snapshot(snapshot, &settings).await {
+            log::error!("make slice fail: {:?}", e);
+        }
+        SLICE_IN_PROGRESS.store(false, Ordering::Release);
+    });
+}
@@ -0,0 +1,485 @@
+use super::snapshot::StateSnapshot;
+use crate::asset::{BalanceMapKey, BalanceType};
+use crate::models::{
+    AccountLockSlice, AssetLedgerSlice, BusinessIdSlice, DepositSlice, MmpSlice, OrderSlice, SliceHistory, SubAccountSlice,
+    TimestampDbType, VestingSlice, WithdrawQuotaSlice, WithdrawSlice, WithdrawUsageSlice,
+};
+use crate::types::{OrderSide, OrderType, PegReference, TrailingType};
+use anyhow::{bail, Result};
+use fluidex_common::rust_decimal::Decimal;
+use std::io::Write;
+use uuid::Uuid;
+
+// A snapshot file is a header followed by the content, all integers are little endian:
+//   magic "DINGIRSS" | version u32 | content length u64 | crc32 of the content u32 | content
+// The content is the slice history, the market and asset config as json, then one section per
+// slice table, each a u64 record count followed by the records field by field.
+const MAGIC: &[u8; 8] = b"DINGIRSS";
+pub const SNAPSHOT_FILE_VERSION: u32 = 1;
+const HEADER_LEN: usize = 24;
+
+#[derive(Debug)]
+pub struct SnapshotFileHeader {
+    pub version: u32,
+    pub content_len: u64,
+    pub checksum: u32,
+}
+
+impl SnapshotFileHeader {
+    // checks the header and the checksum, and returns the content
+    pub fn parse(bytes: &[u8]) -> Result<(Self, &[u8])> {
+        if bytes.len() < HEADER_LEN || &bytes[..8] != MAGIC {
+            bail!("not a snapshot file");
+        }
+        let mut buf = &bytes[8..HEADER_LEN];
+        let header = Self {
+            version: u32::get(&mut buf)?,
+            content_len: u64::get(&mut buf)?,
+            checksum: u32::get(&mut buf)?,
+        };
+        if header.version != SNAPSHOT_FILE_VERSION {
+            bail!("unsupported snapshot file version {}", header.version);
+        }
+        let content = &bytes[HEADER_LEN..];
+        if content.len() as u64 != header.content_len {
+            bail!("snapshot file truncated, {} of {} bytes", content.len(), header.content_len);
+        }
+        if crc32fast::hash(content) != header.checksum {
+            bail!("snapshot file checksum mismatch");
+        }
+        Ok((header, content))
+    }
+}
+
+pub(super) trait Field: Sized {
+    fn put(&self, buf: &mut Vec<u8>);
+    fn get(buf: &mut &[u8]) -> Result<Self>;
+}
+
+pub(super) fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if buf.len() < n {
+        bail!("snapshot content ends early");
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+macro_rules! number_field {
+    ($($t:ty),*) => {
+        $(impl Field for $t {
+            fn put(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_le_bytes());
+            }
+            fn get(buf: &mut &[u8]) -> Result<Self> {
+                Ok(<$t>::from_le_bytes(take(buf, std::mem::size_of::<$t>())?.try_into().unwrap()))
+            }
+        })*
+    };
+}
+
+number_field!(u8, i16, i32, u32, i64, u64, f64);
+
+macro_rules! enum_field {
+    ($t:ident { $($v:ident = $n:literal),* $(,)? }) => {
+        impl Field for $t {
+            fn put(&self, buf: &mut Vec<u8>) {
+                let n: u8 = match self {
+                    $($t::$v => $n,)*
+                };
+                buf.push(n);
+            }
+            fn get(buf: &mut &[u8]) -> Result<Self> {
+                match u8::get(buf)? {
+                    $($n => Ok($t::$v),)*
+                    n => bail!("invalid {} {}", stringify!($t), n),
+                }
+            }
+        }
+    };
+}
+
+enum_field!(OrderType { LIMIT = 0, MARKET = 1 });
+enum_field!(OrderSide { ASK = 0, BID = 1 });
+enum_field!(TrailingType { OFFSET = 0, PERCENTAGE = 1 });
+enum_field!(PegReference {
+    SAMESIDE = 0,
+    OPPOSITE = 1,
+    MID = 2
+});
+enum_field!(BalanceType {
+    AVAILABLE = 1,
+    FREEZE = 2,
+    WITHDRAWING = 3,
+    DEPOSITING = 4,
+    LOCKED = 5
+});
+
+// the fields are written and read back in the order they are listed
+macro_rules! record_field {
+    ($t:ident { $($f:ident),* $(,)? }) => {
+        impl Field for $t {
+            fn put(&self, buf: &mut Vec<u8>) {
+                $(self.$f.put(buf);)*
+            }
+            fn get(buf: &mut &[u8]) -> Result<Self> {
+                Ok($t {
+                    $($f: Field::get(buf)?,)*
+                })
+            }
+        }
+    };
+}
+
+record_field!(SliceHistory {
+    time,
+    end_operation_log_id,
+    end_order_id,
+    end_trade_id,
+    state_hash
+});
+record_field!(BalanceMapKey {
+    user_id,
+    balance_type,
+    asset
+});
+record_field!(OrderSlice {
+    id,
+    slice_id,
+    order_type,
+    order_side,
+    create_time,
+    update_time,
+    user_id,
+    market,
+    price,
+    amount,
+    taker_fee,
+    maker_fee,
+    remain,
+    frozen,
+    finished_base,
+    finished_quote,
+    finished_fee,
+    post_only,
+    trail_type,
+    trail_value,
+    trigger_price,
+    peg_reference,
+    peg_offset,
+    peg_limit,
+});
+record_field!(MmpSlice {
+    slice_id,
+    user_id,
+    market,
+    qty_limit,
+    trade_count_limit,
+    time_window,
+    triggered
+});
+record_field!(SubAccountSlice {
+    slice_id,
+    owner,
+    name,
+    account_id
+});
+record_field!(BusinessIdSlice {
+    slice_id,
+    user_id,
+    asset,
+    business,
+    business_id,
+    time
+});
+record_field!(AssetLedgerSlice {
+    slice_id,
+    asset,
+    deposit,
+    withdraw,
+    fee
+});
+record_field!(WithdrawQuotaSlice {
+    slice_id,
+    user_id,
+    asset,
+    quota
+});
+record_field!(WithdrawUsageSlice {
+    slice_id,
+    user_id,
+    asset,
+    time,
+    amount
+});
+record_field!(AccountLockSlice {
+    slice_id,
+    user_id,
+    asset,
+    reason
+});
+record_field!(VestingSlice {
+    slice_id,
+    user_id,
+    asset,
+    business,
+    business_id,
+    total,
+    released,
+    schedule,
+    detail,
+});
+record_field!(DepositSlice {
+    slice_id,
+    user_id,
+    asset,
+    business,
+    business_id,
+    market_price,
+    amount,
+    confirmations,
+    create_time,
+    detail,
+});
+record_field!(WithdrawSlice {
+    slice_id,
+    user_id,
+    asset,
+    business,
+    business_id,
+    market_price,
+    amount,
+    create_time,
+    detail,
+});
+
+impl Field for bool {
+    fn put(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+    fn get(buf: &mut &[u8]) -> Result<Self> {
+        Ok(u8::get(buf)? != 0)
+    }
+}
+
+impl Field for String {
+    fn put(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).put(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+    fn get(buf: &mut &[u8]) -> Result<Self> {
+        let len = u64::get(buf)? as usize;
+        Ok(String::from_utf8(take(buf, len)?.to_vec())?)
+    }
+}
+
+impl Field for Uuid {
+    fn put(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+    fn get(buf: &mut &[u8]) -> Result<Self> {
+        Ok(Uuid::from_slice(take(buf, 16)?)?)
+    }
+}
+
+impl Field for Decimal {
+    fn put(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.serialize());
+    }
+    fn get(buf: &mut &[u8]) -> Result<Self> {
+        Ok(Decimal::deserialize(take(buf, 16)?.try_into().unwrap()))
+    }
+}
+
+impl Field for TimestampDbType {
+    fn put(&self, buf: &mut Vec<u8>) {
+        self.timestamp().put(buf);
+        self.timestamp_subsec_nanos().put(buf);
+    }
+    fn get(buf: &mut &[u8]) -> Result<Self> {
+        let secs = i64::get(buf)?;
+        let nanos = u32::get(buf)?;
+        match TimestampDbType::from_timestamp_opt(secs, nanos) {
+            Some(time) => Ok(time),
+            None => bail!("invalid timestamp {}.{}", secs, nanos),
+        }
+    }
+}
+
+impl<T: Field> Field for Option<T> {
+    fn put(&self, buf: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                buf.push(1);
+                value.put(buf);
+            }
+            None => buf.push(0),
+        }
+    }
+    fn get(buf: &mut &[u8]) -> Result<Self> {
+        match u8::get(buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(T::get(buf)?)),
+        }
+    }
+}
+
+impl<T: Field> Field for Vec<T> {
+    fn put(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).put(buf);
+        for item in self {
+            item.put(buf);
+        }
+    }
+    fn get(buf: &mut &[u8]) -> Result<Self> {
+        let len = u64::get(buf)? as usize;
+        // the count comes from the file, do not let it reserve more than the content can hold
+        let mut items = Vec::with_capacity(len.min(buf.len()));
+        for _ in 0..len {
+            items.push(T::get(buf)?);
+        }
+        Ok(items)
+    }
+}
+
+impl StateSnapshot {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.slice_history.put(&mut content);
+        serde_json::to_string(&self.markets)?.put(&mut content);
+        serde_json::to_string(&self.assets)?.put(&mut content);
+        (self.balances.len() as u64).put(&mut content);
+        for (key, amount) in self.balances.iter() {
+            key.put(&mut content);
+            amount.put(&mut content);
+        }
+        self.orders.put(&mut content);
+        self.mmps.put(&mut content);
+        self.sub_accounts.put(&mut content);
+        self.business_ids.put(&mut content);
+        self.asset_ledgers.put(&mut content);
+        self.withdraw_quotas.put(&mut content);
+        self.withdraw_usages.put(&mut content);
+        self.account_locks.put(&mut content);
+        self.vestings.put(&mut content);
+        self.deposits.put(&mut content);
+        self.withdraws.put(&mut content);
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + content.len());
+        bytes.extend_from_slice(MAGIC);
+        SNAPSHOT_FILE_VERSION.put(&mut bytes);
+        (content.len() as u64).put(&mut bytes);
+        crc32fast::hash(&content).put(&mut bytes);
+        bytes.extend_from_slice(&content);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let (_, mut buf) = SnapshotFileHeader::parse(bytes)?;
+        let buf = &mut buf;
+        let mut snapshot = StateSnapshot::new(SliceHistory::get(buf)?);
+        snapshot.markets = serde_json::from_str(&String::get(buf)?)?;
+        snapshot.assets = serde_json::from_str(&String::get(buf)?)?;
+        for _ in 0..u64::get(buf)? {
+            let key = BalanceMapKey::get(buf)?;
+            snapshot.balances.insert(key, Decimal::get(buf)?);
+        }
+        snapshot.orders = Field::get(buf)?;
+        snapshot.mmps = Field::get(buf)?;
+        snapshot.sub_accounts = Field::get(buf)?;
+        snapshot.business_ids = Field::get(buf)?;
+        snapshot.asset_ledgers = Field::get(buf)?;
+        snapshot.withdraw_quotas = Field::get(buf)?;
+        snapshot.withdraw_usages = Field::get(buf)?;
+        snapshot.account_locks = Field::get(buf)?;
+        snapshot.vestings = Field::get(buf)?;
+        snapshot.deposits = Field::get(buf)?;
+        snapshot.withdraws = Field::get(buf)?;
+        if !buf.is_empty() {
+            bail!("{} bytes left after the snapshot content", buf.len());
+        }
+        Ok(snapshot)
+    }
+
+    // written aside and renamed, so a crash never leaves a half written file at the path
+    pub fn write_file(&self, path: &str) -> Result<()> {
+        let bytes = self.encode()?;
+        let tmp_path = format!("{}.tmp", path);
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        log::info!("slice {} written to {}, {} bytes", self.slice_id(), path, bytes.len());
+        Ok(())
+    }
+
+    pub fn read_file(path: &str) -> Result<Self> {
+        Self::decode(&std::fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot_file() {
+    use fluidex_common::rust_decimal_macros::*;
+    use std::str::FromStr;
+
+    let alice = Uuid::from_str("0f0e0d0c-0b0a-4908-8706-050403020100").unwrap();
+    let time = TimestampDbType::from_timestamp(1_600_000_000, 500_000_000);
+    let mut snapshot = StateSnapshot::new(SliceHistory {
+        time: 1_600_000_000,
+        end_operation_log_id: 42,
+        end_order_id: 7,
+        end_trade_id: 3,
+        state_hash: Some("00".repeat(32)),
+    });
+    snapshot.balances.insert(
+        BalanceMapKey {
+            user_id: alice,
+            balance_type: BalanceType::FREEZE,
+            asset: "ETH".to_string(),
+        },
+        dec!(1.25),
+    );
+    snapshot.orders.push(OrderSlice {
+        id: 7,
+        slice_id: 1_600_000_000,
+        order_type: OrderType::LIMIT,
+        order_side: OrderSide::ASK,
+        create_time: time,
+        update_time: time,
+        user_id: alice.to_string(),
+        market: "ETH_USDT".to_string(),
+        price: dec!(1000),
+        amount: dec!(1.25),
+        taker_fee: dec!(0.001),
+        maker_fee: dec!(0.001),
+        remain: dec!(1.25),
+        frozen: dec!(1.25),
+        finished_base: dec!(0),
+        finished_quote: dec!(0),
+        finished_fee: dec!(0),
+        post_only: false,
+        trail_type: None,
+        trail_value: None,
+        trigger_price: None,
+        peg_reference: Some(PegReference::MID),
+        peg_offset: Some(dec!(-1)),
+        peg_limit: Some(dec!(900)),
+    });
+
+    let bytes = snapshot.encode().unwrap();
+    let decoded = StateSnapshot::decode(&bytes).unwrap();
+    assert_eq!(decoded.slice_history.end_operation_log_id, 42);
+    assert_eq!(decoded.slice_history.state_hash, snapshot.slice_history.state_hash);
+    assert_eq!(decoded.balances, snapshot.balances);
+    assert_eq!(decoded.orders.len(), 1);
+    assert_eq!(decoded.orders[0].create_time, time);
+    assert_eq!(decoded.orders[0].peg_reference, Some(PegReference::MID));
+    assert_eq!(decoded.orders[0].peg_offset, Some(dec!(-1)));
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert!(StateSnapshot::decode(&corrupted).is_err());
+    assert!(StateSnapshot::decode(&bytes[..bytes.len() - 1]).is_err());
+}
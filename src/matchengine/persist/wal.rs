@@ -0,0 +1,244 @@
+use super::snapshot_file::{take, Field};
+use crate::models::{OperationLog, TimestampDbType};
+use anyhow::{bail, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// The write-ahead log keeps the operation log on local disk, every record is synced before the
+// operation is answered. It is a directory of segments, each named after the id of its first
+// record, and each record is framed as:
+//   payload length u32 | crc32 of the payload u32 | payload
+// with the payload holding the operation log fields in the snapshot file encoding. The leader
+// epoch is the last field, records written before it had one have none.
+const SEGMENT_SUFFIX: &str = "wal";
+const FRAME_LEN: usize = 8;
+
+pub struct OperationWal {
+    dir: PathBuf,
+    segment_size: u64,
+    file: File,
+    written: u64,
+}
+
+impl OperationWal {
+    // appends to the last segment, or starts the first one at `next_id`
+    pub fn open(dir: &str, segment_size: u64, next_id: u64) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = match segments(dir)?.pop() {
+            Some((_, path)) => path,
+            None => segment_path(dir, next_id),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            segment_size,
+            file,
+            written,
+        })
+    }
+
+    pub fn append(&mut self, log: &OperationLog) -> Result<()> {
+        if self.written >= self.segment_size {
+            self.rotate(log.id as u64)?;
+        }
+        let record = encode_record(log);
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.written += record.len() as u64;
+        Ok(())
+    }
+
+    // drops every segment and starts over at `next_id`, used when the db is reset as well
+    pub fn reset(&mut self, next_id: u64) -> Result<()> {
+        for (_, path) in segments(&self.dir)? {
+            fs::remove_file(path)?;
+        }
+        self.rotate(next_id)
+    }
+
+    fn rotate(&mut self, next_id: u64) -> Result<()> {
+        let path = segment_path(&self.dir, next_id);
+        self.file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.written = 0;
+        // make the new segment itself durable
+        File::open(&self.dir)?.sync_all()?;
+        log::info!("wal segment {} started", path.display());
+        Ok(())
+    }
+}
+
+// the leader epoch the engine writing the wal took over in, kept next to the segments
+const EPOCH_FILE: &str = "epoch";
+
+pub fn write_epoch<P: AsRef<Path>>(dir: P, epoch: i64) -> Result<()> {
+    fs::create_dir_all(&dir)?;
+    let tmp = dir.as_ref().join(format!("{}.tmp", EPOCH_FILE));
+    let mut file = File::create(&tmp)?;
+    file.write_all(epoch.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.as_ref().join(EPOCH_FILE))?;
+    File::open(dir.as_ref())?.sync_all()?;
+    Ok(())
+}
+
+// none when the engine never led with this wal
+pub fn read_epoch<P: AsRef<Path>>(dir: P) -> Result<Option<i64>> {
+    let path = dir.as_ref().join(EPOCH_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(path)?.trim().parse()?))
+}
+
+fn segment_path<P: AsRef<Path>>(dir: P, first_id: u64) -> PathBuf {
+    dir.as_ref().join(format!("{:020}.{}", first_id, SEGMENT_SUFFIX))
+}
+
+// the segments in the directory with the id of their first record, oldest first
+pub fn segments<P: AsRef<Path>>(dir: P) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    if !dir.as_ref().exists() {
+        return Ok(segments);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |ext| ext != SEGMENT_SUFFIX) {
+            continue;
+        }
+        if let Some(first_id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+            segments.push((first_id, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// Reads the records after `operation_log_id`. A record cut short or failing its checksum at the
+// end of the last segment was never answered, it is cut off the file. Anywhere else it is an error.
+pub fn read_after<P: AsRef<Path>>(dir: P, operation_log_id: u64) -> Result<Vec<OperationLog>> {
+    let segments = segments(dir)?;
+    let mut logs = Vec::new();
+    for (i, (_, path)) in segments.iter().enumerate() {
+        let is_last = i + 1 == segments.len();
+        if !is_last && segments[i + 1].0 <= operation_log_id + 1 {
+            continue;
+        }
+        let bytes = fs::read(path)?;
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match decode_record(&bytes[offset..]) {
+                Ok((log, len)) => {
+                    if log.id as u64 > operation_log_id {
+                        logs.push(log);
+                    }
+                    offset += len;
+                }
+                Err(e) if is_last => {
+                    log::warn!("cut torn wal tail of {} at {}: {}", path.display(), offset, e);
+                    let file = OpenOptions::new().write(true).open(path)?;
+                    file.set_len(offset as u64)?;
+                    file.sync_all()?;
+                    break;
+                }
+                Err(e) => bail!("wal segment {} corrupted at {}: {}", path.display(), offset, e),
+            }
+        }
+    }
+    Ok(logs)
+}
+
+// Deletes the segments whose records all have an id up to `operation_log_id`. The last segment
+// is never deleted, it is the one being written.
+pub fn truncate<P: AsRef<Path>>(dir: P, operation_log_id: u64) -> Result<usize> {
+    let segments = segments(dir)?;
+    let mut deleted = 0;
+    for pair in segments.windows(2) {
+        if pair[1].0 > operation_log_id + 1 {
+            break;
+        }
+        fs::remove_file(&pair[0].1)?;
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+fn encode_record(log: &OperationLog) -> Vec<u8> {
+    let mut payload = Vec::new();
+    log.id.put(&mut payload);
+    log.time.put(&mut payload);
+    log.user_id.put(&mut payload);
+    log.method.put(&mut payload);
+    log.params.put(&mut payload);
+    log.inputs.put(&mut payload);
+    log.epoch.put(&mut payload);
+    let mut record = Vec::with_capacity(FRAME_LEN + payload.len());
+    (payload.len() as u32).put(&mut record);
+    crc32fast::hash(&payload).put(&mut record);
+    record.extend_from_slice(&payload);
+    record
+}
+
+// the record and the number of bytes it takes
+fn decode_record(bytes: &[u8]) -> Result<(OperationLog, usize)> {
+    let mut buf = bytes;
+    let len = u32::get(&mut buf)? as usize;
+    let checksum = u32::get(&mut buf)?;
+    let mut payload = take(&mut buf, len)?;
+    if crc32fast::hash(payload) != checksum {
+        bail!("checksum mismatch");
+    }
+    let log = OperationLog {
+        id: i64::get(&mut payload)?,
+        time: TimestampDbType::get(&mut payload)?,
+        user_id: String::get(&mut payload)?,
+        method: String::get(&mut payload)?,
+        params: String::get(&mut payload)?,
+        inputs: String::get(&mut payload)?,
+        epoch: if payload.is_empty() { 0 } else { i64::get(&mut payload)? },
+    };
+    Ok((log, FRAME_LEN + len))
+}
+
+#[cfg(test)]
+#[test]
+fn test_operation_wal() {
+    let dir = std::env::temp_dir().join(format!("dingir_wal_test_{}", std::process::id()));
+    let dir_str = dir.to_str().unwrap();
+    let log = |id: i64| OperationLog {
+        id,
+        user_id: "0f0e0d0c-0b0a-4908-8706-050403020100".to_string(),
+        time: TimestampDbType::from_timestamp_opt(1600000000 + id, 0).unwrap(),
+        method: "balance_update".to_string(),
+        params: format!("{{\"business_id\":{}}}", id),
+        inputs: format!("{{\"time\":{}}}", 1600000000 + id),
+        epoch: 1,
+    };
+    // one record per segment
+    let mut wal = OperationWal::open(dir_str, 1, 1).unwrap();
+    for id in 1..=3 {
+        wal.append(&log(id)).unwrap();
+    }
+    assert_eq!(segments(&dir).unwrap().len(), 3);
+    let ids: Vec<i64> = read_after(&dir, 1).unwrap().iter().map(|log| log.id).collect();
+    assert_eq!(ids, vec![2, 3]);
+
+    // a record half written into the last segment is dropped
+    let (_, last) = segments(&dir).unwrap().pop().unwrap();
+    let mut file = OpenOptions::new().append(true).open(&last).unwrap();
+    file.write_all(&encode_record(&log(4))[..10]).unwrap();
+    assert_eq!(read_after(&dir, 0).unwrap().len(), 3);
+    assert_eq!(fs::metadata(&last).unwrap().len(), encode_record(&log(3)).len() as u64);
+
+    assert_eq!(truncate(&dir, 2).unwrap(), 2);
+    assert_eq!(read_after(&dir, 2).unwrap()[0].params, log(3).params);
+    assert_eq!(read_after(&dir, 2).unwrap()[0].epoch, 1);
+
+    assert_eq!(read_epoch(&dir).unwrap(), None);
+    write_epoch(&dir, 3).unwrap();
+    assert_eq!(read_epoch(&dir).unwrap(), Some(3));
+    // not a segment
+    assert_eq!(segments(&dir).unwrap().len(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,4 +1,4 @@
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Sequencer {
     order_id: u64,
     trade_id: u64,
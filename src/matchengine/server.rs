@@ -1,5 +1,6 @@
-use crate::config::Settings;
+use crate::config::{Role, Settings, SliceMode};
 use crate::controller::Controller;
+use crate::types::ConnectionType;
 
 use fluidex_common::rust_decimal::Decimal;
 use std::fmt::Debug;
@@ -9,6 +10,7 @@ use std::sync::Arc;
 
 use crate::matchengine::authentication::UserExtension;
 use orchestra::rpc::exchange::*;
+use sqlx::Connection;
 use tokio::sync::{mpsc, oneshot, RwLock};
 use tonic::{self, Request, Response, Status};
 use uuid::Uuid;
@@ -74,9 +76,40 @@ impl ServerLeave {
     }
 }
 
+// applies the operation logs the leader wrote since the last poll, connecting first if needed
+async fn follow(stub: &mut Controller, conn: &mut Option<ConnectionType>) -> anyhow::Result<()> {
+    if conn.is_none() {
+        *conn = Some(ConnectionType::connect(&stub.settings.db_log).await?);
+    }
+    let applied = crate::persist::follow_operation_log(conn.as_mut().unwrap(), stub).await?;
+    if applied > 0 {
+        log::debug!("{} operation logs applied, at {}", applied, stub.sequencer.get_operation_log_id());
+    }
+    Ok(())
+}
+
+// the leader makes the slices, returns false on a follower
+fn make_slice(stub: &Controller) -> bool {
+    if stub.role == Role::Follower {
+        return false;
+    }
+    log::info!("Start a persisting task");
+    match stub.settings.slice_mode {
+        SliceMode::Fork => unsafe {
+            crate::persist::fork_and_make_slice(stub);
+        },
+        SliceMode::Snapshot => crate::persist::spawn_make_slice(stub),
+    }
+    true
+}
+
 impl GrpcHandler {
     pub fn new(stub: Controller, settings: Settings) -> Self {
         let mut persist_interval = tokio::time::interval(std::time::Duration::from_secs(stub.settings.persist_interval as u64));
+        let mut vesting_interval = tokio::time::interval(std::time::Duration::from_secs(stub.settings.vesting_release_interval as u64));
+        let mut follow_interval = tokio::time::interval(std::time::Duration::from_secs_f64(stub.settings.follow_interval));
+
+        let audit_after_slice = stub.settings.audit_after_slice;
 
         let stub = Arc::new(RwLock::new(stub));
         //we always wait so the size of channel is no matter
@@ -94,6 +127,8 @@ impl GrpcHandler {
 
         tokio::spawn(async move {
             persist_interval.tick().await; //skip first tick
+            let mut follow_conn = None;
+            let mut outbox_cursor = 0;
             loop {
                 tokio::select! {
                     may_task = rx.recv() => {
@@ -101,14 +136,51 @@ impl GrpcHandler {
                         task(stub_for_dispatch.clone()).await;
                     }
                     _ = persist_interval.tick() => {
-                        let stub_rd = stub_for_dispatch.read().await;
-                        log::info!("Start a persisting task");
-                        unsafe {
-                            crate::persist::fork_and_make_slice(&*stub_rd);
+                        if audit_after_slice {
+                            // the slice is written elsewhere and has no message producer, audit the same state here
+                            let mut stub_wr = stub_for_dispatch.write().await;
+                            if make_slice(&*stub_wr) {
+                                stub_wr.audit();
+                            }
+                        } else {
+                            make_slice(&*stub_for_dispatch.read().await);
+                        }
+                    }
+                    _ = vesting_interval.tick() => {
+                        let mut stub_wr = stub_for_dispatch.write().await;
+                        if stub_wr.role == Role::Leader {
+                            stub_wr.release_vestings();
+                        }
+                    }
+                    _ = follow_interval.tick() => {
+                        let mut stub_wr = stub_for_dispatch.write().await;
+                        match stub_wr.role {
+                            Role::Follower => {
+                                if let Err(e) = follow(&mut stub_wr, &mut follow_conn).await {
+                                    log::error!("following the operation log failed: {}", e);
+                                    follow_conn = None;
+                                }
+                            }
+                            Role::Leader => {
+                                if let Err(e) = stub_wr.renew_leader_lease().await {
+                                    // another engine may be the leader already, writing on would fork the log
+                                    log::error!("leader lock lost: {}", e);
+                                    std::process::abort();
+                                }
+                                if let Some(operation_log_id) = stub_wr.persistor.delivered_operation_log_id().filter(|id| *id > outbox_cursor) {
+                                    match stub_wr.save_outbox_cursor(operation_log_id).await {
+                                        Ok(()) => outbox_cursor = operation_log_id,
+                                        Err(e) => log::error!("saving the outbox cursor failed: {}", e),
+                                    }
+                                }
+                            }
                         }
                     }
                     _ = &mut rx_close => {
                         log::info!("Server scheduler is notified to close");
+                        if let Err(e) = stub_for_dispatch.write().await.release_leader_lease().await {
+                            log::error!("releasing the leader lease failed: {}", e);
+                        }
                         rx.close();
                         break;
                     }
@@ -149,6 +221,19 @@ impl matchengine_server::Matchengine for GrpcHandler {
         Ok(Response::new(stub.balance_query(request.into_inner(), user_id)?))
     }
 
+    // users may check their own business ids, balance admins anyone's
+    async fn business_id_query(&self, request: Request<BusinessIdQueryRequest>) -> Result<Response<BusinessIdQueryResponse>, Status> {
+        grpc_block_anonymous(&request)?;
+
+        let caller_id = get_user_id_from_request(&request);
+        let user_id = Uuid::parse_str(&request.get_ref().user_id).map_err(|_| Status::invalid_argument("User id is not a valid UUID"))?;
+        if user_id != caller_id {
+            grpc_block_non_balance_admins(&request)?;
+        }
+        let stub = self.stub.read().await;
+        Ok(Response::new(stub.business_id_query(request.into_inner(), user_id)?))
+    }
+
     async fn order_query(&self, request: tonic::Request<OrderQueryRequest>) -> Result<tonic::Response<OrderQueryResponse>, tonic::Status> {
         grpc_block_anonymous(&request)?;
 
@@ -156,6 +241,13 @@ impl matchengine_server::Matchengine for GrpcHandler {
         let user_id = get_user_id_from_request(&request);
         Ok(Response::new(stub.order_query(request.into_inner(), user_id)?))
     }
+    async fn sub_account_list(&self, request: Request<SubAccountListRequest>) -> Result<Response<SubAccountListResponse>, Status> {
+        grpc_block_anonymous(&request)?;
+
+        let stub = self.stub.read().await;
+        let user_id = get_user_id_from_request(&request);
+        Ok(Response::new(stub.sub_account_list(request.into_inner(), user_id)?))
+    }
     async fn order_book_depth(
         &self,
         request: tonic::Request<OrderBookDepthRequest>,
@@ -198,6 +290,142 @@ impl matchengine_server::Matchengine for GrpcHandler {
         map_dispatch_ret(rt.await)
     }
 
+    async fn deposit_request(&self, request: Request<DepositRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_deposit_admins(&request)?;
+
+        let req = request.into_inner();
+        let user_id = Uuid::parse_str(&req.user_id).map_err(|_| Status::invalid_argument("User id is not a valid UUID"))?;
+        let ControllerDispatch(act, rt) =
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.deposit_request(true, req, user_id) }));
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn deposit_confirm(&self, request: Request<DepositConfirmRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_deposit_admins(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.deposit_confirm(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn deposit_cancel(&self, request: Request<DepositCancelRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_deposit_admins(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.deposit_cancel(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn withdraw_request(&self, request: Request<WithdrawRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_withdrawal_admins(&request)?;
+
+        let req = request.into_inner();
+        let user_id = Uuid::parse_str(&req.user_id).map_err(|_| Status::invalid_argument("User id is not a valid UUID"))?;
+        let ControllerDispatch(act, rt) =
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.withdraw_request(true, req, user_id) }));
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn withdraw_confirm(&self, request: Request<WithdrawConfirmRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_withdrawal_admins(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.withdraw_confirm(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn withdraw_reject(&self, request: Request<WithdrawRejectRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_withdrawal_admins(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.withdraw_reject(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn vesting_create(&self, request: Request<VestingCreateRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_deposit_admins(&request)?;
+
+        let req = request.into_inner();
+        let user_id = Uuid::parse_str(&req.user_id).map_err(|_| Status::invalid_argument("User id is not a valid UUID"))?;
+        let ControllerDispatch(act, rt) =
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.vesting_create(true, req, user_id) }));
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn account_freeze(&self, request: Request<AccountFreezeRequest>) -> Result<Response<AccountFreezeResponse>, Status> {
+        grpc_block_non_admins(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.account_freeze(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn account_unfreeze(&self, request: Request<AccountUnfreezeRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_admins(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.account_unfreeze(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn withdraw_quota_set(&self, request: Request<WithdrawQuotaSetRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_non_admins(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.withdraw_quota_set(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    // users may move their own balance, admins may move anyone's
+    async fn transfer(&self, request: Request<TransferRequest>) -> Result<Response<TransferResponse>, Status> {
+        grpc_block_anonymous(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        if request.get_ref().from != user_id.to_string() {
+            grpc_block_non_admins(&request)?;
+        }
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.transfer(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
     async fn order_put(&self, request: Request<OrderPutRequest>) -> Result<Response<OrderInfo>, Status> {
         grpc_block_anonymous(&request)?;
 
@@ -269,6 +497,119 @@ impl matchengine_server::Matchengine for GrpcHandler {
         map_dispatch_ret(rt.await)
     }
 
+    async fn order_bulk_cancel(
+        &self,
+        request: tonic::Request<OrderBulkCancelRequest>,
+    ) -> Result<tonic::Response<OrderBulkCancelResponse>, tonic::Status> {
+        grpc_block_anonymous(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.order_bulk_cancel(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn sub_account_create(&self, request: Request<SubAccountCreateRequest>) -> Result<Response<SubAccountInfo>, Status> {
+        grpc_block_anonymous(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.sub_account_create(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn mmp_set(&self, request: Request<MmpSetRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_anonymous(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.mmp_set(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn mmp_reset(&self, request: Request<MmpResetRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
+        grpc_block_anonymous(&request)?;
+
+        let user_id = get_user_id_from_request(&request);
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.mmp_reset(true, request.into_inner(), user_id) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn ledger_audit(&self, request: Request<LedgerAuditRequest>) -> Result<Response<LedgerAuditResponse>, Status> {
+        grpc_block_non_admins(&request)?;
+
+        let ControllerDispatch(act, rt) =
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.ledger_audit(request.into_inner()) }));
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn state_hash(&self, request: Request<StateHashRequest>) -> Result<Response<StateHashResponse>, Status> {
+        grpc_block_non_admins(&request)?;
+
+        let ControllerDispatch(act, rt) =
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.state_hash(request.into_inner()) }));
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    // runs on its own connection, the engine is not held while slices are deleted
+    async fn slice_retention(&self, request: Request<SliceRetentionRequest>) -> Result<Response<SliceRetentionResponse>, Status> {
+        grpc_block_non_admins(&request)?;
+
+        let dry_run = request.into_inner().dry_run;
+        let report = async {
+            let mut conn = ConnectionType::connect(&self.settings.db_log).await?;
+            crate::persist::apply_retention(&mut conn, &self.settings, dry_run).await
+        }
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(SliceRetentionResponse {
+            kept: report.kept,
+            deleted: report.deleted,
+            orphan_rows: report.orphan_rows,
+            operation_log_id: report.operation_log_id,
+            archived_operation_logs: report.archived_operation_logs,
+            pruned_operation_logs: report.pruned_operation_logs,
+        }))
+    }
+
+    // makes this follower the leader, once no other engine holds the leader lock
+    async fn promote(&self, request: Request<PromoteRequest>) -> Result<Response<PromoteResponse>, Status> {
+        grpc_block_non_admins(&request)?;
+
+        if self.stub.read().await.role == Role::Leader {
+            return Err(Status::failed_precondition("already the leader"));
+        }
+        let lock = crate::persist::LeaderLock::try_acquire(&self.settings.db_log)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::failed_precondition("another engine holds the leader lock"))?;
+        // the lease of an old leader that lost its lock is over by then, let its last logs land;
+        // the controller goes on following meanwhile
+        tokio::time::sleep(std::time::Duration::from_secs_f64(self.settings.follow_interval * 2.0)).await;
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(ctrl.promote(lock)));
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
     async fn reload_markets(&self, request: Request<ReloadMarketsRequest>) -> Result<Response<SimpleSuccessResponse>, Status> {
         grpc_block_non_admins(&request)?;
 
@@ -386,6 +727,25 @@ fn grpc_block_non_withdrawal_admins<T>(request: &Request<T>) -> Result<(), Statu
     Ok(())
 }
 
+fn grpc_block_non_balance_admins<T>(request: &Request<T>) -> Result<(), Status> {
+    let user_extension: Option<&UserExtension> = request.extensions().get::<UserExtension>();
+
+    match user_extension {
+        Some(user_extension) => {
+            if !(user_extension.is_admin || user_extension.is_deposit_admin || user_extension.is_withdrawal_admin) {
+                log::warn!(
+                    "Reject GRPC call; User {} does not have balance admin rights.",
+                    user_extension.user_id
+                );
+                return Err(Status::permission_denied("Requires admin, deposit-admin or withdrawal-admin role."));
+            }
+        }
+        None => return Err(credentials_missing()),
+    }
+
+    Ok(())
+}
+
 fn grpc_block_anonymous<T>(request: &Request<T>) -> Result<(), Status> {
     if request.extensions().get::<UserExtension>().is_none() {
         return Err(credentials_missing());
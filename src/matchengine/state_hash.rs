@@ -0,0 +1,188 @@
+use crate::models::OrderSlice;
+use crate::persist::StateSnapshot;
+
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::Decimal;
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+
+pub type Hash = [u8; 32];
+
+// Canonical hash of the balances and the order books, equal for two engines holding the same
+// state no matter how they got there. Each part is a merkle root over its records in key order:
+// the balances by (user, asset, type), the orders of a market by id. The root is a merkle root
+// over the balance root followed by the market roots in name order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateHash {
+    pub root: Hash,
+    pub balances: Hash,
+    // only markets with open orders
+    pub markets: BTreeMap<String, Hash>,
+}
+
+impl StateHash {
+    pub fn of(snapshot: &StateSnapshot) -> Self {
+        // zero balances are left out, whether an entry is kept at zero depends on the history
+        let mut balances: Vec<_> = snapshot.balances.iter().filter(|(_, amount)| !amount.is_zero()).collect();
+        balances.sort_by(|(a, _), (b, _)| (a.user_id, &a.asset, a.balance_type as i16).cmp(&(b.user_id, &b.asset, b.balance_type as i16)));
+        let balances = merkle_root(
+            balances
+                .into_iter()
+                .map(|(key, amount)| {
+                    let mut leaf = LeafHasher::new();
+                    leaf.bytes(key.user_id.as_bytes());
+                    leaf.str(&key.asset);
+                    leaf.bytes(&(key.balance_type as i16).to_le_bytes());
+                    leaf.decimal(amount);
+                    leaf.finish()
+                })
+                .collect(),
+        );
+
+        let mut orders: BTreeMap<&str, Vec<&OrderSlice>> = BTreeMap::new();
+        for order in &snapshot.orders {
+            orders.entry(order.market.as_str()).or_default().push(order);
+        }
+        let markets: BTreeMap<String, Hash> = orders
+            .into_iter()
+            .map(|(market, mut orders)| {
+                orders.sort_by_key(|order| order.id);
+                (market.to_owned(), merkle_root(orders.into_iter().map(order_leaf).collect()))
+            })
+            .collect();
+
+        let root = merkle_root(std::iter::once(balances).chain(markets.values().copied()).collect());
+        Self { root, balances, markets }
+    }
+
+    pub fn root_hex(&self) -> String {
+        hex::encode(self.root)
+    }
+}
+
+// times are left out, the hash covers what the orders are matched on
+fn order_leaf(order: &OrderSlice) -> Hash {
+    let mut leaf = LeafHasher::new();
+    leaf.bytes(&order.id.to_le_bytes());
+    leaf.str(&order.user_id);
+    leaf.str(&format!("{:?}", order.order_type));
+    leaf.str(&format!("{:?}", order.order_side));
+    for amount in [
+        &order.price,
+        &order.amount,
+        &order.taker_fee,
+        &order.maker_fee,
+        &order.remain,
+        &order.frozen,
+        &order.finished_base,
+        &order.finished_quote,
+        &order.finished_fee,
+    ] {
+        leaf.decimal(amount);
+    }
+    leaf.bytes(&[order.post_only as u8]);
+    leaf.str(&order.trail_type.map(|trail_type| format!("{:?}", trail_type)).unwrap_or_default());
+    leaf.str(&order.peg_reference.map(|reference| format!("{:?}", reference)).unwrap_or_default());
+    for amount in [&order.trail_value, &order.trigger_price, &order.peg_offset, &order.peg_limit] {
+        leaf.decimal(&amount.unwrap_or_default());
+    }
+    leaf.finish()
+}
+
+// leaves and inner nodes are told apart by a prefix byte, so a leaf can not pass as a node
+struct LeafHasher(Sha256);
+
+impl LeafHasher {
+    fn new() -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update([0u8]);
+        Self(hasher)
+    }
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.update((bytes.len() as u32).to_le_bytes());
+        self.0.update(bytes);
+    }
+    fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+    // 1.50 and 1.5 are the same amount
+    fn decimal(&mut self, amount: &Decimal) {
+        self.str(&amount.normalize().to_string());
+    }
+    fn finish(self) -> Hash {
+        self.0.finalize().into()
+    }
+}
+
+// an odd node at the end of a level is paired with itself
+fn merkle_root(mut level: Vec<Hash>) -> Hash {
+    if level.is_empty() {
+        return Sha256::digest(b"").into();
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update([1u8]);
+                hasher.update(pair[0]);
+                hasher.update(pair.get(1).unwrap_or(&pair[0]));
+                hasher.finalize().into()
+            })
+            .collect();
+    }
+    level[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceMapKey, BalanceType};
+    use crate::models::SliceHistory;
+    use fluidex_common::rust_decimal_macros::*;
+    use std::str::FromStr;
+    use uuid::Uuid;
+
+    fn snapshot(balances: &[(&str, BalanceType, &str, Decimal)]) -> StateSnapshot {
+        let mut snapshot = StateSnapshot::new(SliceHistory {
+            time: 0,
+            end_operation_log_id: 0,
+            end_order_id: 0,
+            end_trade_id: 0,
+            state_hash: None,
+        });
+        for (user_id, balance_type, asset, amount) in balances {
+            let key = BalanceMapKey {
+                user_id: Uuid::from_str(user_id).unwrap(),
+                balance_type: *balance_type,
+                asset: asset.to_string(),
+            };
+            snapshot.balances.insert(key, *amount);
+        }
+        snapshot
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let alice = "0f0e0d0c-0b0a-4908-8706-050403020100";
+        let bob = "1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d";
+        let a = StateHash::of(&snapshot(&[
+            (alice, BalanceType::AVAILABLE, "ETH", dec!(1.5)),
+            (bob, BalanceType::FREEZE, "USDT", dec!(100)),
+        ]));
+        // same state, other scale and an extra zero entry
+        let b = StateHash::of(&snapshot(&[
+            (bob, BalanceType::FREEZE, "USDT", dec!(100.00)),
+            (alice, BalanceType::AVAILABLE, "ETH", dec!(1.50)),
+            (alice, BalanceType::FREEZE, "ETH", dec!(0)),
+        ]));
+        assert_eq!(a, b);
+        let c = StateHash::of(&snapshot(&[
+            (alice, BalanceType::FREEZE, "ETH", dec!(1.5)),
+            (bob, BalanceType::FREEZE, "USDT", dec!(100)),
+        ]));
+        assert_ne!(a.root, c.root);
+        assert!(a.markets.is_empty());
+    }
+}
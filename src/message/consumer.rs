@@ -6,15 +6,27 @@ use std::time::Duration;
 use fluidex_common::rdkafka;
 use fluidex_common::rdkafka::consumer::*;
 use fluidex_common::rdkafka::error::KafkaError;
-use fluidex_common::rdkafka::message::BorrowedMessage;
+use fluidex_common::rdkafka::message::{BorrowedMessage, Headers};
 use fluidex_common::rdkafka::Message;
 
+use super::producer::MESSAGE_ID_HEADER;
+use super::MessageId;
+
 // use crate::config;
 use std::collections::HashMap;
 use std::pin::Pin;
 
 pub(crate) type PinBox<T> = Pin<Box<T>>;
 
+// the id a message was published with, a message seen before is a republished duplicate
+pub fn message_id<M: Message>(msg: &M) -> Option<MessageId> {
+    let headers = msg.headers()?;
+    (0..headers.count())
+        .filter_map(|i| headers.get(i))
+        .find(|(name, _)| *name == MESSAGE_ID_HEADER)
+        .and_then(|(_, value)| std::str::from_utf8(value).ok()?.parse().ok())
+}
+
 pub trait RdConsumerExt {
     type CTXType: ConsumerContext;
     //So we can elimate the generic dep in trait bound ....
@@ -1,16 +1,61 @@
 use crate::market::Order;
-pub use crate::models::BalanceHistory;
-use crate::types::OrderEventType;
+pub use crate::models::{BalanceHistory, InternalTx};
+use crate::types::{DepositStatus, OrderEventType, WithdrawStatus};
 use uuid::Uuid;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 pub mod consumer;
 pub mod persist;
 pub mod producer;
 
-pub use producer::{BALANCES_TOPIC, DEPOSITS_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, UNIFY_TOPIC, USER_TOPIC, WITHDRAWS_TOPIC};
+pub use producer::{
+    AUDIT_TOPIC, BALANCES_TOPIC, DEPOSITS_TOPIC, MMP_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, TRANSFERS_TOPIC, UNIFY_TOPIC, USER_TOPIC,
+    WITHDRAWS_TOPIC,
+};
+
+// Identifies a message across restarts: the operation log entry that caused it and its position
+// among the messages of that operation. A replay of the operation gives the same ids again, so a
+// consumer skips the ones it has seen. Sent as the `message_id` header, as `<id>-<seq>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageId {
+    pub operation_log_id: u64,
+    pub seq: u32,
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.operation_log_id, self.seq)
+    }
+}
+
+impl Serialize for MessageId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (operation_log_id, seq) = s.split_once('-').ok_or_else(|| anyhow::anyhow!("invalid message id {}", s))?;
+        Ok(Self {
+            operation_log_id: operation_log_id.parse()?,
+            seq: seq.parse()?,
+        })
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BalanceMessage {
@@ -25,6 +70,8 @@ pub struct BalanceMessage {
     pub balance_available: String,
     pub balance_frozen: String,
     pub detail: String,
+    #[serde(default)]
+    pub operation_log_id: Option<u64>,
 }
 
 impl From<&BalanceHistory> for BalanceMessage {
@@ -41,6 +88,7 @@ impl From<&BalanceHistory> for BalanceMessage {
             balance_available: balance.balance_available.to_string(),
             balance_frozen: balance.balance_frozen.to_string(),
             detail: balance.detail.clone(),
+            operation_log_id: balance.operation_log_id.map(|id| id as u64),
         }
     }
 }
@@ -51,6 +99,8 @@ pub struct DepositMessage {
     pub user_id: Uuid,
     pub asset: String,
     pub business: String,
+    pub business_id: u64,
+    pub status: DepositStatus,
     pub change: String,
     pub balance: String,
     pub balance_available: String,
@@ -58,13 +108,15 @@ pub struct DepositMessage {
     pub detail: String,
 }
 
-impl From<&BalanceHistory> for DepositMessage {
-    fn from(balance: &BalanceHistory) -> Self {
+impl DepositMessage {
+    pub fn new(balance: &BalanceHistory, status: DepositStatus) -> Self {
         Self {
             timestamp: balance.time.timestamp() as f64,
             user_id: balance.user_id.parse().unwrap(),
             asset: balance.asset.clone(),
             business: balance.business.clone(),
+            business_id: balance.business_id as u64,
+            status,
             change: balance.change.to_string(),
             balance: balance.balance.to_string(),
             balance_available: balance.balance_available.to_string(),
@@ -80,6 +132,8 @@ pub struct WithdrawMessage {
     pub user_id: Uuid,
     pub asset: String,
     pub business: String,
+    pub business_id: u64,
+    pub status: WithdrawStatus,
     pub change: String,
     pub balance: String,
     pub balance_available: String,
@@ -87,13 +141,15 @@ pub struct WithdrawMessage {
     pub detail: String,
 }
 
-impl From<&BalanceHistory> for WithdrawMessage {
-    fn from(balance: &BalanceHistory) -> Self {
+impl WithdrawMessage {
+    pub fn new(balance: &BalanceHistory, status: WithdrawStatus) -> Self {
         Self {
             timestamp: balance.time.timestamp() as f64,
             user_id: balance.user_id.parse().unwrap(),
             asset: balance.asset.clone(),
             business: balance.business.clone(),
+            business_id: balance.business_id as u64,
+            status,
             change: balance.change.to_string(),
             balance: balance.balance.to_string(),
             balance_available: balance.balance_available.to_string(),
@@ -103,6 +159,31 @@ impl From<&BalanceHistory> for WithdrawMessage {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferMessage {
+    pub timestamp: f64,
+    pub user_from: Uuid,
+    pub user_to: Uuid,
+    pub asset: String,
+    pub amount: String,
+    pub business_id: u64,
+    pub memo: String,
+}
+
+impl From<&InternalTx> for TransferMessage {
+    fn from(tx: &InternalTx) -> Self {
+        Self {
+            timestamp: tx.time.timestamp() as f64,
+            user_from: tx.user_from.parse().unwrap(),
+            user_to: tx.user_to.parse().unwrap(),
+            asset: tx.asset.clone(),
+            amount: tx.amount.to_string(),
+            business_id: tx.business_id as u64,
+            memo: tx.memo.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OrderMessage {
     pub event: OrderEventType,
@@ -121,8 +202,22 @@ impl OrderMessage {
         }
     }
 }
+// emitted each time the market maker protection of a user trips
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MmpMessage {
+    pub timestamp: f64,
+    pub user_id: Uuid,
+    pub market: String,
+    pub qty_limit: String,
+    pub trade_count_limit: u32,
+    pub window: f64,
+    pub cancelled_orders: usize,
+}
+
 //re-export from market, act as TradeMessage
 pub use crate::market::Trade;
+//re-export from audit, published when the auditor finds mismatches
+pub use crate::audit::AuditReport;
 
 //TODO: senderstatus is not used anymore?
 #[derive(Serialize, Deserialize)]
@@ -140,17 +235,27 @@ pub trait MessageManager: Sync + Send {
     fn push_balance_message(&mut self, balance: &BalanceMessage);
     fn push_deposit_message(&mut self, balance: &DepositMessage);
     fn push_withdraw_message(&mut self, balance: &WithdrawMessage);
+    fn push_mmp_message(&mut self, mmp: &MmpMessage);
+    fn push_transfer_message(&mut self, transfer: &TransferMessage);
+    fn push_audit_message(&mut self, report: &AuditReport);
+    // a message of an operation, released once the operation is logged
+    fn push_message(&mut self, msg: &Message, id: MessageId);
+    // the operation log id up to which every message is delivered, none when it is not tracked
+    fn delivered_operation_log_id(&self) -> Option<u64> {
+        None
+    }
 }
 
 pub struct RdProducerStub<T> {
-    pub sender: crossbeam_channel::Sender<(&'static str, String)>,
+    pub sender: crossbeam_channel::Sender<(&'static str, String, Option<MessageId>)>,
+    delivered: Arc<AtomicU64>,
     _phantom: std::marker::PhantomData<T>,
 }
 
 impl<T> RdProducerStub<T> {
     fn push_message_and_topic(&self, message: String, topic_name: &'static str) {
         //log::debug!("KAFKA: push {} message: {}", topic_name, message);
-        self.sender.try_send((topic_name, message)).unwrap();
+        self.sender.try_send((topic_name, message, None)).unwrap();
     }
 }
 
@@ -163,11 +268,14 @@ impl<T: producer::MessageScheme + 'static> RdProducerStub<T> {
         let producer_context: producer::RdProducerContext<T> = Default::default();
 
         let kafkaproducer = producer_context.new_producer(brokers)?;
+        let delivered = Arc::new(AtomicU64::new(0));
+        let delivered_for_run = delivered.clone();
         std::thread::spawn(move || {
-            producer::RdProducerContext::<T>::run_default(kafkaproducer, receiver);
+            producer::RdProducerContext::<T>::run_default(kafkaproducer, receiver, delivered_for_run);
         });
         Ok(Self {
             sender,
+            delivered,
             _phantom: std::marker::PhantomData,
         })
     }
@@ -219,6 +327,34 @@ impl<T: producer::MessageScheme> MessageManager for RdProducerStub<T> {
         let message = serde_json::to_string(&withdraw).unwrap();
         self.push_message_and_topic(message, WITHDRAWS_TOPIC)
     }
+    fn push_mmp_message(&mut self, mmp: &MmpMessage) {
+        let message = serde_json::to_string(&mmp).unwrap();
+        self.push_message_and_topic(message, MMP_TOPIC)
+    }
+    fn push_audit_message(&mut self, report: &AuditReport) {
+        let message = serde_json::to_string(&report).unwrap();
+        self.push_message_and_topic(message, AUDIT_TOPIC)
+    }
+    fn push_transfer_message(&mut self, transfer: &TransferMessage) {
+        let message = serde_json::to_string(&transfer).unwrap();
+        self.push_message_and_topic(message, TRANSFERS_TOPIC)
+    }
+    fn push_message(&mut self, msg: &Message, id: MessageId) {
+        let (message, topic_name) = match msg {
+            Message::BalanceMessage(balance) => (serde_json::to_string(balance), BALANCES_TOPIC),
+            Message::DepositMessage(deposit) => (serde_json::to_string(deposit), DEPOSITS_TOPIC),
+            Message::OrderMessage(order) => (serde_json::to_string(order), ORDERS_TOPIC),
+            Message::TradeMessage(trade) => (serde_json::to_string(trade), TRADES_TOPIC),
+            Message::WithdrawMessage(withdraw) => (serde_json::to_string(withdraw), WITHDRAWS_TOPIC),
+            Message::MmpMessage(mmp) => (serde_json::to_string(mmp), MMP_TOPIC),
+            Message::TransferMessage(transfer) => (serde_json::to_string(transfer), TRANSFERS_TOPIC),
+            Message::AuditMessage(report) => (serde_json::to_string(report), AUDIT_TOPIC),
+        };
+        self.sender.try_send((topic_name, message.unwrap(), Some(id))).unwrap();
+    }
+    fn delivered_operation_log_id(&self) -> Option<u64> {
+        Some(self.delivered.load(Ordering::Acquire))
+    }
 }
 
 pub type SimpleMessageManager = RdProducerStub<producer::SimpleMessageScheme>;
@@ -235,10 +371,59 @@ pub type FullOrderMessageManager = RdProducerStub<producer::FullOrderMessageSche
 #[serde(tag = "type", content = "value")]
 pub enum Message {
     BalanceMessage(Box<BalanceMessage>),
-    DepositMessage(Box<BalanceMessage>),
+    DepositMessage(Box<DepositMessage>),
     OrderMessage(Box<OrderMessage>),
     TradeMessage(Box<Trade>),
-    WithdrawMessage(Box<BalanceMessage>),
+    WithdrawMessage(Box<WithdrawMessage>),
+    MmpMessage(Box<MmpMessage>),
+    TransferMessage(Box<TransferMessage>),
+    AuditMessage(Box<AuditReport>),
+}
+
+impl Message {
+    // the topic the message is published to
+    pub fn topic(&self) -> &'static str {
+        match self {
+            Message::BalanceMessage(_) => BALANCES_TOPIC,
+            Message::DepositMessage(_) => DEPOSITS_TOPIC,
+            Message::OrderMessage(_) => ORDERS_TOPIC,
+            Message::TradeMessage(_) => TRADES_TOPIC,
+            Message::WithdrawMessage(_) => WITHDRAWS_TOPIC,
+            Message::MmpMessage(_) => MMP_TOPIC,
+            Message::TransferMessage(_) => TRANSFERS_TOPIC,
+            Message::AuditMessage(_) => AUDIT_TOPIC,
+        }
+    }
+
+    // the users the message is about
+    pub fn users(&self) -> Vec<Uuid> {
+        match self {
+            Message::BalanceMessage(balance) => vec![balance.user_id],
+            Message::DepositMessage(deposit) => vec![deposit.user_id],
+            Message::OrderMessage(order) => vec![order.order.user],
+            Message::TradeMessage(trade) => vec![trade.ask_user_id, trade.bid_user_id],
+            Message::WithdrawMessage(withdraw) => vec![withdraw.user_id],
+            Message::MmpMessage(mmp) => vec![mmp.user_id],
+            Message::TransferMessage(transfer) => vec![transfer.user_from, transfer.user_to],
+            Message::AuditMessage(_) => vec![],
+        }
+    }
+
+    pub fn market(&self) -> Option<&str> {
+        match self {
+            Message::OrderMessage(order) => Some(&*order.order.market),
+            Message::TradeMessage(trade) => Some(trade.market.as_str()),
+            Message::MmpMessage(mmp) => Some(mmp.market.as_str()),
+            _ => None,
+        }
+    }
+
+    // set once the operation that caused the message is logged, balance changes keep it in the history
+    pub fn set_operation_log_id(&mut self, operation_log_id: u64) {
+        if let Message::BalanceMessage(balance) = self {
+            balance.operation_log_id = Some(operation_log_id);
+        }
+    }
 }
 
 /*
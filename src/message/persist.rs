@@ -616,6 +616,21 @@ impl<'r> From<&'r super::BalanceMessage> for models::BalanceHistory {
             balance_available: DecimalDbType::from_str(&origin.balance_available).unwrap_or_else(decimal_warning),
             balance_frozen: DecimalDbType::from_str(&origin.balance_frozen).unwrap_or_else(decimal_warning),
             detail: origin.detail.clone(),
+            operation_log_id: origin.operation_log_id.map(|id| id as i64),
+        }
+    }
+}
+
+impl<'r> From<&'r super::TransferMessage> for models::InternalTx {
+    fn from(origin: &'r super::TransferMessage) -> Self {
+        models::InternalTx {
+            time: FTimestamp::from(&origin.timestamp).into(),
+            user_from: origin.user_from.to_string(),
+            user_to: origin.user_to.to_string(),
+            asset: origin.asset.clone(),
+            amount: DecimalDbType::from_str(&origin.amount).unwrap_or_else(decimal_warning),
+            business_id: origin.business_id as i64,
+            memo: origin.memo.clone(),
         }
     }
 }
@@ -1,10 +1,16 @@
+use super::MessageId;
 use anyhow::Result;
 use crossbeam_channel::{RecvTimeoutError, TryRecvError};
 use fluidex_common::rdkafka::client::ClientContext;
 use fluidex_common::rdkafka::config::ClientConfig;
 use fluidex_common::rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use fluidex_common::rdkafka::message::OwnedHeaders;
 use fluidex_common::rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
 use fluidex_common::rdkafka::util::{IntoOpaque, Timeout};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, LinkedList};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 pub type SimpleDeliverResult = Result<(), KafkaError>;
@@ -18,10 +24,55 @@ pub trait MessageScheme: Default + Sync + Send {
         vec![]
     }
     fn is_full(&self) -> bool;
-    fn on_message(&mut self, title_tip: &'static str, message: String);
+    fn on_message(&mut self, title_tip: &'static str, message: String, id: Option<MessageId>);
     fn pop_up(&mut self) -> Option<BaseRecord<'_, str, str, Self::DeliverOpaque>>;
     fn commit(&mut self, isfailed: Option<Self::DeliverOpaque>);
     fn deliver_commit(&mut self, result: SimpleDeliverResult, opaque: Self::DeliverOpaque);
+    // the operation log id up to which every message taken with an id is delivered
+    fn delivered_operation_log_id(&self) -> u64;
+}
+
+// Follows the messages with an id from when a scheme takes them until they are delivered. A
+// message that failed is never delivered, the cursor stays before it and a restart publishes it
+// again.
+#[derive(Default)]
+pub struct DeliveryTracker {
+    // the number of undelivered messages of each operation
+    undelivered: BTreeMap<u64, usize>,
+    last_taken: u64,
+}
+
+impl DeliveryTracker {
+    pub fn taken(&mut self, id: &Option<MessageId>) {
+        if let Some(id) = id {
+            *self.undelivered.entry(id.operation_log_id).or_default() += 1;
+            self.last_taken = self.last_taken.max(id.operation_log_id);
+        }
+    }
+    pub fn delivered(&mut self, id: &Option<MessageId>) {
+        if let Some(id) = id {
+            if let Entry::Occupied(mut entry) = self.undelivered.entry(id.operation_log_id) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+    }
+    // the rest of the messages of the last operation taken may still be on the way
+    pub fn cursor(&self) -> u64 {
+        let first_undelivered = self.undelivered.keys().next().copied().unwrap_or(self.last_taken);
+        first_undelivered.saturating_sub(1)
+    }
+}
+
+pub const MESSAGE_ID_HEADER: &str = "message_id";
+
+fn with_message_id<'a, D: IntoOpaque>(record: BaseRecord<'a, str, str, D>, id: &Option<MessageId>) -> BaseRecord<'a, str, str, D> {
+    match id {
+        Some(id) => record.headers(OwnedHeaders::new().add(MESSAGE_ID_HEADER, id.to_string().as_str())),
+        None => record,
+    }
 }
 
 pub struct RdProducerContext<T: MessageScheme> {
@@ -74,13 +125,22 @@ impl<T: MessageScheme> RdProducerContext<T> {
         Ok(producer)
     }
 
-    pub fn run_default(producer: BaseProducer<Self>, receiver: crossbeam_channel::Receiver<(&'static str, String)>) {
+    pub fn run_default(
+        producer: BaseProducer<Self>,
+        receiver: crossbeam_channel::Receiver<(&'static str, String, Option<MessageId>)>,
+        delivered: Arc<AtomicU64>,
+    ) {
         let message_scheme = T::default();
-        Self::run(producer, message_scheme, receiver);
+        Self::run(producer, message_scheme, receiver, delivered);
     }
 
-    pub fn run(producer: BaseProducer<Self>, mut message_scheme: T, receiver: crossbeam_channel::Receiver<(&'static str, String)>) {
-        Self::run_loop(&producer, &mut message_scheme, receiver);
+    pub fn run(
+        producer: BaseProducer<Self>,
+        mut message_scheme: T,
+        receiver: crossbeam_channel::Receiver<(&'static str, String, Option<MessageId>)>,
+        delivered: Arc<AtomicU64>,
+    ) {
+        Self::run_loop(&producer, &mut message_scheme, receiver, &delivered);
 
         //flush producer before exit
         while let Some(msg) = message_scheme.pop_up() {
@@ -104,7 +164,12 @@ impl<T: MessageScheme> RdProducerContext<T> {
         log::info!("kafka producer running terminated");
     }
 
-    fn run_loop(producer: &BaseProducer<Self>, message_scheme: &mut T, receiver: crossbeam_channel::Receiver<(&'static str, String)>) {
+    fn run_loop(
+        producer: &BaseProducer<Self>,
+        message_scheme: &mut T,
+        receiver: crossbeam_channel::Receiver<(&'static str, String, Option<MessageId>)>,
+        delivered: &AtomicU64,
+    ) {
         let timeout_interval = Duration::from_millis(100);
         let delivery_report = &producer.context().delivery_record_get;
         // last_poll == 0 means msg canot be sent out
@@ -132,9 +197,9 @@ impl<T: MessageScheme> RdProducerContext<T> {
                     })
                 };
                 match recv_ret {
-                    Ok((topic, message)) => {
+                    Ok((topic, message, id)) => {
                         is_idle &= false;
-                        message_scheme.on_message(topic, message);
+                        message_scheme.on_message(topic, message, id);
                     }
                     Err(TryRecvError::Empty) => {}
                     Err(TryRecvError::Disconnected) => {
@@ -173,6 +238,7 @@ impl<T: MessageScheme> RdProducerContext<T> {
             while let Ok((result, opaque)) = delivery_report.try_recv() {
                 message_scheme.deliver_commit(result, opaque);
             }
+            delivered.fetch_max(message_scheme.delivered_operation_log_id(), Ordering::AcqRel);
 
             if is_idle {
                 // never ever dead loop...
@@ -182,27 +248,46 @@ impl<T: MessageScheme> RdProducerContext<T> {
     }
 }
 
+pub const AUDIT_TOPIC: &str = "audit";
 pub const BALANCES_TOPIC: &str = "balances";
 pub const DEPOSITS_TOPIC: &str = "deposits";
+pub const MMP_TOPIC: &str = "mmp";
 pub const ORDERS_TOPIC: &str = "orders";
 pub const TRADES_TOPIC: &str = "trades";
+pub const TRANSFERS_TOPIC: &str = "transfers";
 pub const UNIFY_TOPIC: &str = "unifyevents";
 pub const USER_TOPIC: &str = "registeruser";
 pub const WITHDRAWS_TOPIC: &str = "withdraws";
 
-use std::collections::LinkedList;
-
 #[derive(Default)]
 pub struct SimpleMessageScheme {
-    balances_list: LinkedList<String>,
-    orders_list: LinkedList<String>,
-    trades_list: LinkedList<String>,
-    users_list: LinkedList<String>,
-    last_poped: Option<(&'static str, String)>,
+    balances_list: LinkedList<(String, Option<MessageId>)>,
+    orders_list: LinkedList<(String, Option<MessageId>)>,
+    trades_list: LinkedList<(String, Option<MessageId>)>,
+    users_list: LinkedList<(String, Option<MessageId>)>,
+    last_poped: Option<(&'static str, String, Option<MessageId>)>,
+    tracker: DeliveryTracker,
+}
+
+impl SimpleMessageScheme {
+    // false for a topic this scheme does not send
+    fn push_back(&mut self, title_tip: &'static str, message: String, id: Option<MessageId>) -> bool {
+        let list = match title_tip {
+            BALANCES_TOPIC => &mut self.balances_list,
+            ORDERS_TOPIC => &mut self.orders_list,
+            TRADES_TOPIC => &mut self.trades_list,
+            USER_TOPIC => &mut self.users_list,
+            _ => return false,
+        };
+
+        list.push_back((message, id));
+        true
+    }
 }
 
 impl MessageScheme for SimpleMessageScheme {
-    type DeliverOpaque = ();
+    // the id of the message
+    type DeliverOpaque = Box<Option<MessageId>>;
     type K = &'static str;
     type V = &'static str;
 
@@ -213,16 +298,10 @@ impl MessageScheme for SimpleMessageScheme {
         self.balances_list.len() >= 100 || self.orders_list.len() >= 100 || self.trades_list.len() >= 100 || self.users_list.len() >= 100
     }
 
-    fn on_message(&mut self, title_tip: &'static str, message: String) {
-        let list = match title_tip {
-            BALANCES_TOPIC => &mut self.balances_list,
-            ORDERS_TOPIC => &mut self.orders_list,
-            TRADES_TOPIC => &mut self.trades_list,
-            USER_TOPIC => &mut self.users_list,
-            _ => return,
-        };
-
-        list.push_back(message);
+    fn on_message(&mut self, title_tip: &'static str, message: String, id: Option<MessageId>) {
+        if self.push_back(title_tip, message, id) {
+            self.tracker.taken(&id);
+        }
     }
 
     fn pop_up(&mut self) -> Option<BaseRecord<'_, str, str, Self::DeliverOpaque>> {
@@ -243,38 +322,49 @@ impl MessageScheme for SimpleMessageScheme {
             }
         }
 
-        self.last_poped = list.pop_front().map(|str| (topic_name, str));
+        self.last_poped = list.pop_front().map(|(str, id)| (topic_name, str, id));
 
         self.last_poped.as_ref().map(|poped_ret| {
-            let (topic_name, str) = poped_ret;
-            BaseRecord::to(topic_name).key("").payload(AsRef::as_ref(str))
+            let (topic_name, str, id) = poped_ret;
+            with_message_id(
+                BaseRecord::with_opaque_to(topic_name, Box::new(*id))
+                    .key("")
+                    .payload(AsRef::as_ref(str)),
+                id,
+            )
         })
     }
 
     fn commit(&mut self, isfailed: Option<Self::DeliverOpaque>) {
         if isfailed.is_some() {
             //push the poped message back
-            let (topic_name, str) = self.last_poped.take().unwrap();
-            self.on_message(topic_name, str);
+            let (topic_name, str, id) = self.last_poped.take().unwrap();
+            self.push_back(topic_name, str, id);
         }
     }
-    fn deliver_commit(&mut self, result: SimpleDeliverResult, _opaque: Self::DeliverOpaque) {
-        if let Err(e) = result {
-            log::error!("kafka send err: {}, MESSAGE LOST", e);
+    fn deliver_commit(&mut self, result: SimpleDeliverResult, opaque: Self::DeliverOpaque) {
+        match result {
+            Ok(()) => self.tracker.delivered(&opaque),
+            Err(e) => log::error!("kafka send err: {}, MESSAGE LOST, the delivery cursor stops before it", e),
         }
     }
+    fn delivered_operation_log_id(&self) -> u64 {
+        self.tracker.cursor()
+    }
 }
 
 #[derive(Default)]
 pub struct FullOrderMessageScheme {
-    ordered_list: LinkedList<(&'static str, String)>,
+    ordered_list: LinkedList<(&'static str, String, Option<MessageId>)>,
     //two counters is used to assigned and verify for delivery
     deliver_cnt: u64,
     commited_cnt: u64,
+    tracker: DeliveryTracker,
 }
 
 impl MessageScheme for FullOrderMessageScheme {
-    type DeliverOpaque = Box<u64>;
+    // the deliver count and the id of the message
+    type DeliverOpaque = Box<(u64, Option<MessageId>)>;
     type K = &'static str;
     type V = &'static str;
 
@@ -293,10 +383,11 @@ impl MessageScheme for FullOrderMessageScheme {
         self.ordered_list.len() >= 100
     }
 
-    fn on_message(&mut self, title_tip: &'static str, message: String) {
+    fn on_message(&mut self, title_tip: &'static str, message: String, id: Option<MessageId>) {
         match title_tip {
-            DEPOSITS_TOPIC | ORDERS_TOPIC | TRADES_TOPIC | USER_TOPIC | WITHDRAWS_TOPIC => {
-                self.ordered_list.push_back((title_tip, message))
+            AUDIT_TOPIC | DEPOSITS_TOPIC | MMP_TOPIC | ORDERS_TOPIC | TRADES_TOPIC | TRANSFERS_TOPIC | USER_TOPIC | WITHDRAWS_TOPIC => {
+                self.ordered_list.push_back((title_tip, message, id));
+                self.tracker.taken(&id);
             }
             _ => {}
         };
@@ -306,12 +397,13 @@ impl MessageScheme for FullOrderMessageScheme {
         if self.ordered_list.is_empty() {
             return None;
         }
-        let (title_tip, message) = self.ordered_list.front().unwrap();
-        Some(
-            BaseRecord::with_opaque_to(UNIFY_TOPIC, Box::new(self.deliver_cnt))
+        let (title_tip, message, id) = self.ordered_list.front().unwrap();
+        Some(with_message_id(
+            BaseRecord::with_opaque_to(UNIFY_TOPIC, Box::new((self.deliver_cnt, *id)))
                 .key(*title_tip)
                 .payload(AsRef::as_ref(message)),
-        )
+            id,
+        ))
     }
 
     fn commit(&mut self, isfailed: Option<Self::DeliverOpaque>) {
@@ -320,18 +412,48 @@ impl MessageScheme for FullOrderMessageScheme {
             self.deliver_cnt += 1;
         } else {
             //sanity check
-            assert!(*isfailed.unwrap() == self.deliver_cnt);
+            assert!(isfailed.unwrap().0 == self.deliver_cnt);
         }
     }
     fn deliver_commit(&mut self, result: SimpleDeliverResult, opaque: Self::DeliverOpaque) {
         //sanity check: verify we are keeping order
-        assert!(*opaque == self.commited_cnt);
+        assert!(opaque.0 == self.commited_cnt);
         self.commited_cnt += 1;
         log::debug!("kafka unify messenger has confirm deliver till {}", self.commited_cnt);
 
-        if let Err(e) = result {
+        match result {
+            Ok(()) => self.tracker.delivered(&opaque.1),
             //TODO: should we panic ?
-            log::error!("kafka send err: {}, MESSAGE LOST", e);
+            Err(e) => log::error!("kafka send err: {}, MESSAGE LOST, the delivery cursor stops before it", e),
         }
     }
+    fn delivered_operation_log_id(&self) -> u64 {
+        self.tracker.cursor()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_delivery_tracker() {
+    let id = |operation_log_id, seq| Some(MessageId { operation_log_id, seq });
+    let mut tracker = DeliveryTracker::default();
+    for (operation_log_id, seq) in [(1, 0), (1, 1), (2, 0), (3, 0)] {
+        tracker.taken(&id(operation_log_id, seq));
+    }
+    // messages without an id are not followed
+    tracker.taken(&None);
+    assert_eq!(tracker.cursor(), 0);
+    tracker.delivered(&id(1, 1));
+    tracker.delivered(&id(2, 0));
+    assert_eq!(tracker.cursor(), 0);
+    tracker.delivered(&id(1, 0));
+    assert_eq!(tracker.cursor(), 2);
+    // the last operation may have more messages to come
+    tracker.delivered(&id(3, 0));
+    assert_eq!(tracker.cursor(), 2);
+    // a failed message is never delivered, the cursor stays before it
+    tracker.taken(&id(4, 0));
+    tracker.taken(&id(5, 0));
+    tracker.delivered(&id(5, 0));
+    assert_eq!(tracker.cursor(), 3);
 }
@@ -0,0 +1,425 @@
+use crate::asset::SubAccountManager;
+use crate::controller::OPERATION_SUB_ACCOUNT_CREATE;
+use crate::matchengine::authentication::UserExtension;
+use crate::models::tablenames::{BALANCEHISTORY, BALANCESLICE, OPERATIONLOG, OPERATIONLOGARCHIVE, SLICEHISTORY, SUBACCOUNTSLICE};
+use crate::models::{DecimalDbType, SliceHistory, TimestampDbType};
+use crate::restapi::errors::RpcError;
+use crate::restapi::state::AppState;
+use actix_web::HttpResponse;
+use paperclip::actix::web::{self, HttpRequest, Json};
+use paperclip::actix::{api_v2_operation, Apiv2Schema};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::Postgres;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use uuid::Uuid;
+
+// the point in time is given either as a unix timestamp or as an operation log id
+#[derive(Deserialize, Apiv2Schema)]
+pub struct BalancesAtReq {
+    time: Option<i64>,
+    operation_log_id: Option<i64>,
+    asset: Option<String>,
+    // admins only
+    user_id: Option<String>,
+    // admins only, "csv" for an export
+    format: Option<String>,
+}
+
+#[derive(Serialize, Apiv2Schema)]
+pub struct UserBalance {
+    // the account id, which is the sub-account id for the balances of a sub-account
+    user_id: String,
+    asset: String,
+    // available + frozen, the balance_history does not track the split when funds are frozen by orders
+    balance: String,
+}
+
+#[derive(Serialize, Apiv2Schema)]
+pub struct BalancesAtResponse {
+    time: i64,
+    balances: Vec<UserBalance>,
+}
+
+#[derive(sqlx::FromRow)]
+struct BalanceRow {
+    user_id: String,
+    asset: String,
+    balance: DecimalDbType,
+}
+
+// The changes up to `time` are applied. With an operation log id the changes recorded with their
+// operation are cut at that operation instead, `time` is the time of the operation then and only
+// cuts the rows written before operations were recorded.
+struct Cutoff {
+    time: TimestampDbType,
+    operation_log_id: Option<i64>,
+}
+
+fn parse_time(time: i64) -> Result<TimestampDbType, RpcError> {
+    TimestampDbType::from_timestamp_opt(time, 0).ok_or_else(|| RpcError::bad_request("invalid time"))
+}
+
+fn operation_time_query() -> String {
+    format!(
+        "select time from {} where id = $1 union all select time from {} where id = $1",
+        OPERATIONLOG, OPERATIONLOGARCHIVE
+    )
+}
+
+fn slice_by_time_query() -> String {
+    format!(
+        "select time, end_operation_log_id, end_order_id, end_trade_id, state_hash from {} \
+         where time <= $1 order by time desc limit 1",
+        SLICEHISTORY
+    )
+}
+
+fn slice_by_operation_query() -> String {
+    format!(
+        "select time, end_operation_log_id, end_order_id, end_trade_id, state_hash from {} \
+         where end_operation_log_id <= $1 order by end_operation_log_id desc, time desc limit 1",
+        SLICEHISTORY
+    )
+}
+
+fn sub_account_slice_query() -> String {
+    format!("select account_id from {} where slice_id = $1 and owner = $2", SUBACCOUNTSLICE)
+}
+
+// the archive holds the ones behind the oldest kept slice
+fn sub_account_create_query() -> String {
+    format!(
+        "select params from {} where method = $1 and user_id = $2 and id > $3 and id <= $4 and time <= $5 \
+         union all select params from {} where method = $1 and user_id = $2 and id > $3 and id <= $4 and time <= $5",
+        OPERATIONLOG, OPERATIONLOGARCHIVE
+    )
+}
+
+// WITHDRAWING and DEPOSITING are not a part of the balance in balance_history
+fn balance_slice_query() -> String {
+    format!(
+        "select user_id, asset, sum(balance) as balance from {} where slice_id = $1 and t in (1, 2) \
+         and ($2::varchar[] is null or user_id = any($2)) and ($3::varchar is null or asset = $3) group by user_id, asset",
+        BALANCESLICE
+    )
+}
+
+// The rows recorded with their operation are taken after the slice and up to the operation asked
+// for. The older rows only have a time with second precision, so the ones within the second of the
+// slice are read again, which is harmless since each row carries the resulting balance.
+fn balance_history_query() -> String {
+    format!(
+        "select distinct on (user_id, asset) user_id, asset, balance from {} \
+         where (case when operation_log_id is null then time >= $1 else operation_log_id > $2 end) \
+         and (case when operation_log_id is null or $3::bigint is null then time <= $4 else operation_log_id <= $3 end) \
+         and ($5::varchar[] is null or user_id = any($5)) and ($6::varchar is null or asset = $6) \
+         order by user_id, asset, time desc, id desc",
+        BALANCEHISTORY
+    )
+}
+
+#[cfg(sqlxverf)]
+fn sqlverf_balances_at() -> impl std::any::Any {
+    let id: i64 = 0;
+    let time = TimestampDbType::from_timestamp(0, 0);
+    let user_ids: Option<Vec<String>> = None;
+    let asset: Option<String> = None;
+    (
+        sqlx::query!(
+            "select time from operation_log where id = $1 union all select time from operation_log_archive where id = $1",
+            id
+        ),
+        sqlx::query!(
+            "select time, end_operation_log_id, end_order_id, end_trade_id, state_hash from slice_history \
+             where time <= $1 order by time desc limit 1",
+            id
+        ),
+        sqlx::query!(
+            "select time, end_operation_log_id, end_order_id, end_trade_id, state_hash from slice_history \
+             where end_operation_log_id <= $1 order by end_operation_log_id desc, time desc limit 1",
+            id
+        ),
+        sqlx::query!(
+            "select account_id from sub_account_slice where slice_id = $1 and owner = $2",
+            id,
+            "owner"
+        ),
+        sqlx::query!(
+            "select params from operation_log where method = $1 and user_id = $2 and id > $3 and id <= $4 and time <= $5 \
+             union all select params from operation_log_archive where method = $1 and user_id = $2 and id > $3 and id <= $4 and time <= $5",
+            "sub_account_create",
+            "owner",
+            id,
+            id,
+            time
+        ),
+        sqlx::query!(
+            "select user_id, asset, sum(balance) as balance from balance_slice where slice_id = $1 and t in (1, 2) \
+             and ($2::varchar[] is null or user_id = any($2)) and ($3::varchar is null or asset = $3) group by user_id, asset",
+            id,
+            user_ids.as_deref(),
+            asset
+        ),
+        sqlx::query!(
+            "select distinct on (user_id, asset) user_id, asset, balance from balance_history \
+             where (case when operation_log_id is null then time >= $1 else operation_log_id > $2 end) \
+             and (case when operation_log_id is null or $3::bigint is null then time <= $4 else operation_log_id <= $3 end) \
+             and ($5::varchar[] is null or user_id = any($5)) and ($6::varchar is null or asset = $6) \
+             order by user_id, asset, time desc, id desc",
+            time,
+            id,
+            Some(id),
+            time,
+            user_ids.as_deref(),
+            asset
+        ),
+    )
+}
+
+#[test]
+fn utest_balances_at() {
+    assert_eq!(
+        operation_time_query(),
+        "select time from operation_log where id = $1 union all select time from operation_log_archive where id = $1"
+    );
+    assert_eq!(
+        slice_by_time_query(),
+        "select time, end_operation_log_id, end_order_id, end_trade_id, state_hash from slice_history \
+         where time <= $1 order by time desc limit 1"
+    );
+    assert_eq!(
+        slice_by_operation_query(),
+        "select time, end_operation_log_id, end_order_id, end_trade_id, state_hash from slice_history \
+         where end_operation_log_id <= $1 order by end_operation_log_id desc, time desc limit 1"
+    );
+    assert_eq!(
+        sub_account_slice_query(),
+        "select account_id from sub_account_slice where slice_id = $1 and owner = $2"
+    );
+    assert_eq!(
+        sub_account_create_query(),
+        "select params from operation_log where method = $1 and user_id = $2 and id > $3 and id <= $4 and time <= $5 \
+         union all select params from operation_log_archive where method = $1 and user_id = $2 and id > $3 and id <= $4 and time <= $5"
+    );
+    assert_eq!(
+        balance_slice_query(),
+        "select user_id, asset, sum(balance) as balance from balance_slice where slice_id = $1 and t in (1, 2) \
+         and ($2::varchar[] is null or user_id = any($2)) and ($3::varchar is null or asset = $3) group by user_id, asset"
+    );
+    assert_eq!(
+        balance_history_query(),
+        "select distinct on (user_id, asset) user_id, asset, balance from balance_history \
+         where (case when operation_log_id is null then time >= $1 else operation_log_id > $2 end) \
+         and (case when operation_log_id is null or $3::bigint is null then time <= $4 else operation_log_id <= $3 end) \
+         and ($5::varchar[] is null or user_id = any($5)) and ($6::varchar is null or asset = $6) \
+         order by user_id, asset, time desc, id desc"
+    );
+}
+
+async fn cutoff_and_slice(db_log: &sqlx::pool::Pool<Postgres>, req: &BalancesAtReq) -> Result<(Cutoff, Option<SliceHistory>), RpcError> {
+    match (req.time, req.operation_log_id) {
+        (_, Some(operation_log_id)) => {
+            let time: Option<TimestampDbType> = sqlx::query_scalar(&operation_time_query())
+                .bind(operation_log_id)
+                .fetch_optional(db_log)
+                .await?;
+            let time = time.ok_or_else(|| RpcError::bad_request("unknown operation_log_id"))?;
+            let slice = sqlx::query_as(&slice_by_operation_query())
+                .bind(operation_log_id)
+                .fetch_optional(db_log)
+                .await?;
+            let cutoff = Cutoff {
+                time,
+                operation_log_id: Some(operation_log_id),
+            };
+            Ok((cutoff, slice))
+        }
+        (Some(time), None) => {
+            let cutoff = Cutoff {
+                time: parse_time(time)?,
+                operation_log_id: None,
+            };
+            let slice = sqlx::query_as(&slice_by_time_query()).bind(time).fetch_optional(db_log).await?;
+            Ok((cutoff, slice))
+        }
+        (None, None) => Err(RpcError::bad_request("either time or operation_log_id is required")),
+    }
+}
+
+// the account id of a sub-account created by a logged operation
+fn created_sub_account(owner: &Uuid, params: &str) -> Option<Uuid> {
+    let params: serde_json::Value = serde_json::from_str(params).ok()?;
+    params["name"].as_str().map(|name| SubAccountManager::account_id(owner, name))
+}
+
+// the user and the sub-accounts it had at the cutoff, from the slice and the operations after it
+async fn user_accounts(
+    db_log: &sqlx::pool::Pool<Postgres>,
+    owner: Uuid,
+    cutoff: &Cutoff,
+    slice: Option<&SliceHistory>,
+) -> Result<Vec<String>, RpcError> {
+    let mut accounts = vec![owner.to_string()];
+    if let Some(slice) = slice {
+        let sub_accounts: Vec<String> = sqlx::query_scalar(&sub_account_slice_query())
+            .bind(slice.time)
+            .bind(owner.to_string())
+            .fetch_all(db_log)
+            .await?;
+        accounts.extend(sub_accounts);
+    }
+    let created: Vec<String> = sqlx::query_scalar(&sub_account_create_query())
+        .bind(OPERATION_SUB_ACCOUNT_CREATE)
+        .bind(owner.to_string())
+        .bind(slice.map_or(0, |slice| slice.end_operation_log_id))
+        .bind(cutoff.operation_log_id.unwrap_or(i64::MAX))
+        .bind(cutoff.time)
+        .fetch_all(db_log)
+        .await?;
+    accounts.extend(
+        created
+            .iter()
+            .filter_map(|params| created_sub_account(&owner, params))
+            .map(|id| id.to_string()),
+    );
+    accounts.sort();
+    accounts.dedup();
+    Ok(accounts)
+}
+
+// Rebuild the balances at the given point from the latest slice before it plus the balance_history
+// after the slice. The slices and the operation log are in the log db, the balance_history in the
+// history db. With an owner only its own and its sub-accounts' balances are returned.
+async fn balances_at(data: &AppState, req: &BalancesAtReq, owner: Option<Uuid>) -> Result<BalancesAtResponse, RpcError> {
+    let (cutoff, slice) = cutoff_and_slice(&data.db_log, req).await?;
+    let accounts = match owner {
+        Some(owner) => Some(user_accounts(&data.db_log, owner, &cutoff, slice.as_ref()).await?),
+        None => None,
+    };
+
+    let mut balances: BTreeMap<(String, String), DecimalDbType> = BTreeMap::new();
+    if let Some(slice) = &slice {
+        let rows: Vec<BalanceRow> = sqlx::query_as(&balance_slice_query())
+            .bind(slice.time)
+            .bind(&accounts)
+            .bind(&req.asset)
+            .fetch_all(&data.db_log)
+            .await?;
+        for row in rows {
+            balances.insert((row.user_id, row.asset), row.balance);
+        }
+    }
+    let (history_from, operation_log_from) = match &slice {
+        Some(slice) => (parse_time(slice.time)?, slice.end_operation_log_id),
+        None => (parse_time(0)?, 0),
+    };
+    let rows: Vec<BalanceRow> = sqlx::query_as(&balance_history_query())
+        .bind(history_from)
+        .bind(operation_log_from)
+        .bind(cutoff.operation_log_id)
+        .bind(cutoff.time)
+        .bind(&accounts)
+        .bind(&req.asset)
+        .fetch_all(&data.db)
+        .await?;
+    for row in rows {
+        balances.insert((row.user_id, row.asset), row.balance);
+    }
+
+    Ok(BalancesAtResponse {
+        time: cutoff.time.timestamp(),
+        balances: balances
+            .into_iter()
+            .map(|((user_id, asset), balance)| UserBalance {
+                user_id,
+                asset,
+                balance: balance.to_string(),
+            })
+            .collect(),
+    })
+}
+
+fn balances_csv(balances: &[UserBalance]) -> String {
+    let mut csv = String::from("user_id,asset,balance\n");
+    for balance in balances {
+        csv.push_str(&format!("{},{},{}\n", balance.user_id, balance.asset, balance.balance));
+    }
+    csv
+}
+
+#[api_v2_operation]
+pub async fn my_balances_at(
+    req: HttpRequest,
+    balances_req: web::Query<BalancesAtReq>,
+    data: web::Data<AppState>,
+) -> Result<Json<BalancesAtResponse>, actix_web::Error> {
+    let user_id = req.extensions().get::<UserExtension>().unwrap().user_id;
+    let resp = balances_at(&data, &balances_req, Some(user_id)).await?;
+    Ok(Json(resp))
+}
+
+#[api_v2_operation]
+pub async fn all_balances_at(balances_req: web::Query<BalancesAtReq>, data: web::Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    let owner = match &balances_req.user_id {
+        Some(user_id) => Some(Uuid::from_str(user_id).map_err(|_| RpcError::bad_request("invalid user_id"))?),
+        None => None,
+    };
+    let resp = balances_at(&data, &balances_req, owner).await?;
+    if balances_req.format.as_deref() == Some("csv") {
+        Ok(HttpResponse::Ok()
+            .content_type("text/csv")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"balances_{}.csv\"", resp.time),
+            ))
+            .body(balances_csv(&resp.balances)))
+    } else {
+        Ok(HttpResponse::Ok().json(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time(86400).unwrap().timestamp(), 86400);
+        assert!(parse_time(i64::MAX).is_err());
+        assert!(parse_time(i64::MIN).is_err());
+    }
+
+    #[test]
+    fn test_created_sub_account() {
+        let owner = Uuid::from_str("0f0e0d0c-0b0a-4908-8706-050403020100").unwrap();
+        assert_eq!(
+            created_sub_account(&owner, r#"{"name":"hedge"}"#),
+            Some(SubAccountManager::account_id(&owner, "hedge"))
+        );
+        assert_eq!(created_sub_account(&owner, r#"{"market":"ETH_USDT"}"#), None);
+        assert_eq!(created_sub_account(&owner, "not json"), None);
+    }
+
+    #[test]
+    fn test_balances_csv() {
+        let balances = vec![
+            UserBalance {
+                user_id: String::from("0f0e0d0c-0b0a-4908-8706-050403020100"),
+                asset: String::from("ETH"),
+                balance: String::from("1.5"),
+            },
+            UserBalance {
+                user_id: String::from("1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d"),
+                asset: String::from("USDT"),
+                balance: String::from("0"),
+            },
+        ];
+        assert_eq!(
+            balances_csv(&balances),
+            "user_id,asset,balance\n\
+             0f0e0d0c-0b0a-4908-8706-050403020100,ETH,1.5\n\
+             1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d,USDT,0\n"
+        );
+    }
+}
@@ -53,6 +53,26 @@ pub mod market {
         }
     }
 
+    #[api_v2_operation]
+    pub async fn update_asset_limits(
+        req: HttpRequest,
+        limits_req: web::Json<types::AssetLimitsReq>,
+        app_state: web::Data<state::AppState>,
+    ) -> Result<&'static str, actix_web::Error> {
+        let limits_req = limits_req.into_inner();
+        log::debug!("Update asset limits {:?}", limits_req.limits);
+        if let Err(e) = storage::config::update_asset_limits(&app_state.db, &limits_req.limits).await {
+            return Err(InternalError::new(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR).into());
+        }
+
+        if !limits_req.not_reload {
+            let jwt = req.extensions().get::<JwtExtension>().unwrap().clone().jwt;
+            do_reload(&jwt, &app_state.into_inner()).await
+        } else {
+            Ok("done")
+        }
+    }
+
     #[api_v2_operation]
     pub async fn reload(req: HttpRequest, app_state: web::Data<state::AppState>) -> Result<&'static str, actix_web::Error> {
         let jwt = req.extensions().get::<JwtExtension>().unwrap().clone().jwt;
@@ -1,3 +1,4 @@
+pub mod balances;
 pub mod config;
 pub mod errors;
 pub mod manage;
@@ -5,7 +5,10 @@ use sqlx::postgres::Postgres;
 use std::cell::RefCell;
 use std::collections::HashMap;
 pub struct AppState {
+    // the history db
     pub db: sqlx::pool::Pool<Postgres>,
+    // the db of the slices and the operation log
+    pub db_log: sqlx::pool::Pool<Postgres>,
     pub manage_channel: Option<tonic::transport::channel::Channel>,
     pub config: Settings,
 }
@@ -1,4 +1,4 @@
-use crate::config::{Asset, Market};
+use crate::config::{Asset, AssetLimits, Market};
 use paperclip::actix::Apiv2Schema;
 use serde::{Deserialize, Serialize};
 
@@ -67,6 +67,13 @@ pub struct NewAssetReq {
     pub jwt: String,
 }
 
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct AssetLimitsReq {
+    pub limits: AssetLimits,
+    #[serde(default)]
+    pub not_reload: bool,
+}
+
 #[derive(Serialize, Deserialize, Default, Apiv2Schema)]
 pub struct NewTradePairReq {
     pub market: Market,
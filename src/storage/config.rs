@@ -10,6 +10,11 @@ impl From<AssetDesc> for config::Asset {
             name: origin.name,
             prec_show: origin.precision_show as u32,
             prec_save: origin.precision_stor as u32,
+            deposit_confirmations: origin.deposit_confirmations as u32,
+            min_deposit: origin.min_deposit,
+            min_withdraw: origin.min_withdraw,
+            max_withdraw: origin.max_withdraw,
+            withdraw_daily_quota: origin.withdraw_daily_quota,
         }
     }
 }
@@ -87,7 +92,8 @@ impl MarketConfigs {
         T: sqlx::Executor<'e, Database = DbType> + Send,
     {
         let query = format!(
-            "select id, symbol, name, precision_stor, precision_show, create_time from {} where create_time > $1",
+            "select id, symbol, name, precision_stor, precision_show, deposit_confirmations,
+        min_deposit, min_withdraw, max_withdraw, withdraw_daily_quota, create_time from {} where create_time > $1",
             tablenames::ASSET
         );
 
@@ -158,13 +164,19 @@ where
 {
     let query_template = if force {
         format!(
-            "insert into {} (id, symbol, name, precision_stor, precision_show) values ($1, $2, $3, $4, $5)
-        on conflict do update set precision_stor=EXCLUDED.precision_stor, precision_show=EXCLUDED.precision_show",
+            "insert into {} (id, symbol, name, precision_stor, precision_show, deposit_confirmations,
+        min_deposit, min_withdraw, max_withdraw, withdraw_daily_quota) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        on conflict do update set precision_stor=EXCLUDED.precision_stor, precision_show=EXCLUDED.precision_show,
+        deposit_confirmations=EXCLUDED.deposit_confirmations, min_deposit=EXCLUDED.min_deposit,
+        min_withdraw=EXCLUDED.min_withdraw, max_withdraw=EXCLUDED.max_withdraw,
+        withdraw_daily_quota=EXCLUDED.withdraw_daily_quota",
             tablenames::ASSET
         )
     } else {
         format!(
-            "insert into {} (id, symbol, name, precision_stor, precision_show) values ($1, $2, $3, $4, $5) on conflict do nothing",
+            "insert into {} (id, symbol, name, precision_stor, precision_show, deposit_confirmations,
+        min_deposit, min_withdraw, max_withdraw, withdraw_daily_quota) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        on conflict do nothing",
             tablenames::ASSET
         )
     };
@@ -175,12 +187,43 @@ where
         .bind(&asset.name)
         .bind(asset.prec_save as i16)
         .bind(asset.prec_show as i16)
+        .bind(asset.deposit_confirmations as i32)
+        .bind(asset.min_deposit)
+        .bind(asset.min_withdraw)
+        .bind(asset.max_withdraw)
+        .bind(asset.withdraw_daily_quota)
         .execute(db_conn)
         .await?;
 
     Ok(())
 }
 
+// create_time is bumped so the next market reload picks the new limits up
+pub async fn update_asset_limits<'c, 'e, T>(db_conn: T, limits: &config::AssetLimits) -> Result<()>
+where
+    T: sqlx::Executor<'e, Database = DbType>,
+{
+    let query = format!(
+        "update {} set min_deposit = $2, min_withdraw = $3, max_withdraw = $4, withdraw_daily_quota = $5,
+        create_time = CURRENT_TIMESTAMP where id = $1",
+        tablenames::ASSET
+    );
+
+    let result = sqlx::query(&query)
+        .bind(&limits.asset_id)
+        .bind(limits.min_deposit)
+        .bind(limits.min_withdraw)
+        .bind(limits.max_withdraw)
+        .bind(limits.withdraw_daily_quota)
+        .execute(db_conn)
+        .await?;
+    if result.rows_affected() == 0 {
+        anyhow::bail!("asset {} not found", limits.asset_id);
+    }
+
+    Ok(())
+}
+
 pub async fn persist_market_to_db<'c, 'e, T>(db_conn: T, market: &config::Market) -> Result<()>
 where
     T: sqlx::Executor<'e, Database = DbType>,
@@ -32,11 +32,24 @@ pub mod tablenames {
     pub const ORDERHISTORY: &str = "order_history";
     pub const USERTRADE: &str = "user_trade";
     pub const OPERATIONLOG: &str = "operation_log";
+    pub const OPERATIONLOGARCHIVE: &str = "operation_log_archive";
+    pub const OUTBOXCURSOR: &str = "outbox_cursor";
+    pub const LEADERSTATE: &str = "leader_state";
     pub const ORDERSLICE: &str = "order_slice";
     pub const BALANCESLICE: &str = "balance_slice";
     pub const SLICEHISTORY: &str = "slice_history";
     pub const MARKETTRADE: &str = "market_trade";
     pub const INTERNALTX: &str = "internal_tx";
+    pub const MMPSLICE: &str = "mmp_slice";
+    pub const SUBACCOUNTSLICE: &str = "sub_account_slice";
+    pub const WITHDRAWSLICE: &str = "withdraw_slice";
+    pub const DEPOSITSLICE: &str = "deposit_slice";
+    pub const BUSINESSIDSLICE: &str = "business_id_slice";
+    pub const ASSETLEDGERSLICE: &str = "asset_ledger_slice";
+    pub const WITHDRAWQUOTASLICE: &str = "withdraw_quota_slice";
+    pub const WITHDRAWUSAGESLICE: &str = "withdraw_usage_slice";
+    pub const ACCOUNTLOCKSLICE: &str = "account_lock_slice";
+    pub const VESTINGSLICE: &str = "vesting_slice";
 }
 
 use tablenames::*;
@@ -48,6 +61,11 @@ pub struct AssetDesc {
     pub name: String,
     pub precision_stor: i16,
     pub precision_show: i16,
+    pub deposit_confirmations: i32,
+    pub min_deposit: DecimalDbType,
+    pub min_withdraw: DecimalDbType,
+    pub max_withdraw: DecimalDbType,
+    pub withdraw_daily_quota: DecimalDbType,
     pub create_time: Option<TimestampDbType>,
 }
 
@@ -81,6 +99,8 @@ pub struct BalanceHistory {
     pub balance_frozen: DecimalDbType,
     // TODO: change it to jsonb
     pub detail: String,
+    // the operation that made the change, none for the rows written before it was recorded
+    pub operation_log_id: Option<i64>,
 }
 
 #[derive(sqlx::Type, Debug, Clone, Serialize, Deserialize, Apiv2Schema)]
@@ -140,6 +160,10 @@ pub struct OperationLog {
     pub method: String,
     // TODO: change it to jsonb
     pub params: String,
+    // the time and other engine inputs the operation was executed with, as json
+    pub inputs: String,
+    // the leader epoch it was logged in, the db rejects the logs of a deposed leader
+    pub epoch: i64,
 }
 
 //Notice this is used for query the full columns but not for insert
@@ -163,14 +187,16 @@ pub struct BalanceSliceInsert {
     pub balance: DecimalDbType,
 }
 
-#[derive(sqlx::FromRow, Debug, Clone)]
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
 pub struct OrderSlice {
     pub id: i64,
     pub slice_id: i64,
     // Type enum: MARKET or LIMIT
     pub order_type: types::OrderType,
     pub order_side: types::OrderSide,
+    #[serde(with = "DateTimeMilliseconds")]
     pub create_time: TimestampDbType,
+    #[serde(with = "DateTimeMilliseconds")]
     pub update_time: TimestampDbType,
     pub user_id: String,
     pub market: String,
@@ -185,6 +211,14 @@ pub struct OrderSlice {
     pub finished_quote: DecimalDbType,
     pub finished_fee: DecimalDbType,
     pub post_only: bool,
+    // only set for trailing stop orders which are not triggered yet
+    pub trail_type: Option<types::TrailingType>,
+    pub trail_value: Option<DecimalDbType>,
+    pub trigger_price: Option<DecimalDbType>,
+    // only set for pegged orders
+    pub peg_reference: Option<types::PegReference>,
+    pub peg_offset: Option<DecimalDbType>,
+    pub peg_limit: Option<DecimalDbType>,
 }
 
 // xx_id here means the last persisted entry id
@@ -194,6 +228,114 @@ pub struct SliceHistory {
     pub end_operation_log_id: i64,
     pub end_order_id: i64,
     pub end_trade_id: i64,
+    // hex root of the state hash, not set on slices made before it existed
+    pub state_hash: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct MmpSlice {
+    pub slice_id: i64,
+    pub user_id: String,
+    pub market: String,
+    pub qty_limit: DecimalDbType,
+    pub trade_count_limit: i32,
+    // seconds
+    pub time_window: f64,
+    pub triggered: bool,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct SubAccountSlice {
+    pub slice_id: i64,
+    pub owner: String,
+    pub name: String,
+    pub account_id: String,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct BusinessIdSlice {
+    pub slice_id: i64,
+    pub user_id: String,
+    pub asset: String,
+    pub business: String,
+    pub business_id: i64,
+    pub time: TimestampDbType,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct AssetLedgerSlice {
+    pub slice_id: i64,
+    pub asset: String,
+    pub deposit: DecimalDbType,
+    pub withdraw: DecimalDbType,
+    pub fee: DecimalDbType,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct WithdrawQuotaSlice {
+    pub slice_id: i64,
+    pub user_id: String,
+    pub asset: String,
+    pub quota: DecimalDbType,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct WithdrawUsageSlice {
+    //pub id: i32,
+    pub slice_id: i64,
+    pub user_id: String,
+    pub asset: String,
+    pub time: TimestampDbType,
+    pub amount: DecimalDbType,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct AccountLockSlice {
+    pub slice_id: i64,
+    pub user_id: String,
+    pub asset: String,
+    pub reason: String,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct VestingSlice {
+    pub slice_id: i64,
+    pub user_id: String,
+    pub asset: String,
+    pub business: String,
+    pub business_id: i64,
+    pub total: DecimalDbType,
+    pub released: DecimalDbType,
+    // json of the schedule
+    pub schedule: String,
+    pub detail: String,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct DepositSlice {
+    pub slice_id: i64,
+    pub user_id: String,
+    pub asset: String,
+    pub business: String,
+    pub business_id: i64,
+    pub market_price: DecimalDbType,
+    pub amount: DecimalDbType,
+    pub confirmations: i32,
+    pub create_time: TimestampDbType,
+    pub detail: String,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct WithdrawSlice {
+    pub slice_id: i64,
+    pub user_id: String,
+    pub asset: String,
+    pub business: String,
+    pub business_id: i64,
+    pub market_price: DecimalDbType,
+    pub amount: DecimalDbType,
+    pub create_time: TimestampDbType,
+    pub detail: String,
 }
 
 #[derive(sqlx::FromRow, Debug, Clone, Serialize, Apiv2Schema)]
@@ -215,6 +357,8 @@ pub struct InternalTx {
     pub user_to: String,
     pub asset: String,
     pub amount: DecimalDbType,
+    pub business_id: i64,
+    pub memo: String,
 }
 
 /*
@@ -230,7 +374,7 @@ impl sqlxextend::TableSchemas for BalanceHistory {
     fn table_name() -> &'static str {
         BALANCEHISTORY
     }
-    const ARGN: i32 = 11;
+    const ARGN: i32 = 12;
     fn default_argsn() -> Vec<i32> {
         vec![1]
     }
@@ -249,6 +393,7 @@ impl sqlxextend::BindQueryArg<'_, DbType> for BalanceHistory {
         arg.add(&self.balance_available);
         arg.add(&self.balance_frozen);
         arg.add(&self.detail);
+        arg.add(self.operation_log_id);
     }
 }
 
@@ -319,7 +464,7 @@ impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for OrderHistor
 
 /* --------------------- models::OperationLog -----------------------------*/
 impl sqlxextend::TableSchemas for OperationLog {
-    const ARGN: i32 = 4;
+    const ARGN: i32 = 7;
     fn table_name() -> &'static str {
         OPERATIONLOG
     }
@@ -331,6 +476,9 @@ impl sqlxextend::BindQueryArg<'_, DbType> for OperationLog {
         arg.add(self.time);
         arg.add(&self.method);
         arg.add(&self.params);
+        arg.add(&self.user_id);
+        arg.add(&self.inputs);
+        arg.add(self.epoch);
     }
 }
 
@@ -342,7 +490,7 @@ impl sqlxextend::TableSchemas for OrderSlice {
     fn table_name() -> &'static str {
         ORDERSLICE
     }
-    const ARGN: i32 = 18;
+    const ARGN: i32 = 24;
     //fn default_argsn() -> Vec<i32>{ vec![1] }
 }
 
@@ -366,6 +514,12 @@ impl sqlxextend::BindQueryArg<'_, DbType> for OrderSlice {
         arg.add(&self.finished_quote);
         arg.add(&self.finished_fee);
         arg.add(&self.post_only);
+        arg.add(self.trail_type);
+        arg.add(&self.trail_value);
+        arg.add(&self.trigger_price);
+        arg.add(self.peg_reference);
+        arg.add(&self.peg_offset);
+        arg.add(&self.peg_limit);
     }
 }
 
@@ -401,7 +555,7 @@ impl sqlxextend::TableSchemas for SliceHistory {
     fn table_name() -> &'static str {
         SLICEHISTORY
     }
-    const ARGN: i32 = 4;
+    const ARGN: i32 = 5;
     fn default_argsn() -> Vec<i32> {
         vec![1]
     }
@@ -413,6 +567,7 @@ impl sqlxextend::BindQueryArg<'_, DbType> for SliceHistory {
         arg.add(self.end_operation_log_id);
         arg.add(self.end_order_id);
         arg.add(self.end_trade_id);
+        arg.add(&self.state_hash);
     }
 }
 
@@ -439,3 +594,244 @@ impl sqlxextend::BindQueryArg<'_, DbType> for MarketTrade {
 }
 
 impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for MarketTrade {}
+
+/* --------------------- models::InternalTx -----------------------------*/
+impl sqlxextend::TableSchemas for InternalTx {
+    fn table_name() -> &'static str {
+        INTERNALTX
+    }
+    const ARGN: i32 = 7;
+    fn default_argsn() -> Vec<i32> {
+        vec![1]
+    }
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for InternalTx {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.time);
+        arg.add(&self.user_from);
+        arg.add(&self.user_to);
+        arg.add(&self.asset);
+        arg.add(&self.amount);
+        arg.add(self.business_id);
+        arg.add(&self.memo);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for InternalTx {}
+
+/* --------------------- models::MmpSlice -----------------------------*/
+impl sqlxextend::TableSchemas for MmpSlice {
+    fn table_name() -> &'static str {
+        MMPSLICE
+    }
+    const ARGN: i32 = 7;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for MmpSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.user_id);
+        arg.add(&self.market);
+        arg.add(&self.qty_limit);
+        arg.add(self.trade_count_limit);
+        arg.add(self.time_window);
+        arg.add(self.triggered);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for MmpSlice {}
+
+/* --------------------- models::SubAccountSlice -----------------------------*/
+impl sqlxextend::TableSchemas for SubAccountSlice {
+    fn table_name() -> &'static str {
+        SUBACCOUNTSLICE
+    }
+    const ARGN: i32 = 4;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for SubAccountSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.owner);
+        arg.add(&self.name);
+        arg.add(&self.account_id);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for SubAccountSlice {}
+
+/* --------------------- models::WithdrawSlice -----------------------------*/
+impl sqlxextend::TableSchemas for WithdrawSlice {
+    fn table_name() -> &'static str {
+        WITHDRAWSLICE
+    }
+    const ARGN: i32 = 9;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for WithdrawSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.business);
+        arg.add(self.business_id);
+        arg.add(self.market_price);
+        arg.add(self.amount);
+        arg.add(self.create_time);
+        arg.add(&self.detail);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for WithdrawSlice {}
+
+/* --------------------- models::DepositSlice -----------------------------*/
+impl sqlxextend::TableSchemas for DepositSlice {
+    fn table_name() -> &'static str {
+        DEPOSITSLICE
+    }
+    const ARGN: i32 = 10;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for DepositSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.business);
+        arg.add(self.business_id);
+        arg.add(self.market_price);
+        arg.add(self.amount);
+        arg.add(self.confirmations);
+        arg.add(self.create_time);
+        arg.add(&self.detail);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for DepositSlice {}
+
+/* --------------------- models::BusinessIdSlice -----------------------------*/
+impl sqlxextend::TableSchemas for BusinessIdSlice {
+    fn table_name() -> &'static str {
+        BUSINESSIDSLICE
+    }
+    const ARGN: i32 = 6;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for BusinessIdSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.business);
+        arg.add(self.business_id);
+        arg.add(self.time);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for BusinessIdSlice {}
+
+/* --------------------- models::AssetLedgerSlice -----------------------------*/
+impl sqlxextend::TableSchemas for AssetLedgerSlice {
+    fn table_name() -> &'static str {
+        ASSETLEDGERSLICE
+    }
+    const ARGN: i32 = 5;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for AssetLedgerSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.asset);
+        arg.add(self.deposit);
+        arg.add(self.withdraw);
+        arg.add(self.fee);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for AssetLedgerSlice {}
+
+/* --------------------- models::WithdrawQuotaSlice -----------------------------*/
+impl sqlxextend::TableSchemas for WithdrawQuotaSlice {
+    fn table_name() -> &'static str {
+        WITHDRAWQUOTASLICE
+    }
+    const ARGN: i32 = 4;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for WithdrawQuotaSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.user_id);
+        arg.add(&self.asset);
+        arg.add(self.quota);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for WithdrawQuotaSlice {}
+
+/* --------------------- models::WithdrawUsageSlice -----------------------------*/
+impl sqlxextend::TableSchemas for WithdrawUsageSlice {
+    fn table_name() -> &'static str {
+        WITHDRAWUSAGESLICE
+    }
+    const ARGN: i32 = 5;
+    fn default_argsn() -> Vec<i32> {
+        vec![1]
+    }
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for WithdrawUsageSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.user_id);
+        arg.add(&self.asset);
+        arg.add(self.time);
+        arg.add(self.amount);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for WithdrawUsageSlice {}
+
+/* --------------------- models::AccountLockSlice -----------------------------*/
+impl sqlxextend::TableSchemas for AccountLockSlice {
+    fn table_name() -> &'static str {
+        ACCOUNTLOCKSLICE
+    }
+    const ARGN: i32 = 4;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for AccountLockSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.reason);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for AccountLockSlice {}
+
+/* --------------------- models::VestingSlice -----------------------------*/
+impl sqlxextend::TableSchemas for VestingSlice {
+    fn table_name() -> &'static str {
+        VESTINGSLICE
+    }
+    const ARGN: i32 = 9;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for VestingSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.business);
+        arg.add(self.business_id);
+        arg.add(self.total);
+        arg.add(self.released);
+        arg.add(&self.schedule);
+        arg.add(&self.detail);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for VestingSlice {}
@@ -31,6 +31,43 @@ pub enum OrderType {
     MARKET,
 }
 
+// how the trigger price of a trailing stop order keeps its distance from the last price
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum TrailingType {
+    OFFSET,
+    PERCENTAGE,
+}
+
+// which price of the orderbook a pegged order follows
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum PegReference {
+    #[serde(rename = "SAME_SIDE")]
+    #[sqlx(rename = "same_side")]
+    SAMESIDE,
+    OPPOSITE,
+    MID,
+}
+
+// lifecycle of a deposit reported before it has enough confirmations, a plain positive balance update is CONFIRMED at once
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum DepositStatus {
+    PENDING,
+    CONFIRMED,
+    CANCELLED,
+}
+
+// lifecycle of a two-phase withdrawal, a plain negative balance update is CONFIRMED at once
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum WithdrawStatus {
+    PENDING,
+    CONFIRMED,
+    REJECTED,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 pub enum OrderEventType {
     PUT = 1,